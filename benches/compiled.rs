@@ -0,0 +1,45 @@
+//! Compares rendering a compiled template against tokenizing and expanding it for each render.
+//!
+//! Run with `cargo bench --bench compiled`.
+
+extern crate token_expander;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use token_expander::{Token, Tokenizer, TokenizerExt};
+
+const ITERATIONS: usize = 100_000;
+const TEMPLATE: &str = "https://${domain}/${repo}/pool/main/${name}/${name}_${version}_${arch}.deb";
+
+fn map(buf: &mut String, token: Token) -> Result<bool, ()> {
+    match token {
+        Token::Normal(text) => buf.push_str(text),
+        Token::Escaped(character) => buf.push(character),
+        Token::Key("domain") => buf.push_str("apt.pop-os.org"),
+        Token::Key("repo") => buf.push_str("release"),
+        Token::Key("name") => buf.push_str("system76-driver"),
+        Token::Key("version") => buf.push_str("20.04.80"),
+        Token::Key(_) => buf.push_str("amd64"),
+        _ => return Err(()),
+    }
+    Ok(true)
+}
+
+fn measure<F: FnMut() -> String>(mut render: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(render());
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let template = Tokenizer::new(TEMPLATE).compile();
+
+    let expand = measure(|| Tokenizer::new(black_box(TEMPLATE)).expand(map).unwrap());
+    let render = measure(|| black_box(&template).render(map).unwrap());
+
+    let per_render = |time: Duration| time.as_nanos() as f64 / ITERATIONS as f64;
+    println!("tokenize and expand: {:.1} ns/render", per_render(expand));
+    println!("compiled render:     {:.1} ns/render", per_render(render));
+}
@@ -0,0 +1,20 @@
+//! The lexer as it was before terminators were looked up in a `ByteSet`, which is the
+//! reference for the `terminators` benchmark and for the tests of the lexer.
+
+/// Lexes `search_space` up to the first byte of `stop_on` which isn't escaped, scanning
+/// `stop_on` for each byte.
+pub fn linear<'a>(search_space: &'a str, stop_on: &[u8], escape: u8) -> &'a str {
+    let bytes = search_space.as_bytes();
+    let mut end = 0;
+    while let Some(byte) = bytes.get(end) {
+        if *byte == escape {
+            end += 1;
+            end += search_space[end..].chars().next().map_or(0, char::len_utf8);
+        } else if stop_on.contains(byte) {
+            break;
+        } else {
+            end += 1;
+        }
+    }
+    &search_space[..end]
+}
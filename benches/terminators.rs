@@ -0,0 +1,57 @@
+//! Compares lexing bareword keys with the terminator lookup table against a linear scan of the
+//! terminators for each byte, and against a predicate on each character.
+//!
+//! Run with `cargo bench --bench terminators`.
+
+extern crate token_expander;
+
+#[path = "support/linear.rs"]
+mod linear;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use token_expander::lexer::{ByteSet, Lexer, LexerRules};
+
+const PATTERN: &[u8] = br#"~!@#$%^&*()+-=[]\{}|;':",./<>?"#;
+const SET: ByteSet = ByteSet::new(PATTERN);
+const ITERATIONS: usize = 200;
+
+fn linear(search_space: &str, escape: u8) -> &str {
+    linear::linear(search_space, PATTERN, escape)
+}
+
+fn lexed(search_space: &str, escape: u8) -> &str {
+    Lexer::new(search_space, LexerRules::with_set(SET, escape)).search()
+}
+
+fn predicate(search_space: &str, escape: u8) -> &str {
+    let terminates = |character: char| character.is_ascii() && SET.contains(character as u8);
+    Lexer::with_predicate(search_space, terminates, escape).search()
+}
+
+fn measure<'a>(input: &'a str, search: fn(&'a str, u8) -> &'a str) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut remaining = black_box(input);
+        while !remaining.is_empty() {
+            let key = search(remaining, b'\\');
+            remaining = &remaining[key.len()..];
+            remaining = &remaining[remaining.chars().next().map_or(0, char::len_utf8)..];
+        }
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let input = "a_long_bareword_key_without_terminators_".repeat(512) + "$next_key_";
+    let input = input.repeat(16);
+
+    let linear_time = measure(&input, linear);
+    let table_time = measure(&input, lexed);
+    let predicate_time = measure(&input, predicate);
+
+    let per_byte = |time: Duration| time.as_nanos() as f64 / (ITERATIONS * input.len()) as f64;
+    println!("linear scan:  {:.3} ns/byte", per_byte(linear_time));
+    println!("lookup table: {:.3} ns/byte", per_byte(table_time));
+    println!("predicate:    {:.3} ns/byte", per_byte(predicate_time));
+}
@@ -0,0 +1,389 @@
+//! Tokenization of a template which arrives in several pieces.
+
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::Range;
+use {Token, TokenOwned, Tokenizer, BALANCED_BRACES, QUOTED_KEYS};
+
+/// A token from a `ChunkedTokenizer`, which is owned only when it straddled two chunks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkedToken<'a> {
+    /// A token which was entirely contained within a single chunk.
+    Borrowed(Token<'a>),
+    /// A token which was assembled from the ends of consecutive chunks.
+    Owned(TokenOwned),
+}
+
+impl<'a> ChunkedToken<'a> {
+    /// Borrows the inner token, regardless of whether it is owned.
+    pub fn as_token(&self) -> Token<'_> {
+        match *self {
+            ChunkedToken::Borrowed(ref token) => token.clone(),
+            ChunkedToken::Owned(ref token) => token.as_token(),
+        }
+    }
+}
+
+/// Tokenizes a sequence of string chunks as if they were one contiguous string.
+///
+/// Only a construct which straddles a chunk boundary, such as a `${key}` or an escape that is
+/// split in two, is buffered and yielded as an owned token. A bareword key at the end of a
+/// chunk may continue into the next, so it is also buffered. Text is yielded as it arrives,
+/// so a run of `Token::Normal` text may be split across several tokens.
+///
+/// ```rust
+/// use token_expander::{ChunkedToken, ChunkedTokenizer, Token, TokenOwned};
+///
+/// assert_eq!(
+///     ChunkedTokenizer::new(vec!["https://${dom", "ain}/$name.deb"]).collect::<Vec<_>>(),
+///     vec![
+///         ChunkedToken::Borrowed(Token::Normal("https://")),
+///         ChunkedToken::Owned(TokenOwned::Key("domain".into())),
+///         ChunkedToken::Borrowed(Token::Normal("/")),
+///         ChunkedToken::Borrowed(Token::Key("name")),
+///         ChunkedToken::Borrowed(Token::Normal(".deb")),
+///     ]
+/// );
+/// ```
+#[derive(Debug)]
+pub struct ChunkedTokenizer<'a, I> {
+    chunks: I,
+    config: Tokenizer<'a>,
+    pending: String,
+    queue: VecDeque<ChunkedToken<'a>>,
+}
+
+impl<'a, I: Iterator<Item = &'a str>> ChunkedTokenizer<'a, I> {
+    /// Tokenizes `chunks` with the default configuration of a `Tokenizer`.
+    pub fn new<C: IntoIterator<Item = &'a str, IntoIter = I>>(chunks: C) -> Self {
+        Self::with_config(chunks, &Tokenizer::new(""))
+    }
+
+    /// Tokenizes `chunks` with the escape, prefixes, and modes of `config`.
+    pub fn with_config<C: IntoIterator<Item = &'a str, IntoIter = I>>(
+        chunks: C,
+        config: &Tokenizer<'a>,
+    ) -> Self {
+        ChunkedTokenizer {
+            chunks: chunks.into_iter(),
+            config: config.rebind(""),
+            pending: String::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn process(&mut self, chunk: &'a str) {
+        let mut offset = 0;
+
+        if !self.pending.is_empty() {
+            let mut combined = mem::take(&mut self.pending);
+            let carried = combined.len();
+            combined.push_str(chunk);
+
+            let mut tokenizer = self.config.rebind(&combined);
+            match tokenizer.next_spanned() {
+                Some((token, span)) => {
+                    match split_incomplete(&self.config, &combined, token, &span) {
+                        (Some(token), None) => {
+                            self.queue.push_back(ChunkedToken::Owned(token.into()));
+                            offset = span.end.saturating_sub(carried);
+                        }
+                        (complete, Some(rest)) => {
+                            if let Some(token) = complete {
+                                self.queue.push_back(ChunkedToken::Owned(token.into()));
+                            }
+                            self.pending = combined[rest..].to_owned();
+                            return;
+                        }
+                        (None, None) => return,
+                    }
+                }
+                None => return,
+            }
+        }
+
+        let window = &chunk[offset..];
+        let mut tokenizer = self.config.rebind(window);
+        while let Some((token, span)) = tokenizer.next_spanned() {
+            let (complete, incomplete) = split_incomplete(&self.config, window, token, &span);
+            if let Some(token) = complete {
+                self.queue.push_back(ChunkedToken::Borrowed(token));
+            }
+
+            if let Some(rest) = incomplete {
+                self.pending = window[rest..].to_owned();
+                break;
+            }
+        }
+    }
+}
+
+/// Whether the source of a key ends with a closing brace or delimiter.
+///
+/// With balanced braces, a closing brace may instead close a brace nested within the key,
+/// and a quoted key is only closed by a quote followed by a brace.
+fn closed(config: &Tokenizer, source: &str) -> bool {
+    let quoted = source
+        .find('{')
+        .is_some_and(|open| source[open + 1..].starts_with('"'));
+    if config.flags & QUOTED_KEYS != 0 && quoted && !source.ends_with("\"}") {
+        return false;
+    }
+
+    (source.ends_with('}') && config.flags & BALANCED_BRACES == 0)
+        || config.delimiters.is_some_and(|delimiters| {
+            source.len() > delimiters.open.len() && source.ends_with(delimiters.close)
+        })
+}
+
+/// Separates the complete portion of a token from the position where an incomplete
+/// construct, which may continue in the next chunk, begins.
+pub(crate) fn split_incomplete<'w>(
+    config: &Tokenizer,
+    window: &'w str,
+    token: Token<'w>,
+    span: &Range<usize>,
+) -> (Option<Token<'w>>, Option<usize>) {
+    if span.end < window.len() {
+        return (Some(token), None);
+    }
+
+    match token {
+        Token::Key(_)
+        | Token::PrefixedKey { .. }
+        | Token::KeyDefault { .. }
+        | Token::KeyTyped { .. }
+        | Token::KeyReplace { .. }
+        | Token::KeyRequired { .. }
+            if !closed(config, &window[span.clone()]) =>
+        {
+            (None, Some(span.start))
+        }
+        // A bareword key may continue in the next chunk.
+        Token::KeyDecoded { .. } => (None, Some(span.start)),
+        Token::Escaped(_) if span.len() == config.escape_len() => (None, Some(span.start)),
+        // Further combining characters may follow in the next chunk, as may the rest of a
+        // user's name.
+        Token::EscapedStr(_) | Token::Home(_) => (None, Some(span.start)),
+        // An unterminated include directive may be closed in the next chunk.
+        Token::Normal(text)
+            if config.include.is_some_and(|directive| {
+                text.starts_with(directive) && text[directive.len()..].starts_with('(')
+            }) =>
+        {
+            (None, Some(span.start))
+        }
+        Token::Normal(text) => {
+            // The text may end with the beginning of a prefix, an escape sequence, or an
+            // include directive.
+            let opening = config.include.map(|directive| format!("{}(", directive));
+            let tagged = config.prefix_kinds.iter().map(|&(prefix, _)| prefix);
+            let partial = config
+                .prefixes
+                .iter()
+                .cloned()
+                .chain(tagged)
+                .map(str::as_bytes)
+                .chain(config.partial_escape())
+                .chain(opening.as_ref().map(String::as_bytes))
+                .flat_map(|construct| (1..construct.len()).map(move |end| &construct[..end]))
+                .filter(|partial| text.as_bytes().ends_with(partial))
+                .map(|partial| partial.len())
+                .max();
+
+            match partial {
+                Some(length) if length == text.len() => (None, Some(span.start)),
+                Some(length) => {
+                    let split = text.len() - length;
+                    (
+                        Some(Token::Normal(&text[..split])),
+                        Some(span.start + split),
+                    )
+                }
+                None => (Some(token), None),
+            }
+        }
+        token => (Some(token), None),
+    }
+}
+
+impl<'a, I: Iterator<Item = &'a str>> Iterator for ChunkedTokenizer<'a, I> {
+    type Item = ChunkedToken<'a>;
+
+    fn next(&mut self) -> Option<ChunkedToken<'a>> {
+        loop {
+            if let Some(token) = self.queue.pop_front() {
+                return Some(token);
+            }
+
+            match self.chunks.next() {
+                Some(chunk) => self.process(chunk),
+                None if self.pending.is_empty() => return None,
+                None => {
+                    let pending = mem::take(&mut self.pending);
+                    let tokens = self.config.rebind(&pending).map(TokenOwned::from);
+                    self.queue.extend(tokens.map(ChunkedToken::Owned));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(chunks: &[&str]) -> Vec<TokenOwned> {
+        ChunkedTokenizer::new(chunks.iter().cloned())
+            .map(|token| token.as_token().into())
+            .collect()
+    }
+
+    fn whole(template: &str) -> Vec<TokenOwned> {
+        Tokenizer::new(template).map(TokenOwned::from).collect()
+    }
+
+    #[test]
+    fn split_mid_key() {
+        assert_eq!(
+            ChunkedTokenizer::new(vec!["a/${ver", "sion}/$arch.deb"]).collect::<Vec<_>>(),
+            vec![
+                ChunkedToken::Borrowed(Token::Normal("a/")),
+                ChunkedToken::Owned(TokenOwned::Key("version".into())),
+                ChunkedToken::Borrowed(Token::Normal("/")),
+                ChunkedToken::Borrowed(Token::Key("arch")),
+                ChunkedToken::Borrowed(Token::Normal(".deb")),
+            ]
+        );
+
+        assert_eq!(
+            ChunkedTokenizer::new(vec!["$na", "me/"]).collect::<Vec<_>>(),
+            vec![
+                ChunkedToken::Owned(TokenOwned::Key("name".into())),
+                ChunkedToken::Borrowed(Token::Normal("/")),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_constructs() {
+        assert_eq!(collect(&["a$", "{b}c"]), whole("a${b}c"));
+        assert_eq!(collect(&["a\\", "$b"]), whole("a\\$b"));
+        assert_eq!(collect(&["${a", "", "b", "c}d"]), whole("${abc}d"));
+        assert_eq!(collect(&["${a}", "${b}"]), whole("${a}${b}"));
+        assert_eq!(collect(&["x${unterminated"]), whole("x${unterminated"));
+        assert_eq!(collect(&["x\\"]), whole("x\\"));
+    }
+
+    #[test]
+    fn split_delimiters() {
+        let config = Tokenizer::windows("");
+        let collect = |chunks: Vec<&'static str>| {
+            ChunkedTokenizer::with_config(chunks, &config).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            collect(vec!["%PATH%", ";%HOME%"]),
+            vec![
+                ChunkedToken::Borrowed(Token::Key("PATH")),
+                ChunkedToken::Borrowed(Token::Normal(";")),
+                ChunkedToken::Borrowed(Token::Key("HOME")),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_prefix() {
+        let mut config = Tokenizer::new("");
+        config.set_prefixes(&["@@"]);
+
+        assert_eq!(
+            ChunkedTokenizer::with_config(vec!["a@", "@b/@c"], &config)
+                .map(|token| TokenOwned::from(token.as_token()))
+                .collect::<Vec<_>>(),
+            vec![
+                TokenOwned::Normal("a".into()),
+                TokenOwned::Key("b".into()),
+                TokenOwned::Normal("/@c".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_escape_sequence() {
+        let mut config = Tokenizer::new("");
+        config.set_escape_sequence(b"^^");
+        let collect = |chunks: Vec<&'static str>| {
+            ChunkedTokenizer::with_config(chunks, &config)
+                .map(|token| TokenOwned::from(token.as_token()))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![
+            TokenOwned::Normal("a".into()),
+            TokenOwned::Escaped('$'),
+            TokenOwned::Normal("b".into()),
+        ];
+        assert_eq!(collect(vec!["a^", "^$b"]), expected);
+        assert_eq!(collect(vec!["a^^", "$b"]), expected);
+    }
+
+    #[test]
+    fn split_balanced() {
+        let mut config = Tokenizer::new("");
+        config.default_values(true).balanced_braces(true);
+        let collect = |chunks: Vec<&'static str>| {
+            ChunkedTokenizer::with_config(chunks, &config)
+                .map(|token| TokenOwned::from(token.as_token()))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![
+            TokenOwned::KeyDefault {
+                key: "a".into(),
+                default: "${b}".into(),
+            },
+            TokenOwned::Normal("c".into()),
+        ];
+        assert_eq!(collect(vec!["${a:-${b}", "}c"]), expected);
+        assert_eq!(collect(vec!["${a:-${b}}", "c"]), expected);
+    }
+
+    #[test]
+    fn split_quoted() {
+        let mut config = Tokenizer::new("");
+        config.quoted_keys(true);
+        let collect = |chunks: Vec<&'static str>| {
+            ChunkedTokenizer::with_config(chunks, &config)
+                .map(|token| TokenOwned::from(token.as_token()))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![
+            TokenOwned::Key("a}b".into()),
+            TokenOwned::Normal("c".into()),
+        ];
+        assert_eq!(collect(vec!["${\"a}", "b\"}c"]), expected);
+        assert_eq!(collect(vec!["${\"a}b\"", "}c"]), expected);
+    }
+
+    #[test]
+    fn split_include() {
+        let mut config = Tokenizer::new("");
+        config.set_include_directive(Some("@include"));
+        let collect = |chunks: Vec<&'static str>| {
+            ChunkedTokenizer::with_config(chunks, &config)
+                .map(|token| TokenOwned::from(token.as_token()))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![
+            TokenOwned::Normal("a".into()),
+            TokenOwned::Include("b.tmpl".into()),
+            TokenOwned::Key("c".into()),
+        ];
+        assert_eq!(collect(vec!["a@inc", "lude(b.tmpl)$c"]), expected);
+        assert_eq!(collect(vec!["a@include", "(b.tmpl)$c"]), expected);
+        assert_eq!(collect(vec!["a@include(b.", "tmpl)$c"]), expected);
+    }
+}
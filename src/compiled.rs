@@ -0,0 +1,117 @@
+//! Templates which are tokenized once, and then rendered repeatedly.
+
+use {normalized, Token, Tokenizer, TokenizerExt};
+
+/// The tokens of a template, collected by `Tokenizer::compile`, so that rendering the
+/// template repeatedly does not scan its source again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledTemplate<'a> {
+    tokens: Vec<Token<'a>>,
+    len: usize,
+    normalize: bool,
+}
+
+impl<'a> CompiledTemplate<'a> {
+    /// The tokens of the template.
+    pub fn tokens(&self) -> &[Token<'a>] {
+        &self.tokens
+    }
+
+    /// Expands the template in the same manner as `TokenizerExt::expand`.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let template = Tokenizer::new("${name}_${version}.deb").compile();
+    /// let render = |version| {
+    ///     template.render(|buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.push_str(text),
+    ///             Token::Key("name") => buf.push_str("system76"),
+    ///             Token::Key("version") => buf.push_str(version),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     })
+    /// };
+    ///
+    /// assert_eq!(render("1.0.0"), Ok("system76_1.0.0.deb".into()));
+    /// assert_eq!(render("1.0.1"), Ok("system76_1.0.1.deb".into()));
+    /// ```
+    pub fn render<T, F>(&self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len * 2);
+        for token in &self.tokens {
+            let token = normalized(self.normalize, &mut collapsed, token.clone());
+
+            if !map(&mut output, token)? {
+                break;
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Collects the remaining tokens into a template which may be rendered repeatedly,
+    /// without tokenizing it again for each render. The `compiled` benchmark compares the two.
+    pub fn compile(self) -> CompiledTemplate<'a> {
+        CompiledTemplate {
+            len: self.len(),
+            normalize: self.normalizes_whitespace(),
+            tokens: self.collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_twice() {
+        let template = Tokenizer::new("${name}  \\$$version")
+            .normalize_whitespace(true)
+            .clone()
+            .compile();
+        assert_eq!(
+            template.tokens(),
+            &[
+                Token::Key("name"),
+                Token::Normal("  "),
+                Token::Escaped('$'),
+                Token::Key("version")
+            ]
+        );
+
+        let render = |values: &[(&str, &str)]| {
+            template.render(|buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Escaped(character) => buf.push(character),
+                    Token::Key(key) => match values.iter().find(|&&(name, _)| name == key) {
+                        Some(&(_, value)) => buf.push_str(value),
+                        None => return Err(key.to_owned()),
+                    },
+                    other => panic!("unexpected token: {:?}", other),
+                }
+                Ok(true)
+            })
+        };
+
+        assert_eq!(
+            render(&[("name", "system76"), ("version", "1.0.0")]),
+            Ok("system76 $1.0.0".into())
+        );
+        assert_eq!(
+            render(&[("name", "pop"), ("version", "22.04")]),
+            Ok("pop $22.04".into())
+        );
+        assert_eq!(render(&[("name", "pop")]), Err("version".into()));
+    }
+}
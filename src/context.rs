@@ -0,0 +1,67 @@
+//! Escaping of substituted values for the context that they are substituted into.
+
+use std::fmt::Write;
+
+/// The kind of text that values are substituted into, which determines how they are escaped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Context {
+    /// A POSIX shell command, where values are single-quoted unless they are plainly safe.
+    Shell,
+    /// A URL, where values are percent-encoded, except for unreserved characters.
+    Url,
+    /// HTML text or attribute values, where markup characters become entities.
+    Html,
+}
+
+impl Context {
+    /// Appends `value` to `buf`, escaped for this context.
+    ///
+    /// ```rust
+    /// use token_expander::Context;
+    ///
+    /// let mut buf = String::new();
+    /// Context::Url.escape(&mut buf, "a b&c");
+    /// assert_eq!(buf, "a%20b%26c");
+    /// ```
+    pub fn escape(self, buf: &mut String, value: &str) {
+        match self {
+            Context::Shell => {
+                let safe = |character: char| {
+                    character.is_ascii_alphanumeric() || "_-./=:,+@%".contains(character)
+                };
+
+                if !value.is_empty() && value.chars().all(safe) {
+                    buf.push_str(value);
+                } else {
+                    buf.push('\'');
+                    buf.push_str(&value.replace('\'', r"'\''"));
+                    buf.push('\'');
+                }
+            }
+            Context::Url => {
+                for byte in value.bytes() {
+                    match byte {
+                        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                            buf.push(byte as char)
+                        }
+                        _ => {
+                            let _ = write!(buf, "%{:02X}", byte);
+                        }
+                    }
+                }
+            }
+            Context::Html => {
+                for character in value.chars() {
+                    match character {
+                        '&' => buf.push_str("&amp;"),
+                        '<' => buf.push_str("&lt;"),
+                        '>' => buf.push_str("&gt;"),
+                        '"' => buf.push_str("&quot;"),
+                        '\'' => buf.push_str("&#39;"),
+                        _ => buf.push(character),
+                    }
+                }
+            }
+        }
+    }
+}
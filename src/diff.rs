@@ -0,0 +1,122 @@
+//! Differences between the token streams of two templates.
+
+use {TokenOwned, Tokenizer};
+
+/// A change between the tokens of two templates, created by `Tokenizer::diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenDiff {
+    /// A token which is only in the other template.
+    Added(TokenOwned),
+    /// A token which is only in this template.
+    Removed(TokenOwned),
+    /// A token which is in both templates.
+    Unchanged(TokenOwned),
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Compares the tokens of this template with those of `other`, from the current position of
+    /// each, returning the changes which turn this template into the other.
+    ///
+    /// Tokens are matched by their longest common subsequence, so that changes are reviewed by
+    /// keys and literals rather than by text. Removals precede additions where tokens were
+    /// replaced.
+    ///
+    /// ```rust
+    /// use token_expander::{TokenDiff, TokenOwned, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${a}/${b}").diff(&Tokenizer::new("${a}/${c}")),
+    ///     vec![
+    ///         TokenDiff::Unchanged(TokenOwned::Key("a".into())),
+    ///         TokenDiff::Unchanged(TokenOwned::Normal("/".into())),
+    ///         TokenDiff::Removed(TokenOwned::Key("b".into())),
+    ///         TokenDiff::Added(TokenOwned::Key("c".into())),
+    ///     ]
+    /// );
+    /// ```
+    pub fn diff<'b>(&self, other: &Tokenizer<'b>) -> Vec<TokenDiff> {
+        let old = self.iter().map(TokenOwned::from).collect::<Vec<_>>();
+        let new = other.iter().map(TokenOwned::from).collect::<Vec<_>>();
+
+        // The length of the longest common subsequence of `old[i..]` and `new[j..]`.
+        let width = new.len() + 1;
+        let mut lengths = vec![0usize; (old.len() + 1) * width];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                lengths[i * width + j] = if old[i] == new[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
+        }
+
+        let mut diff = Vec::with_capacity(old.len().max(new.len()));
+        let (mut old, mut new) = (old.into_iter().peekable(), new.into_iter().peekable());
+        let (mut i, mut j) = (0, 0);
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) if a == b => {
+                    new.next();
+                    diff.push(TokenDiff::Unchanged(old.next().unwrap()));
+                    i += 1;
+                    j += 1;
+                }
+                (Some(_), Some(_))
+                    if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] =>
+                {
+                    diff.push(TokenDiff::Removed(old.next().unwrap()));
+                    i += 1;
+                }
+                (Some(_), None) => {
+                    diff.push(TokenDiff::Removed(old.next().unwrap()));
+                    i += 1;
+                }
+                (_, Some(_)) => {
+                    diff.push(TokenDiff::Added(new.next().unwrap()));
+                    j += 1;
+                }
+                (None, None) => break,
+            }
+        }
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_key() {
+        let old = Tokenizer::new("${user}@${host}");
+        let new = Tokenizer::new("${user}@${host}:${port}");
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                TokenDiff::Unchanged(TokenOwned::Key("user".into())),
+                TokenDiff::Unchanged(TokenOwned::Normal("@".into())),
+                TokenDiff::Unchanged(TokenOwned::Key("host".into())),
+                TokenDiff::Added(TokenOwned::Normal(":".into())),
+                TokenDiff::Added(TokenOwned::Key("port".into())),
+            ]
+        );
+
+        assert_eq!(
+            new.diff(&old)
+                .into_iter()
+                .filter(|change| !matches!(change, TokenDiff::Unchanged(_)))
+                .collect::<Vec<_>>(),
+            vec![
+                TokenDiff::Removed(TokenOwned::Normal(":".into())),
+                TokenDiff::Removed(TokenOwned::Key("port".into())),
+            ]
+        );
+        assert!(old
+            .diff(&old)
+            .iter()
+            .all(|change| matches!(change, TokenDiff::Unchanged(_))));
+        assert_eq!(Tokenizer::new("").diff(&Tokenizer::new("")), vec![]);
+    }
+}
@@ -0,0 +1,165 @@
+//! Routing of tokens to a handler for each kind of token.
+
+use std::fmt;
+use {Token, TokenError, Tokenizer};
+
+/// A handler of the text of a token.
+type TextHandler<'f> = Box<dyn FnMut(&str) + 'f>;
+/// A handler of whole tokens.
+type TokenHandler<'f> = Box<dyn FnMut(Token) + 'f>;
+
+/// Handlers for each kind of token, which are invoked as a tokenizer is run, as an
+/// alternative to matching on every token within one closure. Handlers which are not
+/// registered do nothing.
+///
+/// ```rust
+/// use token_expander::{Dispatcher, Tokenizer};
+///
+/// let (mut text, mut keys) = (String::new(), Vec::new());
+/// Dispatcher::new()
+///     .on_normal(|normal| text.push_str(normal))
+///     .on_key(|token| keys.extend(token.key().map(String::from)))
+///     .run(&mut Tokenizer::new(r"${name}_\$version"))?;
+///
+/// assert_eq!(text, "_version");
+/// assert_eq!(keys, vec!["name"]);
+/// # Ok::<(), token_expander::TokenError>(())
+/// ```
+#[derive(Default)]
+pub struct Dispatcher<'f> {
+    normal: Option<TextHandler<'f>>,
+    key: Option<TokenHandler<'f>>,
+    escaped: Option<TextHandler<'f>>,
+    other: Option<TokenHandler<'f>>,
+}
+
+impl<'f> fmt::Debug for Dispatcher<'f> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("normal", &self.normal.is_some())
+            .field("key", &self.key.is_some())
+            .field("escaped", &self.escaped.is_some())
+            .field("other", &self.other.is_some())
+            .finish()
+    }
+}
+
+impl<'f> Dispatcher<'f> {
+    /// Constructs a dispatcher without any handlers.
+    pub fn new() -> Self {
+        Dispatcher::default()
+    }
+
+    /// Handles normal text.
+    pub fn on_normal<F: FnMut(&str) + 'f>(&mut self, handler: F) -> &mut Self {
+        self.normal = Some(Box::new(handler));
+        self
+    }
+
+    /// Handles any of the key variants, such as `Token::Key` and `Token::KeyDefault`.
+    pub fn on_key<F: FnMut(Token) + 'f>(&mut self, handler: F) -> &mut Self {
+        self.key = Some(Box::new(handler));
+        self
+    }
+
+    /// Handles escaped characters and grapheme clusters, given as the text they escape.
+    pub fn on_escaped<F: FnMut(&str) + 'f>(&mut self, handler: F) -> &mut Self {
+        self.escaped = Some(Box::new(handler));
+        self
+    }
+
+    /// Handles the tokens of other modes, such as `Token::Include` and `Token::Home`.
+    pub fn on_other<F: FnMut(Token) + 'f>(&mut self, handler: F) -> &mut Self {
+        self.other = Some(Box::new(handler));
+        self
+    }
+
+    /// Routes each remaining token of `tokenizer` to its handler, failing on the first
+    /// `Token::Error`.
+    pub fn run(&mut self, tokenizer: &mut Tokenizer) -> Result<(), TokenError> {
+        for token in tokenizer {
+            match token {
+                Token::Normal(text) => {
+                    if let Some(ref mut handler) = self.normal {
+                        handler(text);
+                    }
+                }
+                Token::Escaped(character) => {
+                    if let Some(ref mut handler) = self.escaped {
+                        handler(character.encode_utf8(&mut [0; 4]));
+                    }
+                }
+                Token::EscapedStr(grapheme) => {
+                    if let Some(ref mut handler) = self.escaped {
+                        handler(grapheme);
+                    }
+                }
+                Token::Error(why) => return Err(why),
+                token if token.key().is_some() => {
+                    if let Some(ref mut handler) = self.key {
+                        handler(token);
+                    }
+                }
+                token => {
+                    if let Some(ref mut handler) = self.other {
+                        handler(token);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenOwned;
+
+    #[test]
+    fn defaults() {
+        let mut keys = Vec::new();
+        let mut tokenizer = Tokenizer::new(r"a\$b/${c:-d}/$e");
+        tokenizer.default_values(true);
+        Dispatcher::new()
+            .on_key(|token| keys.push(TokenOwned::from(token)))
+            .run(&mut tokenizer)
+            .unwrap();
+
+        assert_eq!(
+            keys,
+            vec![
+                TokenOwned::KeyDefault {
+                    key: "c".into(),
+                    default: "d".into(),
+                },
+                TokenOwned::Key("e".into()),
+            ]
+        );
+        assert_eq!(tokenizer.next(), None);
+        assert!(Dispatcher::new().run(&mut Tokenizer::new(r"a\$b")).is_ok());
+    }
+
+    #[test]
+    fn routes() {
+        let (mut normal, mut escaped, mut other) = (Vec::new(), Vec::new(), Vec::new());
+        let template = r"~/a\$b${c}";
+        let result = Dispatcher::new()
+            .on_normal(|text| normal.push(text.to_owned()))
+            .on_escaped(|text| escaped.push(text.to_owned()))
+            .on_other(|token| other.push(format!("{:?}", token)))
+            .run(Tokenizer::new(template).tilde_home(true));
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(normal, vec!["/a", "b"]);
+        assert_eq!(escaped, vec!["$"]);
+        assert_eq!(other, vec!["Home(None)"]);
+
+        let template = "a${b";
+        assert_eq!(
+            Dispatcher::new().run(Tokenizer::new(template).strict(true)),
+            Err(TokenError::new(template, 1, "unterminated key".into()))
+        );
+    }
+}
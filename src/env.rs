@@ -0,0 +1,158 @@
+//! Expansion of keys from the process environment.
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use {ResolveError, Token, Tokenizer};
+
+/// How many defaults may be nested within one another before expansion is abandoned.
+const MAX_DEPTH: usize = 16;
+
+/// Failures particular to expanding keys from the environment, which are given by
+/// `ResolveError::Map`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvError {
+    /// The variable's value is not valid unicode.
+    NotUnicode(String),
+    /// Defaults were nested too deeply while resolving the variable.
+    Recursion(String),
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EnvError::NotUnicode(ref key) => write!(f, "variable is not valid unicode: {}", key),
+            EnvError::Recursion(ref key) => write!(f, "defaults nested too deeply: {}", key),
+        }
+    }
+}
+
+impl Error for EnvError {}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands keys with the values of environment variables of the same name.
+    ///
+    /// Unset variables expand to nothing. With default values enabled, the default of an
+    /// unset or empty variable is expanded as a template of its own, using this tokenizer's
+    /// configuration, so `${HOME:-$FALLBACK}` expands `$FALLBACK` when `HOME` is unset. With
+    /// required values enabled, an unset or empty required variable fails with
+    /// `ResolveError::Required`.
+    ///
+    /// ```rust
+    /// use std::env;
+    /// use token_expander::Tokenizer;
+    ///
+    /// env::set_var("EXPAND_ENV_DOC", "system76");
+    /// env::remove_var("EXPAND_ENV_DOC_UNSET");
+    /// assert_eq!(
+    ///     Tokenizer::new("${EXPAND_ENV_DOC}/${EXPAND_ENV_DOC_UNSET:-none}")
+    ///         .default_values(true)
+    ///         .expand_env(),
+    ///     Ok("system76/none".into())
+    /// );
+    /// ```
+    pub fn expand_env(&mut self) -> Result<String, ResolveError<EnvError>> {
+        self.expand_env_nested(0)
+    }
+
+    fn expand_env_nested(&mut self, depth: usize) -> Result<String, ResolveError<EnvError>> {
+        let mut output = String::with_capacity(self.data.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            match token {
+                Token::Normal(text) => output.push_str(text),
+                Token::Escaped(character) => output.push(character),
+                Token::EscapedStr(grapheme) => output.push_str(grapheme),
+                Token::Key(key)
+                | Token::PrefixedKey { name: key, .. }
+                | Token::KeyTyped { key, .. } => output.push_str(&var(key)?.unwrap_or_default()),
+                Token::KeyDecoded { key, .. } => output.push_str(&var(&key)?.unwrap_or_default()),
+                Token::KeyReplace { key, .. } => {
+                    output.push_str(&token.substitute(&var(key)?.unwrap_or_default()))
+                }
+                Token::KeyDefault { key, default } => match var(key)? {
+                    Some(ref value) if !value.is_empty() => output.push_str(value),
+                    _ if depth == MAX_DEPTH => {
+                        return Err(ResolveError::Map(EnvError::Recursion(key.into())))
+                    }
+                    _ => output.push_str(&self.rebind(default).expand_env_nested(depth + 1)?),
+                },
+                Token::KeyRequired { key, message } => match var(key)? {
+                    Some(ref value) if !value.is_empty() => output.push_str(value),
+                    _ => {
+                        return Err(ResolveError::Required {
+                            key: key.into(),
+                            message: message.into_owned(),
+                        })
+                    }
+                },
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    output.push_str(self.unresolved(token, span)?)
+                }
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+fn var(key: &str) -> Result<Option<String>, ResolveError<EnvError>> {
+    match env::var(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            Err(ResolveError::Map(EnvError::NotUnicode(key.into())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_default() {
+        let template = "${TOKEN_EXPANDER_TEST_HOME:-$TOKEN_EXPANDER_TEST_FALLBACK}/.config";
+        env::remove_var("TOKEN_EXPANDER_TEST_HOME");
+        env::set_var("TOKEN_EXPANDER_TEST_FALLBACK", "/var/fallback");
+        assert_eq!(
+            Tokenizer::new(template).default_values(true).expand_env(),
+            Ok("/var/fallback/.config".into())
+        );
+
+        env::set_var("TOKEN_EXPANDER_TEST_HOME", "/home/pop");
+        assert_eq!(
+            Tokenizer::new(template).default_values(true).expand_env(),
+            Ok("/home/pop/.config".into())
+        );
+
+        env::remove_var("TOKEN_EXPANDER_TEST_HOME");
+        env::remove_var("TOKEN_EXPANDER_TEST_FALLBACK");
+    }
+
+    #[test]
+    fn recursion() {
+        // Builds `${UNSET:-${UNSET:-...x` with one more default than may be nested. Without
+        // closing braces, each key runs to the end of the input, so its default is the next
+        // key, and every default is expanded in turn, as the variable is unset.
+        let mut template = String::from("x");
+        for _ in 0..=MAX_DEPTH {
+            template = format!("${{TOKEN_EXPANDER_TEST_UNSET:-{}", template);
+        }
+
+        // Without the trailing `-x`, the innermost key has no default, so one fewer is nested.
+        env::remove_var("TOKEN_EXPANDER_TEST_UNSET");
+        assert_eq!(
+            Tokenizer::new(&template[..template.len() - 2])
+                .default_values(true)
+                .expand_env(),
+            Ok(String::new())
+        );
+        assert_eq!(
+            Tokenizer::new(&template).default_values(true).expand_env(),
+            Err(ResolveError::Map(EnvError::Recursion(
+                "TOKEN_EXPANDER_TEST_UNSET".into()
+            )))
+        );
+    }
+}
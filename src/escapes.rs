@@ -0,0 +1,140 @@
+//! Validation of the escapes within a template.
+
+use std::error::Error;
+use std::fmt;
+use {SpannedToken, Token, Tokenizer, DECODE_ESCAPES};
+
+/// Escapes which are malformed, found by `Tokenizer::validate_escapes`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EscapeError {
+    /// The escape at this byte offset ends the input, with nothing to escape.
+    Trailing(usize),
+    /// With escape decoding enabled, a letter or digit was escaped which does not decode into
+    /// another character, such as the `q` of `\q`.
+    Unknown {
+        /// The escaped character.
+        character: char,
+        /// The byte offset of the escape.
+        offset: usize,
+    },
+}
+
+impl fmt::Display for EscapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EscapeError::Trailing(offset) => write!(f, "trailing escape at byte {}", offset),
+            EscapeError::Unknown { character, offset } => {
+                write!(f, "unknown escape of {:?} at byte {}", character, offset)
+            }
+        }
+    }
+}
+
+impl Error for EscapeError {}
+
+impl<'a> Tokenizer<'a> {
+    /// Checks the escapes from the current position, as a check before expansion, failing on
+    /// the first escape which ends the input, or, with escape decoding enabled, on the first
+    /// escape of a letter or digit which is not decoded, such as `\q`. Escapes of other
+    /// characters, such as `\$`, are always permitted.
+    ///
+    /// ```rust
+    /// use token_expander::{EscapeError, Tokenizer};
+    ///
+    /// assert_eq!(Tokenizer::new(r"\$5\n").validate_escapes(), Ok(()));
+    /// assert_eq!(
+    ///     Tokenizer::new(r"\$5\q").decode_escapes(true).validate_escapes(),
+    ///     Err(EscapeError::Unknown { character: 'q', offset: 3 })
+    /// );
+    /// ```
+    pub fn validate_escapes(&self) -> Result<(), EscapeError> {
+        let mut probe = self.clone();
+        probe.preserve_unknown_escapes(false);
+        let decodes = probe.flags & DECODE_ESCAPES != 0;
+        let escape_len = probe.escape_len();
+        for SpannedToken {
+            token,
+            span,
+            source,
+            ..
+        } in probe.spanned()
+        {
+            // A trailing escape sequence of several bytes is yielded as a `Token::EscapedStr`.
+            let character = match token {
+                Token::Escaped(_) | Token::EscapedStr(_) if span.len() == escape_len => {
+                    return Err(EscapeError::Trailing(span.start))
+                }
+                Token::Escaped(character) => character,
+                _ => continue,
+            };
+
+            let escaped = source[escape_len..].chars().next();
+            if decodes && escaped == Some(character) && character.is_alphanumeric() {
+                return Err(EscapeError::Unknown {
+                    character,
+                    offset: span.start,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean() {
+        let template = r"a\$\\${b}\{\n\u{41}";
+        assert_eq!(Tokenizer::new(template).validate_escapes(), Ok(()));
+        assert_eq!(
+            Tokenizer::new(template)
+                .decode_escapes(true)
+                .validate_escapes(),
+            Ok(())
+        );
+        assert_eq!(Tokenizer::new(r"\q").validate_escapes(), Ok(()));
+    }
+
+    #[test]
+    fn trailing() {
+        assert_eq!(
+            Tokenizer::new(r"a\$b\").validate_escapes(),
+            Err(EscapeError::Trailing(4))
+        );
+        assert_eq!(
+            Tokenizer::new("a^^")
+                .set_escape_sequence(b"^^")
+                .validate_escapes(),
+            Err(EscapeError::Trailing(1))
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        let validate = |template| {
+            Tokenizer::new(template)
+                .decode_escapes(true)
+                .preserve_unknown_escapes(true)
+                .validate_escapes()
+        };
+
+        assert_eq!(
+            validate(r"\n${a}\q"),
+            Err(EscapeError::Unknown {
+                character: 'q',
+                offset: 6,
+            })
+        );
+        assert_eq!(
+            validate(r"\u{zz}"),
+            Err(EscapeError::Unknown {
+                character: 'u',
+                offset: 0,
+            })
+        );
+        assert_eq!(validate(r"\\\$\t"), Ok(()));
+    }
+}
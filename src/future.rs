@@ -0,0 +1,192 @@
+//! Expansion of keys with values which are resolved asynchronously.
+
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{self, Poll};
+use {Token, TokenError, Tokenizer};
+
+impl<'a> Tokenizer<'a> {
+    /// Expands keys with the values of the futures returned by `resolve`.
+    ///
+    /// Keys are resolved sequentially, in the order that they appear, so the future for a key
+    /// is not created until the future of the previous key has completed. Normal text and
+    /// escaped characters are pushed as they are read. With default values enabled, the
+    /// default of a key is used when its resolved value is empty.
+    ///
+    /// ```rust
+    /// extern crate tokio;
+    /// extern crate token_expander;
+    ///
+    /// use std::future;
+    /// use token_expander::{TokenError, Tokenizer};
+    ///
+    /// # fn main() {
+    /// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    /// let expanded = runtime.block_on(Tokenizer::new("${name}.deb").expand_async(|key| {
+    ///     future::ready(Ok::<_, TokenError>(key.to_uppercase()))
+    /// }));
+    ///
+    /// assert_eq!(expanded, Ok("NAME.deb".into()));
+    /// # }
+    /// ```
+    pub fn expand_async<T, F, Fut>(&mut self, resolve: F) -> ExpandAsync<'_, 'a, F, Fut>
+    where
+        F: FnMut(&str) -> Fut,
+        Fut: Future<Output = Result<String, T>>,
+        T: From<TokenError>,
+    {
+        ExpandAsync {
+            output: String::with_capacity(self.data.len() * 2),
+            tokenizer: self,
+            resolve,
+            pending: None,
+        }
+    }
+}
+
+/// A future which expands a template, created by `Tokenizer::expand_async`.
+pub struct ExpandAsync<'t, 'a: 't, F, Fut> {
+    tokenizer: &'t mut Tokenizer<'a>,
+    resolve: F,
+    output: String,
+    /// The future resolving the current key, that key's token, and the offset of the token.
+    pending: Option<(Pin<Box<Fut>>, Token<'a>, usize)>,
+}
+
+// Neither the closure nor the pending future are structurally pinned.
+impl<'t, 'a, F, Fut> Unpin for ExpandAsync<'t, 'a, F, Fut> {}
+
+impl<'t, 'a, T, F, Fut> Future for ExpandAsync<'t, 'a, F, Fut>
+where
+    F: FnMut(&str) -> Fut,
+    Fut: Future<Output = Result<String, T>>,
+    T: From<TokenError>,
+{
+    type Output = Result<String, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if let Some((ref mut future, ref token, offset)) = this.pending {
+                let value = match future.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(result) => result?,
+                };
+
+                match *token {
+                    Token::KeyDefault { default, .. } if value.is_empty() => {
+                        this.output.push_str(default)
+                    }
+                    Token::KeyRequired { key, ref message } if value.is_empty() => {
+                        let why = this.tokenizer.required_error(offset, key, message);
+                        return Poll::Ready(Err(why.into()));
+                    }
+                    _ => this.output.push_str(&token.substitute(&value)),
+                }
+                this.pending = None;
+            }
+
+            match this
+                .tokenizer
+                .next_spanned()
+                .map(|(token, span)| (token, span.start))
+            {
+                Some((Token::Normal(text), _)) | Some((Token::EscapedStr(text), _)) => {
+                    this.output.push_str(text)
+                }
+                Some((Token::Escaped(character), _)) => this.output.push(character),
+                Some((Token::Home(user), _)) => {
+                    this.output.push('~');
+                    this.output.push_str(user.unwrap_or_default());
+                }
+                Some((Token::Error(why), _)) => return Poll::Ready(Err(why.into())),
+                Some((Token::Include(path), offset)) => {
+                    let why = this.tokenizer.include_error(offset, &path);
+                    return Poll::Ready(Err(why.into()));
+                }
+                Some((token, offset)) => {
+                    let future = (this.resolve)(token.key().unwrap_or_default());
+                    this.pending = Some((Box::pin(future), token, offset));
+                }
+                None => return Poll::Ready(Ok(mem::take(&mut this.output))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tokio;
+
+    use super::*;
+    use std::future;
+
+    /// Completes with its value after first returning `Poll::Pending`.
+    struct Deferred(Option<Result<String, TokenError>>, bool);
+
+    impl Future for Deferred {
+        type Output = Result<String, TokenError>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+            if !self.1 {
+                self.1 = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.0.take().expect("polled after completion"))
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn resolve_keys() {
+        let mut resolved = Vec::new();
+        let expanded = block_on(
+            Tokenizer::new("${name}_\\$${version}.deb").expand_async(|key| {
+                resolved.push(key.to_owned());
+                Deferred(Some(Ok(format!("<{}>", key))), false)
+            }),
+        );
+
+        assert_eq!(expanded, Ok("<name>_$<version>.deb".into()));
+        assert_eq!(resolved, vec!["name", "version"]);
+    }
+
+    #[test]
+    fn errors() {
+        let template = "${name:-none} ${broken";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.default_values(true).strict(true);
+
+        let expanded = block_on(tokenizer.expand_async(|_| future::ready(Ok(String::new()))));
+        assert_eq!(
+            expanded,
+            Err(TokenError::new(template, 14, "unterminated key".into()))
+        );
+
+        let expanded = block_on(Tokenizer::new("$a$b").expand_async(|key| {
+            future::ready(match key {
+                "a" => Ok("a".into()),
+                _ => Err(TokenError::new("", 0, format!("unresolved: {}", key))),
+            })
+        }));
+        assert_eq!(expanded.unwrap_err().reason, "unresolved: b");
+
+        let template = "a\n${b:?is unset}";
+        let expanded = block_on(
+            Tokenizer::new(template)
+                .required_values(true)
+                .expand_async(|_| future::ready(Ok::<_, TokenError>(String::new()))),
+        );
+        let why = expanded.unwrap_err();
+        assert_eq!(why, TokenError::new(template, 2, "b: is unset".into()));
+        assert_eq!((why.line, why.column), (2, 1));
+    }
+}
@@ -0,0 +1,128 @@
+//! Resolution of keys against a `serde_json::Value`, enabled by the `serde_json` feature.
+
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+
+/// Failures particular to expanding keys against a JSON tree, which are given by
+/// `ResolveError::Map`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+    /// No value exists at the given key's path.
+    Missing(String),
+    /// The value at the given key's path is an array or object.
+    NotScalar(String),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Missing(ref key) => write!(f, "no JSON value at path: {}", key),
+            JsonError::NotScalar(ref key) => write!(f, "JSON value is not a scalar: {}", key),
+        }
+    }
+}
+
+impl Error for JsonError {}
+
+/// Walks `root` along the segments of `key` split on `sep`.
+pub fn lookup<'v>(root: &'v Value, key: &str, sep: char) -> Result<&'v Value, JsonError> {
+    key.split(sep).try_fold(root, |value, segment| {
+        let next = match *value {
+            Value::Object(ref map) => map.get(segment),
+            Value::Array(ref array) => segment.parse::<usize>().ok().and_then(|id| array.get(id)),
+            _ => None,
+        };
+
+        next.ok_or_else(|| JsonError::Missing(key.into()))
+    })
+}
+
+pub(crate) fn push_leaf(buf: &mut String, leaf: &Value, key: &str) -> Result<(), JsonError> {
+    match *leaf {
+        Value::String(ref string) => buf.push_str(string),
+        Value::Number(ref number) => buf.push_str(&number.to_string()),
+        Value::Bool(boolean) => buf.push_str(if boolean { "true" } else { "false" }),
+        Value::Null => (),
+        Value::Array(_) | Value::Object(_) => return Err(JsonError::NotScalar(key.into())),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ResolveError, TokenError, Tokenizer};
+
+    fn root() -> Value {
+        ::serde_json::from_str(
+            r#"{
+                "user": { "name": "system76", "version": "1.0.0" },
+                "arch": ["amd64", "arm64"],
+                "repo": { "free": true }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn nested_object() {
+        assert_eq!(
+            Tokenizer::new("${user.name}_${user.version}").expand_json(&root(), '.'),
+            Ok("system76_1.0.0".into())
+        );
+    }
+
+    #[test]
+    fn array_index() {
+        assert_eq!(
+            Tokenizer::new("${arch/0}:${arch/1} ${repo/free}").expand_json(&root(), '/'),
+            Ok("amd64:arm64 true".into())
+        );
+    }
+
+    #[test]
+    fn missing_path() {
+        assert_eq!(
+            Tokenizer::new("${user/email}").expand_json(&root(), '/'),
+            Err(ResolveError::Map(JsonError::Missing("user/email".into())))
+        );
+        assert_eq!(
+            Tokenizer::new("${arch/2}").expand_json(&root(), '/'),
+            Err(ResolveError::Map(JsonError::Missing("arch/2".into())))
+        );
+        assert_eq!(
+            Tokenizer::new("${user}").expand_json(&root(), '/'),
+            Err(ResolveError::Map(JsonError::NotScalar("user".into())))
+        );
+    }
+
+    #[test]
+    fn include() {
+        let template = "${user/name}@include(user.tmpl)";
+        assert_eq!(
+            Tokenizer::new(template)
+                .set_include_directive(Some("@include"))
+                .expand_json(&root(), '/'),
+            Err(ResolveError::Invalid(TokenError::new(
+                template,
+                12,
+                "unsupported include: user.tmpl".into()
+            )))
+        );
+    }
+
+    #[test]
+    fn required_value() {
+        assert_eq!(
+            Tokenizer::new("${user/email:?no email}")
+                .required_values(true)
+                .expand_json(&root(), '/'),
+            Err(ResolveError::Required {
+                key: "user/email".into(),
+                message: "no email".into()
+            })
+        );
+    }
+}
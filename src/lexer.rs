@@ -1,15 +1,95 @@
+/// A set of bytes, with constant-time membership tests.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    /// Collects `bytes` into a set, which may be evaluated at compile time.
+    pub const fn new(bytes: &[u8]) -> ByteSet {
+        let mut set = [0; 4];
+        let mut index = 0;
+        while index < bytes.len() {
+            let byte = bytes[index];
+            set[(byte >> 6) as usize] |= 1 << (byte & 63);
+            index += 1;
+        }
+        ByteSet(set)
+    }
+
+    /// Whether `byte` is a member of the set.
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0[(byte >> 6) as usize] & (1 << (byte & 63)) != 0
+    }
+}
+
+/// The condition on which the lexer stops.
+#[derive(Debug, Clone, Copy)]
+enum StopOn {
+    Bytes(ByteSet),
+    Chars(fn(char) -> bool),
+}
+
 #[derive(new, Debug, SmartDefault)]
 pub struct LexerRules<'a> {
     stop_on: &'a [u8],
     #[default = b'\\']
     escape: u8,
+    /// Stops in place of `stop_on`, with a set collected in advance, or a predicate.
+    #[new(default)]
+    condition: Option<StopOn>,
+    #[new(default)]
+    escape_char: Option<char>,
 }
 
 impl<'a> LexerRules<'a> {
+    /// Rules which stop on a set that has already been collected, such as a constant.
+    pub fn with_set(stop_on: ByteSet, escape: u8) -> Self {
+        LexerRules {
+            stop_on: &[],
+            escape,
+            condition: Some(StopOn::Bytes(stop_on)),
+            escape_char: None,
+        }
+    }
+
+    /// Rules which stop on the characters matching `stop_on`, such as `char::is_whitespace`.
+    ///
+    /// This decodes each character and calls `stop_on` for it, so it is slower than stopping
+    /// on a set of bytes, as the `terminators` benchmark shows. Prefer a set when the
+    /// characters to stop on are ASCII.
+    pub fn with_predicate(stop_on: fn(char) -> bool, escape: u8) -> Self {
+        LexerRules {
+            stop_on: &[],
+            escape,
+            condition: Some(StopOn::Chars(stop_on)),
+            escape_char: None,
+        }
+    }
+
     pub fn with_escape(mut self, escape: u8) -> Self {
         self.escape = escape;
+        self.escape_char = None;
         self
     }
+
+    /// Escapes with a character, which may be encoded in more than one byte, such as `§`.
+    pub fn with_escape_char(mut self, escape: char) -> Self {
+        let mut encoded = [0; 4];
+        self.escape = escape.encode_utf8(&mut encoded).as_bytes()[0];
+        self.escape_char = if escape.is_ascii() {
+            None
+        } else {
+            Some(escape)
+        };
+        self
+    }
+
+    /// Whether `text` begins with the escape.
+    fn escapes(&self, text: &str) -> bool {
+        text.as_bytes().first() == Some(&self.escape)
+            && self
+                .escape_char
+                .is_none_or(|escape| text.starts_with(escape))
+    }
 }
 
 #[derive(new, Debug, Default)]
@@ -21,22 +101,48 @@ pub struct Lexer<'a> {
 }
 
 impl<'a> Lexer<'a> {
+    /// A lexer which stops on the characters matching `stop_on`, as with
+    /// `LexerRules::with_predicate`.
+    pub fn with_predicate(search_space: &'a str, stop_on: fn(char) -> bool, escape: u8) -> Self {
+        Lexer::new(search_space, LexerRules::with_predicate(stop_on, escape))
+    }
+
     pub fn search(&mut self) -> &'a str {
         let start = self.read;
         let mut end = start;
+        let bytes = self.search_space.as_bytes();
 
-        {
-            let mut search = self.search_space.as_bytes()[start..].iter();
-
-            while let Some(byte) = search.next() {
-                if *byte == self.rules.escape {
-                    end += 2;
-                    let _ = search.next();
-                    continue;
-                } else if self.rules.stop_on.contains(byte) {
-                    break;
-                } else {
-                    end += 1;
+        let stop_on = self.rules.stop_on;
+        let condition = self.rules.condition;
+        match condition.unwrap_or_else(|| StopOn::Bytes(ByteSet::new(stop_on))) {
+            StopOn::Bytes(stop_on) => {
+                while let Some(&byte) = bytes.get(end) {
+                    if byte == self.rules.escape && self.rules.escapes(&self.search_space[end..]) {
+                        // Skip the escape and the whole of the escaped character, which may be
+                        // absent at the end.
+                        let mut chars = self.search_space[end..].chars();
+                        end += chars.next().map_or(1, char::len_utf8);
+                        end += chars.next().map_or(0, char::len_utf8);
+                    } else if stop_on.contains(byte) {
+                        break;
+                    } else {
+                        end += 1;
+                    }
+                }
+            }
+            StopOn::Chars(stop_on) => {
+                let mut chars = self.search_space[start..].chars();
+                while let Some(character) = chars.next() {
+                    if bytes[end] == self.rules.escape
+                        && self.rules.escapes(&self.search_space[end..])
+                    {
+                        end += character.len_utf8();
+                        end += chars.next().map_or(0, char::len_utf8);
+                    } else if stop_on(character) {
+                        break;
+                    } else {
+                        end += character.len_utf8();
+                    }
                 }
             }
         }
@@ -44,3 +150,76 @@ impl<'a> Lexer<'a> {
         &self.search_space[start..end]
     }
 }
+
+#[cfg(test)]
+#[path = "../benches/support/linear.rs"]
+mod linear;
+
+#[cfg(test)]
+mod tests {
+    use super::linear::linear;
+    use super::*;
+
+    const PATTERN: &[u8] = br#"~!@#$%^&*()+-=[]\{}|;':",./<>?"#;
+
+    #[test]
+    fn byte_set() {
+        let set = ByteSet::new(PATTERN);
+        for byte in 0..=255 {
+            assert_eq!(set.contains(byte), PATTERN.contains(&byte), "byte {}", byte);
+        }
+    }
+
+    #[test]
+    fn escape_char() {
+        let lexed = |input| {
+            let rules = LexerRules::new(b"}", b'\\').with_escape_char('§');
+            Lexer::new(input, rules).search()
+        };
+
+        assert_eq!(lexed("a§}b}c"), "a§}b");
+        assert_eq!(lexed("a¢}b"), "a¢");
+        assert_eq!(lexed("a\\}b"), "a\\");
+        assert_eq!(lexed("a§"), "a§");
+        assert_eq!(lexed("a§é}"), "a§é");
+    }
+
+    #[test]
+    fn predicate() {
+        let lexed = |input| Lexer::with_predicate(input, char::is_whitespace, b'\\').search();
+
+        assert_eq!(lexed("name rest"), "name");
+        assert_eq!(lexed("na\u{a0}me"), "na");
+        assert_eq!(lexed("name\u{3000}rest"), "name");
+        assert_eq!(lexed("é_ü\u{2003}"), "é_ü");
+        assert_eq!(lexed("a\\ b c"), "a\\ b");
+        assert_eq!(lexed("a\\\u{a0}b c"), "a\\\u{a0}b");
+        assert_eq!(lexed("a\\"), "a\\");
+        assert_eq!(lexed(""), "");
+        assert_eq!(lexed(" a"), "");
+    }
+
+    #[test]
+    fn matches_linear_search() {
+        let inputs = [
+            "name",
+            "name.deb",
+            "na\\.me/x",
+            "é_ü-1",
+            "a\\",
+            "",
+            "x y",
+            "\u{7f}\u{80}}",
+            "key}",
+        ];
+        for input in &inputs {
+            for &escape in b"\\#." {
+                let expected = linear(input, PATTERN, escape);
+                let rules = LexerRules::new(PATTERN, escape);
+                assert_eq!(Lexer::new(input, rules).search(), expected);
+                let rules = LexerRules::with_set(ByteSet::new(PATTERN), escape);
+                assert_eq!(Lexer::new(input, rules).search(), expected);
+            }
+        }
+    }
+}
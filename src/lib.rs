@@ -2,31 +2,819 @@
 extern crate derive_new;
 #[macro_use]
 extern crate smart_default;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+#[cfg(feature = "unicode-segmentation")]
+extern crate unicode_segmentation;
+#[cfg(all(test, feature = "proptest"))]
+#[macro_use]
+extern crate proptest;
 
+pub mod chunked;
+pub mod compiled;
+pub mod context;
+pub mod diff;
+pub mod dispatch;
+pub mod env;
+pub mod escapes;
+#[cfg(feature = "async")]
+pub mod future;
+#[cfg(feature = "serde_json")]
+pub mod json;
 pub mod lexer;
+pub mod policy;
+pub mod positional;
+pub mod recursive;
+pub mod rope;
+pub mod sealed;
+pub mod sectioned;
+pub mod source;
+pub mod spanned;
+pub mod writer;
+
+pub use chunked::{ChunkedToken, ChunkedTokenizer};
+pub use compiled::CompiledTemplate;
+pub use context::Context;
+pub use diff::TokenDiff;
+pub use dispatch::Dispatcher;
+pub use env::EnvError;
+pub use escapes::EscapeError;
+#[cfg(feature = "async")]
+pub use future::ExpandAsync;
+pub use policy::{MissingKeyPolicy, PolicyError};
+pub use positional::PositionalError;
+pub use recursive::ExpandLimits;
+pub use rope::Rope;
+pub use sealed::SealedTokenizer;
+pub use sectioned::SectionError;
+pub use source::KeySource;
+pub use spanned::{Spanned, SpannedToken};
+pub use writer::ExpandWriter;
+
+use lexer::{ByteSet, Lexer, LexerRules};
+use rope::Piece;
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::ptr;
+use std::rc::Rc;
+use std::str::{self, Utf8Error};
+
+const ESCAPED: u32 = 1;
+const NORMALIZE_WHITESPACE: u32 = 2;
+const COLLAPSE_ESCAPES: u32 = 4;
+const DEFAULT_VALUES: u32 = 8;
+const DECODE_ESCAPES: u32 = 16;
+const TRIM_KEYS: u32 = 32;
+const STRICT: u32 = 64;
+const STRICT_BRACES: u32 = 128;
+const ESCAPE_GRAPHEME: u32 = 256;
+const TYPE_HINTS: u32 = 512;
+const SUBSTITUTIONS: u32 = 1024;
+const WHITESPACE_KEYS_LITERAL: u32 = 2048;
+const REQUIRED_VALUES: u32 = 4096;
+const BALANCED_BRACES: u32 = 8192;
+const QUOTED_KEYS: u32 = 16384;
+const URL_KEYS: u32 = 32768;
+const RAW_STRINGS: u32 = 65536;
+const CANONICAL_KEYS: u32 = 131072;
+const LOWERCASE_KEYS: u32 = 262144;
+const PRESERVE_UNKNOWN_ESCAPES: u32 = 524288;
+const RECOVER_LITERAL: u32 = 1048576;
+const DOLLAR_REQUIRES_LETTER: u32 = 2097152;
+const STRIP_BOM: u32 = 4194304;
+const TILDE_HOME: u32 = 8388608;
+const TRAILING_MARKS: u32 = 16777216;
 
-use lexer::{Lexer, LexerRules};
+/// Bytes which terminate a bareword key.
+const TERMINATORS: ByteSet = ByteSet::new(br#"~!@#$%^&*()+-=[]\{}|;':",./<>?"#);
+/// Bytes which terminate a bareword key in URL mode, which permits the `%` of
+/// percent-encoding and the `+` of form-encoded spaces.
+const URL_TERMINATORS: ByteSet = ByteSet::new(br#"~!@#$^&*()-=[]\{}|;':",./<>?"#);
+/// Bytes which terminate a braced key.
+const CLOSE_BRACE: ByteSet = ByteSet::new(b"}");
 
-const ESCAPED: u8 = 1;
+/// Names of the modes which may be enabled, as rendered by the `Debug` implementation.
+const MODES: &[(u32, &str)] = &[
+    (NORMALIZE_WHITESPACE, "normalize_whitespace"),
+    (COLLAPSE_ESCAPES, "collapse_escapes"),
+    (DEFAULT_VALUES, "default_values"),
+    (DECODE_ESCAPES, "decode_escapes"),
+    (TRIM_KEYS, "trim_keys"),
+    (STRICT, "strict"),
+    (STRICT_BRACES, "strict_braces"),
+    (ESCAPE_GRAPHEME, "escape_grapheme"),
+    (TYPE_HINTS, "type_hints"),
+    (SUBSTITUTIONS, "substitutions"),
+    (WHITESPACE_KEYS_LITERAL, "whitespace_keys_literal"),
+    (REQUIRED_VALUES, "required_values"),
+    (BALANCED_BRACES, "balanced_braces"),
+    (QUOTED_KEYS, "quoted_keys"),
+    (URL_KEYS, "url_keys"),
+    (RAW_STRINGS, "raw_strings"),
+    (CANONICAL_KEYS, "canonical_keys"),
+    (LOWERCASE_KEYS, "lowercase_keys"),
+    (PRESERVE_UNKNOWN_ESCAPES, "preserve_unknown_escapes"),
+    (RECOVER_LITERAL, "recover_literal"),
+    (DOLLAR_REQUIRES_LETTER, "dollar_requires_letter"),
+    (STRIP_BOM, "strip_bom"),
+    (TILDE_HOME, "tilde_home"),
+    (TRAILING_MARKS, "trailing_marks"),
+];
 
 /// Simple, efficient shell-like string tokenizer, and expander extraordinaire.
-#[derive(Debug, Clone)]
+///
+/// Tokenizers are equal when their input, position and configuration are equal, so that
+/// configured tokenizers may be compared in tests. Key validators are compared by address,
+/// which is not guaranteed to be unique to a function, so tokenizers with distinct
+/// validators may compare equal, and those with the same validator unequal.
+///
+/// ```rust
+/// use token_expander::{Tokenizer, TokenizerExt};
+///
+/// let mut tokenizer = Tokenizer::new("foo#${bar}");
+/// tokenizer.set_escape(b'#').trim_keys(true);
+/// let debug = format!("{:?}", tokenizer);
+/// assert!(debug.contains("escape: '#'"));
+/// assert!(debug.contains("modes: [\"trim_keys\"]"));
+/// ```
+#[derive(Clone)]
 pub struct Tokenizer<'a> {
     data: &'a str,
     read: usize,
-    flags: u8,
+    flags: u32,
     escape: u8,
+    escape_sequence: Option<EscapeSequence<'a>>,
+    prefixes: &'a [&'a str],
+    prefix_kinds: &'a [(&'a str, PrefixKind)],
+    policy: MatchPolicy,
+    brace_match: BraceMatch,
+    delimiters: Option<Delimiters<'a>>,
+    raw: Option<Delimiters<'a>>,
+    include: Option<&'a str>,
+    validator: Option<KeyValidator<'a>>,
+}
+
+/// An escape of more than one byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EscapeSequence<'a> {
+    /// A sequence of bytes, as in `^^`.
+    Bytes(&'a [u8]),
+    /// A character which is encoded in more than one byte, as in `§`.
+    Char(char),
+}
+
+/// Decides whether a key name is acceptable, describing why it is not.
+type ValidatorFn<'a> = dyn Fn(&str) -> Result<(), String> + 'a;
+
+/// A key validator, which is shared by the tokenizers that are cloned from its own.
+#[derive(Clone)]
+enum KeyValidator<'a> {
+    /// A function, as given by presets, which are constants.
+    Fn(fn(&str) -> Result<(), String>),
+    /// A closure given to `Tokenizer::with_key_validator`, which may capture its policy.
+    Closure(Rc<ValidatorFn<'a>>),
+}
+
+impl<'a> KeyValidator<'a> {
+    fn validate(&self, key: &str) -> Result<(), String> {
+        match *self {
+            KeyValidator::Fn(validator) => validator(key),
+            KeyValidator::Closure(ref validator) => validator(key),
+        }
+    }
+}
+
+/// Validators are compared by address, which is not guaranteed to be unique.
+impl<'a> PartialEq for KeyValidator<'a> {
+    fn eq(&self, other: &KeyValidator<'a>) -> bool {
+        match (self, other) {
+            (KeyValidator::Fn(a), KeyValidator::Fn(b)) => ptr::fn_addr_eq(*a, *b),
+            (KeyValidator::Closure(a), KeyValidator::Closure(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// A construct which the tokenizer was configured to reject.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenError {
+    /// The byte offset of the start of the rejected construct.
+    pub offset: usize,
+    /// The line of the rejected construct, starting from 1.
+    pub line: usize,
+    /// The character within the line of the rejected construct, starting from 1.
+    pub column: usize,
+    /// Why the construct was rejected.
+    pub reason: String,
+}
+
+impl TokenError {
+    /// Describes a construct at the given byte offset of `data`, locating its line and column.
+    pub fn new(data: &str, offset: usize, reason: String) -> TokenError {
+        let before = &data[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        TokenError {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.reason, self.line, self.column)
+    }
+}
+
+impl Error for TokenError {}
+
+/// A pair of delimiters which enclose a key without any prefix, such as the `%` of `%PATH%`.
+///
+/// A delimiter consisting of a single character is written literally by doubling it, so `%%`
+/// yields `Token::Escaped('%')`. An unterminated key is yielded as `Token::Normal` text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delimiters<'a> {
+    /// Begins a key.
+    pub open: &'a str,
+    /// Ends a key.
+    pub close: &'a str,
+}
+
+impl Delimiters<'static> {
+    /// Keys enclosed by `%`, as in `%PATH%`, used by `Tokenizer::windows`.
+    pub const PERCENT: Delimiters<'static> = Delimiters {
+        open: "%",
+        close: "%",
+    };
+
+    /// Keys enclosed by `{{` and `}}`, as in `{{ name }}`, used by `Tokenizer::mustache`.
+    pub const MUSTACHE: Delimiters<'static> = Delimiters {
+        open: "{{",
+        close: "}}",
+    };
+
+    /// Keys enclosed by `{` and `}`, as in `{0}`, used by `Tokenizer::positional`.
+    pub const BRACES: Delimiters<'static> = Delimiters {
+        open: "{",
+        close: "}",
+    };
+}
+
+impl<'a> Delimiters<'a> {
+    /// The character written by a doubled single-character delimiter at the start of `text`.
+    fn literal(&self, text: &str) -> Option<char> {
+        [self.open, self.close].iter().find_map(|delimiter| {
+            let mut chars = delimiter.chars();
+            match (chars.next(), chars.next()) {
+                (Some(character), None) if text.starts_with(&delimiter.repeat(2)) => {
+                    Some(character)
+                }
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Decides which prefix introduces a key when several configured prefixes match at once.
+///
+/// With the prefixes `$` and `$$`, the input `$$pid` is a single `pid` key under `Longest`,
+/// whereas under `First` the `$` prefix wins, and is immediately terminated by the second
+/// `$`, yielding an empty key followed by the `pid` key.
+#[derive(Debug, Clone, Copy, PartialEq, SmartDefault)]
+pub enum MatchPolicy {
+    /// Use the longest of the matching prefixes.
+    #[default]
+    Longest,
+    /// Use the matching prefix which was configured first.
+    First,
+}
+
+/// Decides which closing brace ends a braced key when balanced braces are enabled.
+///
+/// In `${a:-b}c}`, the key ends after `b` under `Lazy`, leaving `c}` as text, whereas under
+/// `Greedy` the key is `a:-b}c`.
+#[derive(Debug, Clone, Copy, PartialEq, SmartDefault)]
+pub enum BraceMatch {
+    /// End the key at the first closing brace which balances its opening brace.
+    #[default]
+    Lazy,
+    /// End the key at the last closing brace which would balance its opening brace, from the
+    /// remainder of the input. Such a brace isn't known until the whole of the input is read,
+    /// so a `ChunkedTokenizer` only considers the braces of its current chunks.
+    Greedy,
+}
+
+/// The form in which `Tokenizer::canonicalize_keys` writes keys.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyForm {
+    /// Keys without braces, as in `$name`.
+    Bareword,
+    /// Keys enclosed by braces, as in `${name}`.
+    Braced,
+}
+
+/// A tag for a prefix, given to `Tokenizer::set_prefix_kinds`, so that keys may be routed to
+/// the resolver of the prefix which introduced them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PrefixKind(pub &'static str);
+
+/// The kind of a delimiter found by `Tokenizer::delimiter_positions`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelimKind {
+    /// A prefix which begins a bareword key, as in `$name`.
+    Prefix,
+    /// A prefix followed by an opening brace, as in `${name}`.
+    OpenBrace,
+    /// A closing brace, whether or not a braced key was opened.
+    CloseBrace,
+    /// An escape, which hides the character following it.
+    Escape,
+}
+
+/// A part of a template, as split by `Tokenizer::segments`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment<'a> {
+    /// Text between keys, with escaped characters in place of their escapes.
+    Literal(Cow<'a, str>),
+    /// The name of a key, as it was written.
+    Key(&'a str),
+}
+
+/// A part of the output of `Tokenizer::expand_structured`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputPart {
+    /// Text pushed for the normal text and escaped characters between keys.
+    Text(String),
+    /// The value pushed for a key.
+    Substituted {
+        /// The name of the key.
+        key: String,
+        /// The value of the key.
+        value: String,
+    },
 }
 
 /// An individual token, which may be a variable key, an escaped character, or plain text.
-#[derive(Debug, PartialEq)]
+///
+/// Variants may be added in minor releases, so matches on tokens require a catch-all arm,
+/// which should typically reject the token, as constructs it doesn't handle would otherwise
+/// be silently dropped from the output.
+///
+/// ```rust
+/// use token_expander::{Token, Tokenizer, TokenizerExt};
+///
+/// let expanded = Tokenizer::new("${name}").expand(|buf, token| {
+///     match token {
+///         Token::Normal(text) => buf.push_str(text),
+///         Token::Escaped(character) => buf.push(character),
+///         Token::Key("name") => buf.push_str("system76"),
+///         other => return Err(format!("unsupported: {:?}", other)),
+///     }
+///     Ok(true)
+/// });
+/// assert_eq!(expanded, Ok("system76".into()));
+/// ```
+///
+/// Matches without a catch-all arm are rejected, even if every variant is listed:
+///
+/// ```rust,compile_fail
+/// use token_expander::Token;
+///
+/// fn text(token: Token) -> Option<&str> {
+///     match token {
+///         Token::Escaped(_) => None,
+///         Token::EscapedStr(text) => Some(text),
+///         Token::Key(_) => None,
+///         Token::PrefixedKey { .. } => None,
+///         Token::KeyDecoded { .. } => None,
+///         Token::KeyDefault { .. } => None,
+///         Token::KeyTyped { .. } => None,
+///         Token::KeyReplace { .. } => None,
+///         Token::KeyRequired { .. } => None,
+///         Token::Include(_) => None,
+///         Token::Normal(text) => Some(text),
+///         Token::Error(_) => None,
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Token<'a> {
-    /// The character that follows the escape byte.
+    /// The character that follows the escape byte, or the escape character itself when the
+    /// escape ends the input.
     Escaped(char),
+    /// The grapheme cluster that follows the escape byte, when it consists of more than one
+    /// character and escaping of graphemes is enabled, or an escape sequence of several bytes
+    /// which ends the input.
+    EscapedStr(&'a str),
     /// The discovered key.
     Key(&'a str),
+    /// A key introduced by a prefix which was tagged with a kind by
+    /// `Tokenizer::set_prefix_kinds`.
+    PrefixedKey {
+        /// The kind of the prefix which introduced the key.
+        kind: PrefixKind,
+        /// The name of the key.
+        name: &'a str,
+    },
+    /// A bareword or quoted key containing escaped characters, such as `$foo\.bar`, with its
+    /// escapes removed.
+    ///
+    /// The decoded name is not a slice of the template, so it cannot be yielded as a
+    /// `Token::Key`, which borrows. It is owned instead, and allocated only for keys which
+    /// contain escapes; keys without them are still yielded as `Token::Key`. Matching on
+    /// `Token::key` handles both variants alike.
+    KeyDecoded {
+        /// The name of the key, without its escapes.
+        key: String,
+        /// The name of the key, as it was written.
+        raw: &'a str,
+    },
+    /// A braced key with a fallback, as in `${key:-default}`, when default values are enabled.
+    KeyDefault {
+        /// The name of the key.
+        key: &'a str,
+        /// The unexpanded text to use when the key has no value.
+        default: &'a str,
+    },
+    /// A braced key with a type hint, as in `${port:int}`, when type hints are enabled.
+    KeyTyped {
+        /// The name of the key.
+        key: &'a str,
+        /// The name of the type.
+        ty: &'a str,
+    },
+    /// A braced key whose value has text replaced, as in `${path/-/_}`, when substitutions
+    /// are enabled.
+    KeyReplace {
+        /// The name of the key.
+        key: &'a str,
+        /// The text to search for, without its escapes.
+        search: Cow<'a, str>,
+        /// The text to replace it with, without its escapes.
+        replace: Cow<'a, str>,
+        /// Whether every occurrence is replaced, as in `${path//-/_}`, rather than the first.
+        global: bool,
+    },
+    /// A braced key which must have a value, as in `${name:?name is required}`, when required
+    /// values are enabled.
+    KeyRequired {
+        /// The name of the key.
+        key: &'a str,
+        /// The message to report when the key has no value, without its escapes.
+        message: Cow<'a, str>,
+    },
+    /// An include directive, as in `@include(path)`, when an include directive is defined.
+    /// The path is given without its escapes, and the file is left to the caller to read.
+    Include(Cow<'a, str>),
+    /// A home directory reference at the start of a path, as in `~/src` or `~user/src`, when
+    /// home references are enabled. The user is given for `~user`, and the directory is left
+    /// to the caller to resolve.
+    Home(Option<&'a str>),
     /// Text which did not contain any matched patterns.
     Normal(&'a str),
+    /// A construct which was rejected, such as a key refused by the key validator.
+    Error(TokenError),
+}
+
+impl<'a> Token<'a> {
+    /// The name of the key, for any of the key variants.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let keys = Tokenizer::new(r"$a.${b}.$c\.d")
+    ///     .filter_map(|token| token.key().map(String::from))
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(keys, vec!["a", "b", "c.d"]);
+    /// ```
+    pub fn key(&self) -> Option<&str> {
+        match *self {
+            Token::Key(key)
+            | Token::PrefixedKey { name: key, .. }
+            | Token::KeyDefault { key, .. }
+            | Token::KeyTyped { key, .. }
+            | Token::KeyReplace { key, .. }
+            | Token::KeyRequired { key, .. } => Some(key),
+            Token::KeyDecoded { ref key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Applies the replacement of a `Token::KeyReplace` to the value of its key. The search
+    /// text is matched literally, and the values of other tokens are returned unchanged.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let token = Tokenizer::new("${version/./_}").substitutions(true).next().unwrap();
+    /// assert_eq!(token.substitute("1.0.0"), "1_0.0");
+    /// ```
+    pub fn substitute<'v>(&self, value: &'v str) -> Cow<'v, str> {
+        match *self {
+            Token::KeyReplace {
+                ref search,
+                ref replace,
+                global,
+                ..
+            } if !search.is_empty() && value.contains(search.as_ref()) => Cow::Owned(if global {
+                value.replace(search.as_ref(), replace)
+            } else {
+                value.replacen(search.as_ref(), replace, 1)
+            }),
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// Renders the token as template source with the default `$` prefix, in which `escape` is
+    /// the escape byte. Keys are rendered in their braced form, and escapes are restored where
+    /// they are needed in decoded text, such as the search text of a `Token::KeyReplace`.
+    ///
+    /// For templates whose keys are all braced and terminated, concatenating the source of
+    /// every token reproduces the template, unless modes which discard text, such as
+    /// `trim_keys`, are enabled, or needless escapes are decoded. Otherwise the invariant does
+    /// not hold: a bareword key such as `$a` is rendered as `${a}`, an unterminated key gains
+    /// its closing brace, a quoted key is rendered without its quotes, and includes and errors
+    /// have no source, and are rendered as empty strings.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let template = r"\$HOME is ${home:-/root}";
+    /// let source = Tokenizer::new(template)
+    ///     .default_values(true)
+    ///     .map(|token| token.as_source(b'\\').into_owned())
+    ///     .collect::<String>();
+    /// assert_eq!(source, template);
+    /// ```
+    pub fn as_source(&self, escape: u8) -> Cow<'_, str> {
+        let escape = char::from(escape);
+        let escaped = |text: &str, special: &[char]| {
+            let mut output = String::with_capacity(text.len());
+            for character in text.chars() {
+                if character == escape || special.contains(&character) {
+                    output.push(escape);
+                }
+                output.push(character);
+            }
+            output
+        };
+
+        match *self {
+            Token::Normal(text) => Cow::Borrowed(text),
+            Token::Escaped(character) => Cow::Owned([escape, character].iter().collect()),
+            Token::EscapedStr(text) => Cow::Owned(format!("{}{}", escape, text)),
+            Token::Key(key) | Token::PrefixedKey { name: key, .. } => {
+                Cow::Owned(format!("${{{}}}", key))
+            }
+            Token::KeyDecoded { raw, .. } => Cow::Owned(format!("${{{}}}", raw)),
+            Token::KeyDefault { key, default } => Cow::Owned(format!("${{{}:-{}}}", key, default)),
+            Token::KeyTyped { key, ty } => Cow::Owned(format!("${{{}:{}}}", key, ty)),
+            Token::KeyReplace {
+                key,
+                ref search,
+                ref replace,
+                global,
+            } => Cow::Owned(format!(
+                "${{{}/{}{}/{}}}",
+                key,
+                if global { "/" } else { "" },
+                escaped(search, &['/', '}']),
+                escaped(replace, &['}']),
+            )),
+            Token::KeyRequired { key, ref message } => {
+                Cow::Owned(format!("${{{}:?{}}}", key, escaped(message, &['}'])))
+            }
+            Token::Home(user) => Cow::Owned(format!("~{}", user.unwrap_or_default())),
+            Token::Include(_) | Token::Error(_) => Cow::Borrowed(""),
+        }
+    }
+}
+
+/// An owned counterpart to `Token`, for tokens which cannot borrow from their source.
+///
+/// As with `Token`, variants may be added in minor releases.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum TokenOwned {
+    /// The character that follows the escape byte.
+    Escaped(char),
+    /// The grapheme cluster that follows the escape byte.
+    EscapedStr(String),
+    /// The discovered key.
+    Key(String),
+    /// A key introduced by a prefix which was tagged with a kind.
+    PrefixedKey {
+        /// The kind of the prefix which introduced the key.
+        kind: PrefixKind,
+        /// The name of the key.
+        name: String,
+    },
+    /// A bareword or quoted key containing escaped characters, with its escapes removed.
+    KeyDecoded {
+        /// The name of the key, without its escapes.
+        key: String,
+        /// The name of the key, as it was written.
+        raw: String,
+    },
+    /// A braced key with a fallback, as in `${key:-default}`, when default values are enabled.
+    KeyDefault {
+        /// The name of the key.
+        key: String,
+        /// The unexpanded text to use when the key has no value.
+        default: String,
+    },
+    /// A braced key with a type hint, as in `${port:int}`.
+    KeyTyped {
+        /// The name of the key.
+        key: String,
+        /// The name of the type.
+        ty: String,
+    },
+    /// A braced key whose value has text replaced, as in `${path/-/_}`.
+    KeyReplace {
+        /// The name of the key.
+        key: String,
+        /// The text to search for, without its escapes.
+        search: String,
+        /// The text to replace it with, without its escapes.
+        replace: String,
+        /// Whether every occurrence is replaced, rather than the first.
+        global: bool,
+    },
+    /// A braced key which must have a value, as in `${name:?name is required}`.
+    KeyRequired {
+        /// The name of the key.
+        key: String,
+        /// The message to report when the key has no value, without its escapes.
+        message: String,
+    },
+    /// An include directive, as in `@include(path)`.
+    Include(String),
+    /// A home directory reference at the start of a path, as in `~/src` or `~user/src`.
+    Home(Option<String>),
+    /// Text which did not contain any matched patterns.
+    Normal(String),
+    /// A construct which was rejected, such as a key refused by the key validator.
+    Error(TokenError),
+}
+
+impl TokenOwned {
+    /// Borrows this as a `Token`.
+    pub fn as_token(&self) -> Token<'_> {
+        match *self {
+            TokenOwned::Escaped(character) => Token::Escaped(character),
+            TokenOwned::EscapedStr(ref grapheme) => Token::EscapedStr(grapheme),
+            TokenOwned::Key(ref key) => Token::Key(key),
+            TokenOwned::PrefixedKey { kind, ref name } => Token::PrefixedKey { kind, name },
+            TokenOwned::KeyDecoded { ref key, ref raw } => Token::KeyDecoded {
+                key: key.clone(),
+                raw,
+            },
+            TokenOwned::KeyDefault {
+                ref key,
+                ref default,
+            } => Token::KeyDefault { key, default },
+            TokenOwned::KeyTyped { ref key, ref ty } => Token::KeyTyped { key, ty },
+            TokenOwned::KeyReplace {
+                ref key,
+                ref search,
+                ref replace,
+                global,
+            } => Token::KeyReplace {
+                key,
+                search: Cow::Borrowed(search),
+                replace: Cow::Borrowed(replace),
+                global,
+            },
+            TokenOwned::KeyRequired {
+                ref key,
+                ref message,
+            } => Token::KeyRequired {
+                key,
+                message: Cow::Borrowed(message),
+            },
+            TokenOwned::Include(ref path) => Token::Include(Cow::Borrowed(path)),
+            TokenOwned::Home(ref user) => Token::Home(user.as_deref()),
+            TokenOwned::Normal(ref text) => Token::Normal(text),
+            TokenOwned::Error(ref error) => Token::Error(error.clone()),
+        }
+    }
+}
+
+impl<'a> From<Token<'a>> for TokenOwned {
+    fn from(token: Token<'a>) -> TokenOwned {
+        match token {
+            Token::Escaped(character) => TokenOwned::Escaped(character),
+            Token::EscapedStr(grapheme) => TokenOwned::EscapedStr(grapheme.into()),
+            Token::Key(key) => TokenOwned::Key(key.into()),
+            Token::PrefixedKey { kind, name } => TokenOwned::PrefixedKey {
+                kind,
+                name: name.into(),
+            },
+            Token::KeyDecoded { key, raw } => TokenOwned::KeyDecoded {
+                key,
+                raw: raw.into(),
+            },
+            Token::KeyDefault { key, default } => TokenOwned::KeyDefault {
+                key: key.into(),
+                default: default.into(),
+            },
+            Token::KeyTyped { key, ty } => TokenOwned::KeyTyped {
+                key: key.into(),
+                ty: ty.into(),
+            },
+            Token::KeyReplace {
+                key,
+                search,
+                replace,
+                global,
+            } => TokenOwned::KeyReplace {
+                key: key.into(),
+                search: search.into_owned(),
+                replace: replace.into_owned(),
+                global,
+            },
+            Token::KeyRequired { key, message } => TokenOwned::KeyRequired {
+                key: key.into(),
+                message: message.into_owned(),
+            },
+            Token::Include(path) => TokenOwned::Include(path.into_owned()),
+            Token::Home(user) => TokenOwned::Home(user.map(String::from)),
+            Token::Normal(text) => TokenOwned::Normal(text.into()),
+            Token::Error(error) => TokenOwned::Error(error),
+        }
+    }
+}
+
+impl Tokenizer<'static> {
+    /// The configuration of `Tokenizer::new`, over empty input.
+    ///
+    /// Each preset may be applied to a tokenizer with `with_config_from`, or cloned as the
+    /// starting point of a custom configuration.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    ///
+    /// const PRESETS: [Tokenizer<'static>; 4] =
+    ///     [Tokenizer::SHELL, Tokenizer::WINDOWS, Tokenizer::MUSTACHE, Tokenizer::URL];
+    ///
+    /// let mut preset = PRESETS[1].clone();
+    /// preset.set_escape(b'#');
+    /// assert_eq!(
+    ///     Tokenizer::new("%A%#%").with_config_from(&preset).collect::<Vec<_>>(),
+    ///     vec![Token::Key("A"), Token::Escaped('%')]
+    /// );
+    /// ```
+    pub const SHELL: Tokenizer<'static> = Tokenizer {
+        data: "",
+        read: 0,
+        flags: 0,
+        escape: b'\\',
+        escape_sequence: None,
+        prefixes: &["$"],
+        prefix_kinds: &[],
+        policy: MatchPolicy::Longest,
+        brace_match: BraceMatch::Lazy,
+        delimiters: None,
+        raw: None,
+        include: None,
+        validator: None,
+    };
+
+    /// The configuration of `Tokenizer::windows`, over empty input.
+    pub const WINDOWS: Tokenizer<'static> = Tokenizer {
+        escape: b'^',
+        prefixes: &[],
+        delimiters: Some(Delimiters::PERCENT),
+        ..Tokenizer::SHELL
+    };
+
+    /// The configuration of `Tokenizer::mustache`, over empty input.
+    pub const MUSTACHE: Tokenizer<'static> = Tokenizer {
+        flags: TRIM_KEYS,
+        prefixes: &[],
+        delimiters: Some(Delimiters::MUSTACHE),
+        ..Tokenizer::SHELL
+    };
+
+    /// The configuration of `Tokenizer::url`, over empty input.
+    pub const URL: Tokenizer<'static> = Tokenizer {
+        flags: URL_KEYS,
+        ..Tokenizer::SHELL
+    };
 }
 
 impl<'a> Tokenizer<'a> {
@@ -47,286 +835,6741 @@ impl<'a> Tokenizer<'a> {
     ///     ]
     /// );
     /// ```
-    pub fn new(data: &'a str) -> Tokenizer<'a> {
+    pub const fn new(data: &'a str) -> Tokenizer<'a> {
         Tokenizer {
             data,
-            read: 0,
-            flags: 0,
-            escape: b'\\',
+            ..Tokenizer::SHELL
         }
     }
 
-    fn escaped_character(&mut self) -> Token<'a> {
-        match self.data[self.read..].chars().next() {
-            Some(char) => {
-                self.read += char.len_utf8();
-                Token::Escaped(char)
-            }
-            None => Token::Escaped('\\'),
-        }
+    /// Validates the name of each key as it is lexed, yielding a `Token::Error` with the
+    /// validator's reason in place of a rejected key.
+    ///
+    /// This centralizes the naming policy of keys, such as their length, character set, or
+    /// reserved words, which the validator may capture.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, TokenError, Tokenizer};
+    ///
+    /// let reserved = ["self", "super"];
+    /// let mut tokenizer = Tokenizer::new("${name}/${self}");
+    /// tokenizer.with_key_validator(|name| match name {
+    ///     name if reserved.contains(&name) => Err(format!("'{}' is reserved", name)),
+    ///     _ => Ok(()),
+    /// });
+    ///
+    /// assert_eq!(
+    ///     tokenizer.collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Key("name"),
+    ///         Token::Normal("/"),
+    ///         Token::Error(TokenError::new("${name}/${self}", 8, "'self' is reserved".into())),
+    ///     ]
+    /// );
+    /// ```
+    pub fn with_key_validator<F>(&mut self, validator: F) -> &mut Self
+    where
+        F: Fn(&str) -> Result<(), String> + 'a,
+    {
+        self.validator = Some(KeyValidator::Closure(Rc::new(validator)));
+        self
     }
 
-    fn check_return<S: FnMut(&mut Self), F: FnMut(&mut Self) -> Token<'a>>(
-        &mut self,
-        start: usize,
-        mut do_this: S,
-        mut else_apply: F,
-    ) -> Token<'a> {
-        let token = &self.data[start..self.read];
-
-        if token.is_empty() {
-            else_apply(self)
-        } else {
-            do_this(self);
-            Token::Normal(token)
-        }
+    /// Defines the prefixes which introduce a key, instead of `$`.
+    ///
+    /// A prefix followed by `{` begins a braced key, which ends at the next `}`.
+    /// Empty prefixes are ignored.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("@user:${home}")
+    ///         .set_prefixes(&["@"])
+    ///         .collect::<Vec<_>>(),
+    ///     vec![Token::Key("user"), Token::Normal(":${home}")]
+    /// );
+    /// ```
+    pub fn set_prefixes(&mut self, prefixes: &'a [&'a str]) -> &mut Self {
+        self.prefixes = prefixes;
+        self
     }
-}
-
-/// Trait for providing expansion abstractions to any type which implements it.
-pub trait TokenizerExt<'a>: Iterator<Item = Token<'a>> {
-    /// Retrieve the escape char being used by the tokenizer.
-    fn get_escape(&self) -> u8;
-
-    /// Define a new escape character to use instead of `\`.
-    fn set_escape(self, escape: u8) -> Self;
 
-    /// Whether the inner string is empty or not.
-    fn is_empty(&self) -> bool {
-        self.len() != 0
+    /// Defines prefixes which introduce a key, alongside those of `set_prefixes`, each tagged
+    /// with a kind, so that one template may route keys to several resolvers. A key which is
+    /// introduced by a tagged prefix is yielded as a `Token::PrefixedKey` carrying its kind.
+    ///
+    /// Only plain keys are tagged: keys with escapes, or with the syntax of other modes, such
+    /// as `${key:-default}`, are yielded as usual. Under `MatchPolicy::First`, untagged
+    /// prefixes are considered before tagged prefixes.
+    ///
+    /// ```rust
+    /// use token_expander::{PrefixKind, Token, Tokenizer};
+    ///
+    /// const CONFIG: PrefixKind = PrefixKind("config");
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("$user:@{home}")
+    ///         .set_prefix_kinds(&[("@", CONFIG)])
+    ///         .collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Key("user"),
+    ///         Token::Normal(":"),
+    ///         Token::PrefixedKey { kind: CONFIG, name: "home" },
+    ///     ]
+    /// );
+    /// ```
+    pub fn set_prefix_kinds(&mut self, prefix_kinds: &'a [(&'a str, PrefixKind)]) -> &mut Self {
+        self.prefix_kinds = prefix_kinds;
+        self
     }
 
-    /// The length of the inner string.
-    fn len(&self) -> usize;
-
-    /// The number of bytes that have been read from the inner string.
-    fn read(&self) -> usize;
-
-    /// Abstraction for handling consumption and expansion of tokens.
+    /// Constructs a tokenizer for `cmd.exe`-style templates, where keys are enclosed by `%`
+    /// rather than introduced by a prefix, `%%` is a literal `%`, and `^` is the escape.
     ///
-    /// # Notes
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
     ///
-    /// If the `map` closure returns `Ok(false)` or `Err(why)`, expansion will stop early.
+    /// assert_eq!(
+    ///     Tokenizer::windows(r"%USERPROFILE%\100%%").collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Key("USERPROFILE"),
+    ///         Token::Normal(r"\100"),
+    ///         Token::Escaped('%'),
+    ///     ]
+    /// );
+    /// ```
+    pub const fn windows(data: &'a str) -> Tokenizer<'a> {
+        Tokenizer {
+            data,
+            ..Tokenizer::WINDOWS
+        }
+    }
+
+    /// Constructs a tokenizer for Mustache and Handlebars-style templates, where keys are
+    /// enclosed by `{{` and `}}`, and whitespace around the key is trimmed.
     ///
-    /// # Example
+    /// Triple-brace raw keys, such as `{{{ raw }}}`, are not supported, and are yielded as a
+    /// `Token::Error` which spans the whole construct.
     ///
     /// ```rust
-    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    /// use token_expander::{Token, Tokenizer};
     ///
-    /// let url = "https://$domain/$repo/$name/${name}_${version}_$arch.deb";
     /// assert_eq!(
-    ///     Tokenizer::new(url).expand(|buf, key| {
-    ///         match key {
-    ///             Token::Normal(text)       => buf.push_str(text),
-    ///             Token::Key("name")        => buf.push_str("system76"),
-    ///             Token::Key("version")     => buf.push_str("1.0.0"),
-    ///             Token::Key("arch")        => buf.push_str("amd64"),
-    ///             Token::Key("domain")      => buf.push_str("apt.pop-os.org"),
-    ///             Token::Key("repo")        => buf.push_str("free"),
-    ///             Token::Key(other)         => return Err(format!("unsupported key: {}", other)),
-    ///             Token::Escaped('n')       => buf.push('\n'),
-    ///             Token::Escaped('t')       => buf.push('\t'),
-    ///             Token::Escaped(character) => buf.push(character),
-    ///         }
-    ///         Ok(true)
-    ///     }),
-    ///     Ok("https://apt.pop-os.org/free/system76/system76_1.0.0_amd64.deb".into())
+    ///     Tokenizer::mustache("Hello, {{ name }}!").collect::<Vec<_>>(),
+    ///     vec![Token::Normal("Hello, "), Token::Key("name"), Token::Normal("!")]
     /// );
     /// ```
-    fn expand<T, F>(&mut self, mut map: F) -> Result<String, T>
-    where
-        F: FnMut(&mut String, Token) -> Result<bool, T>,
-    {
-        let mut output = String::with_capacity(self.len() * 2);
-        for token in self {
-            if !map(&mut output, token)? {
-                break;
-            }
+    pub const fn mustache(data: &'a str) -> Tokenizer<'a> {
+        Tokenizer {
+            data,
+            ..Tokenizer::MUSTACHE
         }
-
-        output.shrink_to_fit();
-        Ok(output)
     }
-}
 
-impl<'a> TokenizerExt<'a> for Tokenizer<'a> {
-    fn get_escape(&self) -> u8 {
-        self.escape
+    /// Constructs a tokenizer for URL templates, in which bareword keys may contain the `%`
+    /// of percent-encoding and the `+` of form-encoded spaces.
+    ///
+    /// Bareword keys are terminated by the same characters as by default, aside from `%` and
+    /// `+`. In particular, the URL delimiters `:`, `/`, `?`, `#`, `@`, `&` and `=` terminate
+    /// keys, as do `.`, `-` and `~`, so a host such as `$name.example.com` reads the key
+    /// `name`. Braced keys are unaffected.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::url("https://$user@$host:$port/$path").collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Normal("https://"),
+    ///         Token::Key("user"),
+    ///         Token::Normal("@"),
+    ///         Token::Key("host"),
+    ///         Token::Normal(":"),
+    ///         Token::Key("port"),
+    ///         Token::Normal("/"),
+    ///         Token::Key("path"),
+    ///     ]
+    /// );
+    /// ```
+    pub const fn url(data: &'a str) -> Tokenizer<'a> {
+        Tokenizer {
+            data,
+            ..Tokenizer::URL
+        }
     }
 
-    fn set_escape(mut self, escape: u8) -> Self {
-        self.escape = escape;
-        self
+    /// Permits the `%` and `+` characters within bareword keys, as in the `Tokenizer::url`
+    /// preset.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("$a+b%20c").url_keys(true).collect::<Vec<_>>(),
+    ///     vec![Token::Key("a+b%20c")]
+    /// );
+    /// ```
+    pub fn url_keys(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(URL_KEYS, enable)
     }
 
-    fn len(&self) -> usize {
-        self.data.len()
-    }
+    /// Only begins a bareword key where the prefix is followed by a letter or `_`, so that
+    /// currency such as `$5.00` is literal text. Braced keys, such as `${5}`, are unaffected.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("$5.00 for ${item}").dollar_requires_letter(true).collect::<Vec<_>>(),
+    ///     vec![Token::Normal("$5.00 for "), Token::Key("item")]
+    /// );
+    /// ```
+    pub fn dollar_requires_letter(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(DOLLAR_REQUIRES_LETTER, enable)
+    }
+
+    /// Permits a bareword key to end with a single `!` or `?`, as in `$present?`, for
+    /// languages with method-like names. The mark is only part of the key where it ends the
+    /// input or is followed by a character which terminates a key, so `$a?/b` reads the key
+    /// `a?`, while `$a?b` reads the key `a` followed by the text `?b`.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("$value!/$present?").trailing_marks(true).collect::<Vec<_>>(),
+    ///     vec![Token::Key("value!"), Token::Normal("/"), Token::Key("present?")]
+    /// );
+    /// ```
+    pub fn trailing_marks(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(TRAILING_MARKS, enable)
+    }
+
+    /// Skips a UTF-8 byte order mark at the start of the input, as may begin templates which
+    /// are read from files, rather than yielding it within the first token.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("\u{FEFF}name=$name").strip_bom(true).collect::<Vec<_>>(),
+    ///     vec![Token::Normal("name="), Token::Key("name")]
+    /// );
+    /// ```
+    pub fn strip_bom(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(STRIP_BOM, enable)
+    }
+
+    /// Yields a `~` or `~user` at the start of a path as a `Token::Home`, for the caller to
+    /// resolve to a home directory.
+    ///
+    /// As in shells, the `~` must begin the input or follow a `/`, and must be followed by a
+    /// `/` or the end of the input, with any user name in between consisting of alphanumeric
+    /// characters, `_`, `-` and `.`. Any other `~` is literal text.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("~/src:~pop/src").tilde_home(true).collect::<Vec<_>>(),
+    ///     vec![Token::Home(None), Token::Normal("/src:~pop/src")]
+    /// );
+    /// ```
+    pub fn tilde_home(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(TILDE_HOME, enable)
+    }
+
+    /// Rejects unterminated keys, such as a `${` without a closing `}`, yielding a
+    /// `Token::Error` which locates the start of the key.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// match Tokenizer::new("name=$name\nversion=${version").strict(true).last() {
+    ///     Some(Token::Error(why)) => assert_eq!((why.line, why.column), (2, 9)),
+    ///     other => panic!("expected an error: {:?}", other),
+    /// }
+    /// ```
+    pub fn strict(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(STRICT, enable)
+    }
+
+    /// Yields an unterminated braced key, such as an accidental `${` without a closing `}`, as
+    /// the literal text it was written as, rather than as a key which extends to the end of
+    /// the input. Strict mode takes precedence, rejecting the key instead.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${abc").recover_literal(true).collect::<Vec<_>>(),
+    ///     vec![Token::Normal("${abc")]
+    /// );
+    /// ```
+    pub fn recover_literal(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(RECOVER_LITERAL, enable)
+    }
+
+    /// Escapes the whole grapheme cluster following the escape byte, yielding a
+    /// `Token::EscapedStr` when it consists of more than one character.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("\\e\u{301}$a").escape_grapheme(true).collect::<Vec<_>>(),
+    ///     vec![Token::EscapedStr("e\u{301}"), Token::Key("a")]
+    /// );
+    /// ```
+    #[cfg(feature = "unicode-segmentation")]
+    pub fn escape_grapheme(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(ESCAPE_GRAPHEME, enable)
+    }
+
+    /// Rejects an unescaped `}` outside of a key, which often indicates a missing `${`,
+    /// yielding a `Token::Error` in place of the brace.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// match Tokenizer::new("name=$name}").strict_braces(true).last() {
+    ///     Some(Token::Error(why)) => assert_eq!(why.offset, 10),
+    ///     other => panic!("expected an error: {:?}", other),
+    /// }
+    /// ```
+    pub fn strict_braces(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(STRICT_BRACES, enable)
+    }
+
+    /// Trims whitespace surrounding the names of braced and delimited keys, so that
+    /// `${ name }` yields `Token::Key("name")`.
+    pub fn trim_keys(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(TRIM_KEYS, enable)
+    }
+
+    /// Normalizes the names of keys of every form, so that consumers match a single name.
+    /// Keys are trimmed, as with `trim_keys`, and the escapes of braced keys are removed, as
+    /// they are from bareword keys.
+    ///
+    /// A key is yielded as a `Token::Key` when it borrows from the template, and as a
+    /// `Token::KeyDecoded` only when normalization changes its bytes. The keys of other
+    /// variants, such as `Token::KeyDefault`, are trimmed, but escapes remain within them.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"${ name }/${a\.b}").canonical_keys(true).collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Key("name"),
+    ///         Token::Normal("/"),
+    ///         Token::KeyDecoded { key: "a.b".into(), raw: r"a\.b" },
+    ///     ]
+    /// );
+    /// ```
+    pub fn canonical_keys(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(CANONICAL_KEYS, enable)
+    }
+
+    /// Lowercases the names of keys, such as alongside `canonical_keys`, so that keys are
+    /// matched regardless of case. A key which contains uppercase characters is yielded as a
+    /// `Token::KeyDecoded`, and the keys of other variants, such as `Token::KeyDefault`, are
+    /// left as they were written.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let keys = Tokenizer::new("${ Name }/$name")
+    ///     .canonical_keys(true)
+    ///     .lowercase_keys(true)
+    ///     .filter_map(|token| token.key().map(String::from))
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(keys, vec!["name", "name"]);
+    /// ```
+    pub fn lowercase_keys(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(LOWERCASE_KEYS, enable)
+    }
+
+    /// Defines delimiters which enclose keys without a prefix, alongside any prefixes.
+    /// Delimiters with an empty opening delimiter are ignored, as they would begin a key at
+    /// every position.
+    pub fn set_delimiters(&mut self, delimiters: Option<Delimiters<'a>>) -> &mut Self {
+        self.delimiters = delimiters.filter(|delimiters| !delimiters.open.is_empty());
+        self
+    }
+
+    /// Defines delimiters which enclose a raw block, the contents of which are yielded verbatim
+    /// as a single `Token::Normal`, without interpreting keys or escapes.
+    ///
+    /// An unterminated raw block extends to the end of the input, or yields a `Token::Error`
+    /// in strict mode.
+    ///
+    /// ```rust
+    /// use token_expander::{Delimiters, Token, Tokenizer};
+    ///
+    /// let raw = Delimiters { open: "{{raw}}", close: "{{/raw}}" };
+    /// let mut tokenizer = Tokenizer::new("${a} {{raw}}$b \\n{{/raw}}");
+    /// assert_eq!(
+    ///     tokenizer.set_raw_delimiters(Some(raw)).collect::<Vec<_>>(),
+    ///     vec![Token::Key("a"), Token::Normal(" "), Token::Normal("$b \\n")]
+    /// );
+    /// ```
+    pub fn set_raw_delimiters(&mut self, raw: Option<Delimiters<'a>>) -> &mut Self {
+        self.raw = raw;
+        self
+    }
+
+    /// Reads raw strings in the style of Rust, as in `r"$x"`, the contents of which are
+    /// yielded verbatim as a single `Token::Normal`, without interpreting keys or escapes.
+    /// Quotes may be embedded by opening the string with hashes, as in `r#"a"b"#`, which is
+    /// then closed by a quote followed by as many hashes.
+    ///
+    /// The `r` must not follow a letter, a digit, or an underscore, so that words ending in
+    /// `r` may be followed by quotes. As with raw blocks, an unterminated raw string extends to
+    /// the end of the input, or yields a `Token::Error` in strict mode.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r#"match r"^\$\d+" on $input"#)
+    ///         .raw_strings(true)
+    ///         .collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Normal("match "),
+    ///         Token::Normal(r"^\$\d+"),
+    ///         Token::Normal(" on "),
+    ///         Token::Key("input"),
+    ///     ]
+    /// );
+    /// ```
+    pub fn raw_strings(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(RAW_STRINGS, enable)
+    }
+
+    /// Defines the name of an include directive, such as `@include`, which is yielded as a
+    /// `Token::Include` with the path between the parentheses that follow it. Parentheses may
+    /// be escaped within the path.
+    ///
+    /// An unterminated directive extends to the end of the input as normal text, or yields a
+    /// `Token::Error` in strict mode. The expanders which resolve keys by name reject includes.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut tokenizer = Tokenizer::new(r"@include(header.tmpl)\n$body");
+    /// assert_eq!(
+    ///     tokenizer.set_include_directive(Some("@include")).collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Include("header.tmpl".into()),
+    ///         Token::Escaped('n'),
+    ///         Token::Key("body"),
+    ///     ]
+    /// );
+    /// ```
+    pub fn set_include_directive(&mut self, directive: Option<&'a str>) -> &mut Self {
+        self.include = directive.filter(|directive| !directive.is_empty());
+        self
+    }
+
+    /// Ends braced keys at the closing brace which balances their opening brace, counting the
+    /// braces nested within them, so that `${a:-${b}}` is a single key with the default `${b}`.
+    /// Escaped braces are not counted. Which balancing brace ends the key is decided by
+    /// `set_brace_match`.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${a:-${b}}!")
+    ///         .default_values(true)
+    ///         .balanced_braces(true)
+    ///         .collect::<Vec<_>>(),
+    ///     vec![Token::KeyDefault { key: "a", default: "${b}" }, Token::Normal("!")]
+    /// );
+    /// ```
+    pub fn balanced_braces(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(BALANCED_BRACES, enable)
+    }
+
+    /// Defines which balancing brace ends a braced key when balanced braces are enabled. The
+    /// default is `Lazy`.
+    pub fn set_brace_match(&mut self, brace_match: BraceMatch) -> &mut Self {
+        self.brace_match = brace_match;
+        self
+    }
+
+    /// Defines how to choose between overlapping prefixes. The default is `Longest`.
+    pub fn set_match_policy(&mut self, policy: MatchPolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Iterates over the tokens of this tokenizer without consuming or mutating it.
+    ///
+    /// The cursor is cloned internally, so each call begins from the tokenizer's current
+    /// position, and the original may be iterated again afterwards.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let tokenizer = Tokenizer::new("${name}.deb");
+    /// assert_eq!(
+    ///     tokenizer.iter().collect::<Vec<_>>(),
+    ///     vec![Token::Key("name"), Token::Normal(".deb")]
+    /// );
+    /// assert_eq!(tokenizer.iter().count(), 2);
+    /// ```
+    pub fn iter(&self) -> TokenizerIter<'_, 'a> {
+        TokenizerIter {
+            tokenizer: self.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Whether the template references `key`, in either its bareword or braced form.
+    ///
+    /// Tokenization stops at the first reference, without modifying this tokenizer.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let tokenizer = Tokenizer::new("https://$domain/${name}.deb");
+    /// assert!(tokenizer.references("domain"));
+    /// assert!(tokenizer.references("name"));
+    /// assert!(!tokenizer.references("arch"));
+    /// ```
+    pub fn references(&self, key: &str) -> bool {
+        self.iter().any(|token| token.key() == Some(key))
+    }
+
+    /// Defines a sequence of bytes which escapes the character that follows it, for formats
+    /// which escape with a sequence such as `^^` rather than a single byte. An occurrence of
+    /// the sequence's first byte which does not begin the whole sequence is normal text.
+    ///
+    /// Within keys, the first character of the sequence escapes, as a single escape byte would.
+    /// A sequence of a single character is equivalent to `set_escape_char`. A sequence which is
+    /// empty or is not valid UTF-8 leaves the escape unchanged, as it could end partway through
+    /// a character of the input, and `set_escape` replaces the sequence.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut tokenizer = Tokenizer::new("^^$5 ^$name");
+    /// assert_eq!(
+    ///     tokenizer.set_escape_sequence(b"^^").collect::<Vec<_>>(),
+    ///     vec![Token::Escaped('$'), Token::Normal("5 ^"), Token::Key("name")]
+    /// );
+    /// ```
+    pub fn set_escape_sequence(&mut self, sequence: &'a [u8]) -> &mut Self {
+        let mut chars = match str::from_utf8(sequence) {
+            Ok(text) => text.chars(),
+            Err(_) => return self,
+        };
+
+        match (chars.next(), chars.next()) {
+            (Some(escape), None) => return self.set_escape_char(escape),
+            (Some(_), Some(_)) => {
+                self.escape = sequence[0];
+                self.escape_sequence = Some(EscapeSequence::Bytes(sequence));
+            }
+            (None, _) => (),
+        }
+        self
+    }
+
+    /// Defines a character to escape with, which unlike `set_escape` may be encoded in more
+    /// than one byte, such as `§`. Within keys, the whole character escapes.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("§$5 ${a§}b}").set_escape_char('§').collect::<Vec<_>>(),
+    ///     vec![Token::Escaped('$'), Token::Normal("5 "), Token::Key("a§}b")]
+    /// );
+    /// ```
+    pub fn set_escape_char(&mut self, escape: char) -> &mut Self {
+        let mut encoded = [0; 4];
+        self.escape = escape.encode_utf8(&mut encoded).as_bytes()[0];
+        self.escape_sequence = if escape.is_ascii() {
+            None
+        } else {
+            Some(EscapeSequence::Char(escape))
+        };
+        self
+    }
+
+    /// The length of the escape byte or sequence.
+    pub(crate) fn escape_len(&self) -> usize {
+        match self.escape_sequence {
+            Some(EscapeSequence::Bytes(sequence)) => sequence.len(),
+            Some(EscapeSequence::Char(escape)) => escape.len_utf8(),
+            None => 1,
+        }
+    }
+
+    /// The escape sequence, if it may be split between the chunks of a `ChunkedTokenizer`.
+    pub(crate) fn partial_escape(&self) -> Option<&'a [u8]> {
+        match self.escape_sequence {
+            Some(EscapeSequence::Bytes(sequence)) => Some(sequence),
+            _ => None,
+        }
+    }
+
+    /// The escape, as the character which escapes within keys.
+    fn escape_char(&self) -> char {
+        match self.escape_sequence {
+            Some(EscapeSequence::Char(escape)) => escape,
+            Some(EscapeSequence::Bytes(sequence)) => str::from_utf8(sequence)
+                .ok()
+                .and_then(|sequence| sequence.chars().next())
+                .unwrap_or(self.escape as char),
+            None => self.escape as char,
+        }
+    }
+
+    /// Whether the escape byte or sequence begins at `position`.
+    fn at_escape(&self, position: usize) -> bool {
+        let bytes = self.data.as_bytes();
+        bytes[position] == self.escape
+            && match self.escape_sequence {
+                Some(EscapeSequence::Bytes(sequence)) => bytes[position..].starts_with(sequence),
+                Some(EscapeSequence::Char(escape)) => self.data[position..].starts_with(escape),
+                None => true,
+            }
+    }
+
+    /// Lexer rules which stop on `stop_on`, escaping as keys are escaped.
+    fn rules(&self, stop_on: ByteSet) -> LexerRules<'static> {
+        LexerRules::with_set(stop_on, self.escape).with_escape_char(self.escape_char())
+    }
+
+    /// The bytes which terminate a bareword key.
+    fn terminators(&self) -> ByteSet {
+        if self.flags & URL_KEYS != 0 {
+            URL_TERMINATORS
+        } else {
+            TERMINATORS
+        }
+    }
+
+    /// Whether the escape byte has been read, so that the next token will be the escaped
+    /// character which follows it.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut tokenizer = Tokenizer::new("a\\$b");
+    /// assert_eq!(tokenizer.next(), Some(Token::Normal("a")));
+    /// assert!(tokenizer.escape_pending());
+    /// assert_eq!(tokenizer.next(), Some(Token::Escaped('$')));
+    /// assert!(!tokenizer.escape_pending());
+    /// ```
+    pub fn escape_pending(&self) -> bool {
+        self.flags & ESCAPED != 0
+    }
+
+    /// The length in bytes of the source of the next token, without consuming it, such as for
+    /// moving a cursor over a template a token at a time.
+    ///
+    /// This is the whole construct of a key, the escape and the character of an escaped
+    /// character, and the text of normal text. Text which precedes an escape is yielded after
+    /// the escape has been read, so reading it advances past its text and the escape.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let mut tokenizer = Tokenizer::new("${name}.deb");
+    /// assert_eq!(tokenizer.peek_len(), Some(7));
+    /// tokenizer.next();
+    /// assert_eq!(tokenizer.peek_len(), Some(4));
+    /// tokenizer.next();
+    /// assert_eq!(tokenizer.peek_len(), None);
+    /// ```
+    pub fn peek_len(&self) -> Option<usize> {
+        self.clone().next_spanned().map(|(_, span)| span.len())
+    }
+
+    /// The distinct characters that follow an escape, in order of their first appearance.
+    ///
+    /// Characters are reported as they are written, without decoding, so `\n` is reported as
+    /// `'n'` even when escape decoding is enabled.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let tokenizer = Tokenizer::new(r"a\nb\tc\$d\n");
+    /// assert_eq!(tokenizer.escapes_used(), vec!['n', 't', '$']);
+    /// ```
+    pub fn escapes_used(mut self) -> Vec<char> {
+        self.flags &= !DECODE_ESCAPES;
+        let mut escapes = Vec::new();
+        for token in self {
+            if let Token::Escaped(character) = token {
+                if !escapes.contains(&character) {
+                    escapes.push(character);
+                }
+            }
+        }
+        escapes
+    }
+
+    /// Splits a template containing exactly one key, and no escapes, into its prefix, key, and
+    /// suffix, without allocating.
+    ///
+    /// Returns `None` for any other template. The remaining input is consumed either way.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("/usr/share/${name}.desktop").single_key(),
+    ///     Some(("/usr/share/", "name", ".desktop"))
+    /// );
+    /// assert_eq!(Tokenizer::new("$a$b").single_key(), None);
+    /// ```
+    pub fn single_key(&mut self) -> Option<(&'a str, &'a str, &'a str)> {
+        let mut parts = ("", None, "");
+        for token in self {
+            parts = match (token, parts) {
+                (Token::Normal(text), ("", None, "")) => (text, None, ""),
+                (Token::Key(key), (prefix, None, "")) => (prefix, Some(key), ""),
+                (Token::Normal(text), (prefix, Some(key), "")) => (prefix, Some(key), text),
+                _ => return None,
+            };
+        }
+
+        match parts {
+            (prefix, Some(key), suffix) => Some((prefix, key, suffix)),
+            _ => None,
+        }
+    }
+
+    /// Estimates the length of the expanded output without expanding, given the length that
+    /// each key will expand to.
+    ///
+    /// `Normal` text counts by its length and escaped characters by their UTF-8 length, so the
+    /// estimate is exact for expansions which push each token verbatim. With whitespace
+    /// normalization enabled, the estimate is an upper bound.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let tokenizer = Tokenizer::new("${name}_${version}.deb");
+    /// let estimate = tokenizer.estimate_len(|key| match key {
+    ///     "name" => 8,
+    ///     _ => 5,
+    /// });
+    /// let mut buffer = String::with_capacity(estimate);
+    /// buffer.push_str("system76_1.0.0.deb");
+    /// assert_eq!(estimate, buffer.len());
+    /// ```
+    pub fn estimate_len<F: Fn(&str) -> usize>(&self, key_len: F) -> usize {
+        self.iter()
+            .map(|token| match token {
+                Token::Normal(text) | Token::EscapedStr(text) => text.len(),
+                Token::Key(key)
+                | Token::PrefixedKey { name: key, .. }
+                | Token::KeyDefault { key, .. }
+                | Token::KeyTyped { key, .. }
+                | Token::KeyReplace { key, .. }
+                | Token::KeyRequired { key, .. } => key_len(key),
+                Token::KeyDecoded { key, .. } => key_len(&key),
+                Token::Escaped(character) => character.len_utf8(),
+                Token::Home(user) => 1 + user.map_or(0, str::len),
+                Token::Include(_) | Token::Error(_) => 0,
+            })
+            .sum()
+    }
+
+    /// Replaces the configuration of this tokenizer, such as its escape, prefixes, delimiters,
+    /// and modes, with that of `other`, while keeping its own data and position. A tokenizer
+    /// may thereby serve as a profile which is applied to many templates.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    ///
+    /// let mut profile = Tokenizer::new("");
+    /// profile.set_escape(b'^').strict(true);
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("^$a$b").with_config_from(&profile).collect::<Vec<_>>(),
+    ///     vec![Token::Escaped('$'), Token::Normal("a"), Token::Key("b")]
+    /// );
+    /// ```
+    pub fn with_config_from(self, other: &Tokenizer<'a>) -> Self {
+        Tokenizer {
+            data: self.data,
+            read: self.read,
+            flags: (other.flags & !ESCAPED) | (self.flags & ESCAPED),
+            validator: other.validator.clone(),
+            ..*other
+        }
+    }
+
+    /// A tokenizer over `data`, sharing this tokenizer's configuration.
+    pub(crate) fn rebind<'b>(&self, data: &'b str) -> Tokenizer<'b>
+    where
+        'a: 'b,
+    {
+        Tokenizer {
+            data,
+            read: 0,
+            flags: self.flags & !ESCAPED,
+            validator: self.validator.clone(),
+            ..*self
+        }
+    }
+
+    /// Fetches the next token alongside the range of source bytes it was parsed from.
+    pub(crate) fn next_spanned(&mut self) -> Option<(Token<'a>, Range<usize>)> {
+        self.skip_bom();
+        let escaped = self.flags & ESCAPED != 0;
+        let start = self.read;
+        let token = self.next()?;
+        let end = self.read;
+        let span = match token {
+            // Text preceding an escape is returned after the escape byte has been consumed,
+            // while the text of a raw block lies within its delimiters.
+            Token::Normal(_) if self.flags & ESCAPED != 0 => start..end - self.escape_len(),
+            Token::Normal(_) if !escaped => start..end,
+            // The escape was consumed alongside the preceding token.
+            Token::Normal(_) | Token::Escaped(_) | Token::EscapedStr(_) if escaped => {
+                start - self.escape_len()..end
+            }
+            _ => start..end,
+        };
+
+        Some((token, span))
+    }
+
+    /// Skips a byte order mark at the start of the input, when stripping it is enabled.
+    fn skip_bom(&mut self) {
+        if self.read == 0 && self.flags & STRIP_BOM != 0 && self.data.starts_with('\u{FEFF}') {
+            self.read = '\u{FEFF}'.len_utf8();
+        }
+    }
+
+    /// Consumes what follows the escape byte. This is a single character, so an escaped
+    /// grapheme cluster of several characters, such as `e` followed by a combining accent,
+    /// escapes only its first character, unless escaping of graphemes is enabled.
+    fn escaped_character(&mut self) -> Token<'a> {
+        #[cfg(feature = "unicode-segmentation")]
+        {
+            use unicode_segmentation::UnicodeSegmentation;
+
+            if self.flags & ESCAPE_GRAPHEME != 0 {
+                let remaining = &self.data[self.read..];
+                if let Some(grapheme) = remaining.graphemes(true).next() {
+                    if grapheme.chars().nth(1).is_some() {
+                        self.read += grapheme.len();
+                        return Token::EscapedStr(grapheme);
+                    }
+                }
+            }
+        }
+
+        match self.data[self.read..].chars().next() {
+            Some(char) => {
+                self.read += char.len_utf8();
+                if self.flags & COLLAPSE_ESCAPES != 0 && char == self.escape_char() {
+                    let pair = [char; 2].iter().collect::<String>();
+                    while self.data[self.read..].starts_with(&pair) {
+                        self.read += pair.len();
+                    }
+                }
+                if self.flags & DECODE_ESCAPES != 0 {
+                    let decoded = self.decode(char);
+                    if decoded == char
+                        && char.is_alphanumeric()
+                        && self.flags & PRESERVE_UNKNOWN_ESCAPES != 0
+                    {
+                        let start = self.read - char.len_utf8() - self.escape_len();
+                        return Token::Normal(&self.data[start..self.read]);
+                    }
+                    return Token::Escaped(decoded);
+                }
+                Token::Escaped(char)
+            }
+            // A sequence of bytes is not one character, so a trailing sequence is yielded as
+            // it was written.
+            None => match self.escape_sequence {
+                Some(EscapeSequence::Bytes(_)) => {
+                    Token::EscapedStr(&self.data[self.read - self.escape_len()..])
+                }
+                _ => Token::Escaped(self.escape_char()),
+            },
+        }
+    }
+
+    fn decode(&mut self, char: char) -> char {
+        match char {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            'u' => {
+                let remaining = &self.data[self.read..];
+                let decoded = remaining
+                    .find('}')
+                    .filter(|_| remaining.starts_with('{'))
+                    .and_then(|end| {
+                        u32::from_str_radix(&remaining[1..end], 16)
+                            .ok()
+                            .and_then(::std::char::from_u32)
+                            .map(|decoded| (decoded, end + 1))
+                    });
+
+                match decoded {
+                    Some((decoded, length)) => {
+                        self.read += length;
+                        decoded
+                    }
+                    None => char,
+                }
+            }
+            _ => char,
+        }
+    }
+
+    /// Decodes the escape sequences `\n`, `\t`, `\r`, `\0`, and `\u{...}` into the characters
+    /// that they represent. Any other escaped character is yielded as-is.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"\t\u{1F4E6}\$").decode_escapes(true).collect::<Vec<_>>(),
+    ///     vec![Token::Escaped('\t'), Token::Escaped('📦'), Token::Escaped('$')]
+    /// );
+    /// ```
+    pub fn decode_escapes(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(DECODE_ESCAPES, enable)
+    }
+
+    /// Alongside `decode_escapes`, keeps an escaped letter or digit which is not decoded, such
+    /// as `\z`, as it was written, yielding a `Token::Normal` of the escape and the character.
+    /// Other escaped characters, such as `\$`, are yielded as `Token::Escaped` as usual, so
+    /// that the syntax of the template may still be escaped.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"\d+\t\$")
+    ///         .decode_escapes(true)
+    ///         .preserve_unknown_escapes(true)
+    ///         .collect::<Vec<_>>(),
+    ///     vec![
+    ///         Token::Normal(r"\d"),
+    ///         Token::Normal("+"),
+    ///         Token::Escaped('\t'),
+    ///         Token::Escaped('$'),
+    ///     ]
+    /// );
+    /// ```
+    pub fn preserve_unknown_escapes(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(PRESERVE_UNKNOWN_ESCAPES, enable)
+    }
+
+    /// Iterates over tokens alongside the source text that each was parsed from.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let mut spanned = Tokenizer::new(r"a\tb").decode_escapes(true).clone().spanned();
+    /// assert_eq!(spanned.nth(1).and_then(|token| token.escape()), Some(('\t', r"\t")));
+    /// ```
+    pub fn spanned(self) -> Spanned<'a> {
+        Spanned::new(self)
+    }
+
+    /// Iterates over tokens, as they would be without tracing, while reporting each token and
+    /// the byte offset of its construct to `sink`, such as for debugging how a template is
+    /// parsed.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut trace = Vec::new();
+    /// let tokens = Tokenizer::new("a${b}")
+    ///     .trace_tokens(|token, offset| trace.push(format!("{}: {:?}", offset, token)))
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(tokens, vec![Token::Normal("a"), Token::Key("b")]);
+    /// assert_eq!(trace, vec![r#"0: Normal("a")"#, r#"1: Key("b")"#]);
+    /// ```
+    pub fn trace_tokens<F>(self, mut sink: F) -> impl Iterator<Item = Token<'a>>
+    where
+        F: FnMut(&Token<'a>, usize),
+    {
+        self.spanned().map(move |spanned| {
+            sink(&spanned.token, spanned.span.start);
+            spanned.token
+        })
+    }
+
+    /// Rewrites the template so that its prefixed keys are consistently written in the given
+    /// form, leaving the remainder of the template as it was written.
+    ///
+    /// A braced key is only rewritten as a bareword if doing so doesn't change how it is
+    /// tokenized, so `${name}_x` keeps its braces, as `$name_x` is a different key.
+    ///
+    /// ```rust
+    /// use token_expander::{KeyForm, Tokenizer};
+    ///
+    /// let template = "$name/${name}_${version}.deb";
+    /// assert_eq!(
+    ///     Tokenizer::new(template).canonicalize_keys(KeyForm::Braced),
+    ///     "${name}/${name}_${version}.deb"
+    /// );
+    /// assert_eq!(
+    ///     Tokenizer::new(template).canonicalize_keys(KeyForm::Bareword),
+    ///     "$name/${name}_$version.deb"
+    /// );
+    /// ```
+    pub fn canonicalize_keys(self, form: KeyForm) -> String {
+        let data = self.data;
+        let mut probe = self.rebind(data);
+        let mut output = String::with_capacity(data.len());
+        for SpannedToken {
+            token,
+            span,
+            source,
+        } in self.spanned()
+        {
+            let key = match token {
+                Token::Key(key) => key,
+                Token::KeyDecoded { ref key, .. } => key,
+                _ => {
+                    output.push_str(source);
+                    continue;
+                }
+            };
+
+            probe.read = span.start;
+            let prefix = match probe.match_prefix() {
+                Some(length) => &source[..length],
+                None => {
+                    output.push_str(source);
+                    continue;
+                }
+            };
+
+            let braced = source[prefix.len()..].starts_with('{');
+            match form {
+                KeyForm::Braced if !braced && !key.contains('}') => {
+                    output.push_str(prefix);
+                    output.push('{');
+                    output.push_str(key);
+                    output.push('}');
+                }
+                KeyForm::Bareword if braced && probe.is_bareword(key, &data[span.end..]) => {
+                    output.push_str(prefix);
+                    output.push_str(key);
+                }
+                _ => output.push_str(source),
+            }
+        }
+
+        output
+    }
+
+    /// Whether `rest`, which follows a bareword key, begins with a `!` or `?` which belongs to
+    /// the key, when trailing marks are enabled.
+    fn trailing_mark(&self, rest: &str) -> bool {
+        let terminators = self.terminators();
+        let bytes = rest.as_bytes();
+        self.flags & TRAILING_MARKS != 0
+            && matches!(bytes.first(), Some(b'!') | Some(b'?'))
+            && bytes.get(1).is_none_or(|&byte| terminators.contains(byte))
+    }
+
+    /// Whether `key`, followed by `rest`, would be lexed as the same bareword key.
+    fn is_bareword(&self, key: &str, rest: &str) -> bool {
+        let terminators = self.terminators();
+        let terminates = |byte: u8| byte != self.escape && terminators.contains(byte);
+        let key = match key.strip_suffix(['!', '?']) {
+            Some(stem) if self.trailing_mark(&key[stem.len()..]) => stem,
+            _ if self.trailing_mark(rest) => return false,
+            _ => key,
+        };
+        !key.is_empty()
+            && key
+                .bytes()
+                .all(|byte| byte != self.escape && !terminates(byte))
+            && !key.chars().any(char::is_whitespace)
+            && (self.flags & DOLLAR_REQUIRES_LETTER == 0
+                || key.starts_with(|c: char| c.is_alphabetic() || c == '_'))
+            && rest.bytes().next().is_none_or(terminates)
+    }
+
+    /// Expands like `TokenizerExt::expand`, but reports where the `map` closure failed,
+    /// alongside the name of the key that it failed on.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let failure = Tokenizer::new("${name}_${version}.deb")
+    ///     .expand_located(|buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.push_str(text),
+    ///             Token::Key("name") => buf.push_str("system76"),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     })
+    ///     .unwrap_err();
+    ///
+    /// assert_eq!((failure.at, failure.key), (8, Some("version".into())));
+    /// ```
+    pub fn expand_located<T, F>(&mut self, mut map: F) -> Result<String, ExpandFailure<T>>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            let key = match token {
+                Token::Key(key)
+                | Token::PrefixedKey { name: key, .. }
+                | Token::KeyDefault { key, .. }
+                | Token::KeyTyped { key, .. }
+                | Token::KeyReplace { key, .. }
+                | Token::KeyRequired { key, .. } => Some(Cow::Borrowed(key)),
+                Token::KeyDecoded { ref key, .. } => Some(Cow::Owned(key.clone())),
+                _ => None,
+            };
+
+            let token = normalized(normalize, &mut collapsed, token);
+
+            match map(&mut output, token) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(source) => {
+                    return Err(ExpandFailure {
+                        at: span.start,
+                        key: key.map(Cow::into_owned),
+                        source,
+                    })
+                }
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+
+    /// Iterates over the name of each key, and the byte range of its whole construct, such as
+    /// for locating keys within an editor.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${name}_$version").key_spans().collect::<Vec<_>>(),
+    ///     vec![("name", 0..7), ("version", 8..16)]
+    /// );
+    /// ```
+    pub fn key_spans(self) -> impl Iterator<Item = (&'a str, Range<usize>)> {
+        self.spanned().filter_map(|spanned| match spanned.token {
+            Token::Key(key)
+            | Token::PrefixedKey { name: key, .. }
+            | Token::KeyDefault { key, .. }
+            | Token::KeyTyped { key, .. }
+            | Token::KeyReplace { key, .. }
+            | Token::KeyRequired { key, .. } => Some((key, spanned.span)),
+            Token::KeyDecoded { raw, .. } => Some((raw, spanned.span)),
+            _ => None,
+        })
+    }
+
+    /// Splits the template into keys and the literal text between them, merging normal text
+    /// and escaped characters, and yielding rejected constructs as their source text.
+    ///
+    /// A literal borrows from the template, unless it contains an escaped character, in which
+    /// case the literal is allocated. Keys containing escapes are named by their source text,
+    /// as in `key_spans`.
+    ///
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use token_expander::{Segment, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"${name}_\$5.deb").segments(),
+    ///     vec![Segment::Key("name"), Segment::Literal(Cow::Owned("_$5.deb".into()))]
+    /// );
+    /// ```
+    pub fn segments(self) -> Vec<Segment<'a>> {
+        self.segment_iter().collect()
+    }
+
+    /// Iterates over the segments of the template as `segments` does, but with escape
+    /// sequences such as `\n` decoded, for consumers which only distinguish text from keys.
+    ///
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use token_expander::{Segment, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"a\tb${c}").decoded_text().collect::<Vec<_>>(),
+    ///     vec![Segment::Literal(Cow::Owned("a\tb".into())), Segment::Key("c")]
+    /// );
+    /// ```
+    pub fn decoded_text(mut self) -> impl Iterator<Item = Segment<'a>> {
+        self.decode_escapes(true);
+        self.segment_iter()
+    }
+
+    fn segment_iter(self) -> impl Iterator<Item = Segment<'a>> {
+        let data = self.data;
+        let offset = move |text: &str| text.as_ptr() as usize - data.as_ptr() as usize;
+
+        let mut spanned = self.spanned();
+        let mut next_key = None;
+        iter::from_fn(move || {
+            if let Some(key) = next_key.take() {
+                return Some(Segment::Key(key));
+            }
+
+            let mut literal: Option<Cow<'a, str>> = None;
+            for SpannedToken { token, source, .. } in spanned.by_ref() {
+                let text = match token {
+                    Token::Normal(text) | Token::EscapedStr(text) => Cow::Borrowed(text),
+                    Token::Escaped(character) => Cow::Owned(character.to_string()),
+                    Token::Home(_) | Token::Include(_) | Token::Error(_) => Cow::Borrowed(source),
+                    Token::Key(key)
+                    | Token::PrefixedKey { name: key, .. }
+                    | Token::KeyDefault { key, .. }
+                    | Token::KeyTyped { key, .. }
+                    | Token::KeyReplace { key, .. }
+                    | Token::KeyRequired { key, .. }
+                    | Token::KeyDecoded { raw: key, .. } => match literal {
+                        Some(literal) => {
+                            next_key = Some(key);
+                            return Some(Segment::Literal(literal));
+                        }
+                        None => return Some(Segment::Key(key)),
+                    },
+                };
+
+                literal = Some(match (literal.take(), text) {
+                    (None, text) => text,
+                    // Adjacent text within the template is borrowed as a whole.
+                    (Some(Cow::Borrowed(before)), Cow::Borrowed(text))
+                        if offset(before) + before.len() == offset(text) =>
+                    {
+                        Cow::Borrowed(&data[offset(before)..offset(text) + text.len()])
+                    }
+                    (Some(before), text) => Cow::Owned(before.into_owned() + &text),
+                });
+            }
+
+            literal.map(Segment::Literal)
+        })
+    }
+
+    /// Counts the occurrences of each key, whether bareword or braced, such as for deciding
+    /// whether `expand_cached` is worthwhile. Keys containing escapes are counted under their
+    /// source text, as in `key_spans`.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let counts = Tokenizer::new("$name/${name}_$version").key_counts();
+    /// assert_eq!(counts["name"], 2);
+    /// assert_eq!(counts["version"], 1);
+    /// ```
+    pub fn key_counts(self) -> HashMap<&'a str, usize> {
+        let mut counts = HashMap::new();
+        for (key, _) in self.key_spans() {
+            *counts.entry(key).or_insert(0) += 1;
+        }
+
+        counts
+    }
+
+    /// Hashes the structure of the template from the current position, such as for caching a
+    /// plan of its expansion which may be shared by templates of the same structure.
+    ///
+    /// The kind of each token is hashed alongside its key and the parts of its construct, such
+    /// as a default value, while the content of literal text, including escaped characters, is
+    /// ignored. Each run of literal text is hashed as a single placeholder, so templates which
+    /// differ only in their literal text hash equally. The hash is stable for a given build,
+    /// but may differ between releases of Rust.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let hash = |template| Tokenizer::new(template).structure_hash();
+    /// assert_eq!(hash("${name}_${version}.deb"), hash("${name}-${version}.rpm"));
+    /// assert_ne!(hash("${name}_${version}.deb"), hash("${name}.deb"));
+    /// ```
+    pub fn structure_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut literal = false;
+        for token in self.iter() {
+            let kind = match token {
+                Token::Normal(_) | Token::Escaped(_) | Token::EscapedStr(_) if literal => continue,
+                Token::Normal(_) | Token::Escaped(_) | Token::EscapedStr(_) => {
+                    mem::discriminant(&Token::Normal(""))
+                }
+                ref token => mem::discriminant(token),
+            };
+
+            kind.hash(&mut hasher);
+            literal = kind == mem::discriminant(&Token::Normal(""));
+            match token {
+                Token::Key(key) => key.hash(&mut hasher),
+                Token::PrefixedKey { kind, name } => (kind, name).hash(&mut hasher),
+                Token::KeyDecoded { key, .. } => key.hash(&mut hasher),
+                Token::KeyDefault { key, default } => (key, default).hash(&mut hasher),
+                Token::KeyTyped { key, ty } => (key, ty).hash(&mut hasher),
+                Token::KeyReplace {
+                    key,
+                    search,
+                    replace,
+                    global,
+                } => (key, search, replace, global).hash(&mut hasher),
+                Token::KeyRequired { key, message } => (key, message).hash(&mut hasher),
+                Token::Include(path) => path.hash(&mut hasher),
+                Token::Home(user) => user.hash(&mut hasher),
+                Token::Error(why) => why.reason.hash(&mut hasher),
+                Token::Normal(_) | Token::Escaped(_) | Token::EscapedStr(_) => (),
+            }
+        }
+
+        hasher.finish()
+    }
+
+    /// Collects the default declared by each key of the form `${key:-default}`, such as for
+    /// pre-populating a map of values, or for listing the defaults which a template declares.
+    /// Default values must be enabled. Where a key declares several defaults, the last one wins.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let mut tokenizer = Tokenizer::new("${user:-root}@${host:-localhost}:$port");
+    /// let defaults = tokenizer.default_values(true).clone().defaults();
+    /// assert_eq!(defaults.len(), 2);
+    /// assert_eq!(defaults["user"], "root");
+    /// assert_eq!(defaults["host"], "localhost");
+    /// ```
+    pub fn defaults(self) -> HashMap<&'a str, &'a str> {
+        self.filter_map(|token| match token {
+            Token::KeyDefault { key, default } => Some((key, default)),
+            _ => None,
+        })
+        .collect()
+    }
+
+    /// Lists each escaped character from the current position, with the byte offset of the
+    /// escape sequence which produced it, such as for flagging escapes which are redundant.
+    /// With escape decoding enabled, the decoded character is listed. Escaped grapheme
+    /// clusters of several characters are not listed.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// assert_eq!(Tokenizer::new(r"\$a \b").escape_sites(), vec![('$', 0), ('b', 4)]);
+    /// ```
+    pub fn escape_sites(self) -> Vec<(char, usize)> {
+        self.spanned()
+            .filter_map(|token| {
+                token
+                    .escape()
+                    .map(|(character, _)| (character, token.span.start))
+            })
+            .collect()
+    }
+
+    /// Lists the byte offset and kind of each unescaped prefix, opening and closing brace,
+    /// and escape from the current position, for highlighting templates which may be
+    /// malformed. An opening brace is reported at the offset of its prefix.
+    ///
+    /// ```rust
+    /// use token_expander::{DelimKind, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"$a${b}\$c}").delimiter_positions(),
+    ///     vec![
+    ///         (0, DelimKind::Prefix),
+    ///         (2, DelimKind::OpenBrace),
+    ///         (5, DelimKind::CloseBrace),
+    ///         (6, DelimKind::Escape),
+    ///         (9, DelimKind::CloseBrace),
+    ///     ]
+    /// );
+    /// ```
+    pub fn delimiter_positions(&self) -> Vec<(usize, DelimKind)> {
+        let bytes = self.data.as_bytes();
+        let mut probe = self.clone();
+        let mut positions = Vec::new();
+        while probe.read < bytes.len() {
+            let position = probe.read;
+            if self.at_escape(position) {
+                positions.push((position, DelimKind::Escape));
+                probe.read += self.escape_len();
+                probe.read += self.data[probe.read..]
+                    .chars()
+                    .next()
+                    .map_or(0, char::len_utf8);
+            } else if let Some(prefix) = probe.match_prefix() {
+                let braced = bytes.get(position + prefix) == Some(&b'{');
+                let kind = if braced {
+                    DelimKind::OpenBrace
+                } else {
+                    DelimKind::Prefix
+                };
+                positions.push((position, kind));
+                probe.read += prefix + braced as usize;
+            } else {
+                if bytes[position] == b'}' {
+                    positions.push((position, DelimKind::CloseBrace));
+                }
+                probe.read += self.data[position..]
+                    .chars()
+                    .next()
+                    .map_or(1, char::len_utf8);
+            }
+        }
+
+        positions
+    }
+
+    /// Collapses each run of whitespace in `Token::Normal` text to its first character when
+    /// expanding, for minifying templates.
+    ///
+    /// This changes the bytes of the expanded output. Whitespace within keys, escaped
+    /// characters, and substituted values is preserved.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("a  \t b\\  c").normalize_whitespace(true).expand(|buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.push_str(text),
+    ///             Token::Escaped(character) => buf.push(character),
+    ///             _ => (),
+    ///         }
+    ///         Ok::<_, ()>(true)
+    ///     }),
+    ///     Ok("a b  c".into())
+    /// );
+    /// ```
+    pub fn normalize_whitespace(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(NORMALIZE_WHITESPACE, enable)
+    }
+
+    /// Collapses a run of escaped escape characters into a single `Token::Escaped`.
+    ///
+    /// By default, each escape pair is its own token, so `\\\\` yields two
+    /// `Token::Escaped('\\')`. With this enabled, it yields just one, as does `\\`.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"a\\\\b").collapse_escapes(true).collect::<Vec<_>>(),
+    ///     vec![Token::Normal("a"), Token::Escaped('\\'), Token::Normal("b")]
+    /// );
+    /// ```
+    pub fn collapse_escapes(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(COLLAPSE_ESCAPES, enable)
+    }
+
+    fn set_flag(&mut self, flag: u32, enable: bool) -> &mut Self {
+        if enable {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+
+    /// Parses `${key:type}` braced keys as `Token::KeyTyped`, where the type is an identifier
+    /// following the last `:`, so that resolvers may validate or convert the value.
+    ///
+    /// The `:-` of a default value is not an identifier, so `${key:-default}` is unaffected.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${port:int}").type_hints(true).next(),
+    ///     Some(Token::KeyTyped { key: "port", ty: "int" })
+    /// );
+    /// ```
+    pub fn type_hints(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(TYPE_HINTS, enable)
+    }
+
+    /// Reads braced keys which are enclosed by double quotes, as in `${"my key"}`, as the key
+    /// between the quotes, so that keys may contain any character. Quotes may be escaped
+    /// within the key, and the closing brace must follow the closing quote.
+    ///
+    /// As with other braced keys, a quoted key which is unterminated at the end of the input
+    /// extends to the end of the input. A closing quote which is followed by anything other
+    /// than the closing brace is read as part of an ordinary braced key. Either yields a
+    /// `Token::Error` in strict mode.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r#"${"a}b"}"#).quoted_keys(true).collect::<Vec<_>>(),
+    ///     vec![Token::Key("a}b")]
+    /// );
+    /// ```
+    pub fn quoted_keys(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(QUOTED_KEYS, enable)
+    }
+
+    /// Reads a quoted key from after its opening brace, returning `None` if the key should be
+    /// read as an ordinary braced key.
+    fn quoted_key(&mut self, offset: usize) -> Option<Token<'a>> {
+        let inner = self.data[self.read..].strip_prefix('"')?;
+        let lexed = Lexer::new(inner, self.rules(ByteSet::new(b"\""))).search();
+        let rest = &inner[lexed.len()..];
+        if self.flags & STRICT != 0 && !rest.starts_with("\"}") {
+            self.read += self.braced_text().len();
+            self.read = self.data.len().min(self.read + 1);
+            return Some(self.error(offset, "unterminated quoted key".into()));
+        }
+
+        self.read = match rest {
+            "" | "\"" if self.flags & RECOVER_LITERAL != 0 => {
+                self.read = self.data.len();
+                return Some(Token::Normal(&self.data[offset..]));
+            }
+            "" | "\"" => self.data.len(),
+            _ if rest.starts_with("\"}") => self.read + lexed.len() + 3,
+            _ => return None,
+        };
+        let token = match self.unescape(lexed) {
+            Some(key) => Token::KeyDecoded { key, raw: lexed },
+            None => Token::Key(lexed),
+        };
+        Some(self.validate(offset, token))
+    }
+
+    /// Yields braced keys consisting solely of whitespace, such as `${ }`, as the
+    /// `Token::Normal` text of their source, rather than as keys. An empty `${}` is still a
+    /// key.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${ }${ a }").whitespace_keys_literal(true).collect::<Vec<_>>(),
+    ///     vec![Token::Normal("${ }"), Token::Key(" a ")]
+    /// );
+    /// ```
+    pub fn whitespace_keys_literal(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(WHITESPACE_KEYS_LITERAL, enable)
+    }
+
+    /// Parses `${key/search/replace}` braced keys as `Token::KeyReplace`, which replaces the
+    /// first occurrence of `search` within the value of the key with `replace`, or every
+    /// occurrence with `${key//search/replace}`. An escaped `/` is part of the search or
+    /// replacement text, and the replacement may be omitted to remove the search text.
+    ///
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"${path//\//-}").substitutions(true).next(),
+    ///     Some(Token::KeyReplace {
+    ///         key: "path",
+    ///         search: Cow::Owned("/".into()),
+    ///         replace: Cow::Borrowed("-"),
+    ///         global: true,
+    ///     })
+    /// );
+    /// ```
+    pub fn substitutions(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(SUBSTITUTIONS, enable)
+    }
+
+    /// Parses `${key:-default}` braced keys as `Token::KeyDefault`, whose default is itself a
+    /// template to be expanded when the key has no value.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${user:-$USER}").default_values(true).next(),
+    ///     Some(Token::KeyDefault { key: "user", default: "$USER" })
+    /// );
+    /// ```
+    pub fn default_values(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(DEFAULT_VALUES, enable)
+    }
+
+    /// Parses `${key:?message}` braced keys as `Token::KeyRequired`, whose message is reported
+    /// by the expanders when the key has no value. Closing braces may be escaped within the
+    /// message.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new(r"${user:?set \{user\}}").required_values(true).next(),
+    ///     Some(Token::KeyRequired { key: "user", message: "set {user}".into() })
+    /// );
+    /// ```
+    pub fn required_values(&mut self, enable: bool) -> &mut Self {
+        self.set_flag(REQUIRED_VALUES, enable)
+    }
+
+    /// Lexes the text of a braced key, up to its closing brace or the end of the input.
+    fn braced_text(&self) -> &'a str {
+        let remaining = &self.data[self.read..];
+        if self.flags & BALANCED_BRACES == 0 {
+            return Lexer::new(remaining, self.rules(CLOSE_BRACE)).search();
+        }
+
+        let bytes = remaining.as_bytes();
+        let mut depth = 0usize;
+        let mut close = None;
+        let mut position = 0;
+        while let Some(&byte) = bytes.get(position) {
+            match byte {
+                _ if byte == self.escape
+                    && remaining[position..].starts_with(self.escape_char()) =>
+                {
+                    let mut chars = remaining[position..].chars();
+                    position += chars.next().map_or(1, char::len_utf8);
+                    position += chars.next().map_or(0, char::len_utf8);
+                    continue;
+                }
+                b'{' => depth += 1,
+                b'}' if depth > 0 => depth -= 1,
+                b'}' => {
+                    close = Some(position);
+                    if self.brace_match == BraceMatch::Lazy {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+            position += 1;
+        }
+
+        &remaining[..close.unwrap_or(remaining.len())]
+    }
+
+    fn braced_key(&self, lexed: &'a str) -> Token<'a> {
+        if self.flags & DEFAULT_VALUES != 0 {
+            if let Some(position) = lexed.find(":-") {
+                return Token::KeyDefault {
+                    key: self.trim(&lexed[..position]),
+                    default: self.trim(&lexed[position + 2..]),
+                };
+            }
+        }
+
+        if self.flags & REQUIRED_VALUES != 0 {
+            if let Some(position) = lexed.find(":?") {
+                let message = self.trim(&lexed[position + 2..]);
+                return Token::KeyRequired {
+                    key: self.trim(&lexed[..position]),
+                    message: self
+                        .unescape(message)
+                        .map_or(Cow::Borrowed(message), Cow::Owned),
+                };
+            }
+        }
+
+        if self.flags & SUBSTITUTIONS != 0 {
+            if let Some((key, pattern)) = split_unescaped(lexed, self.escape_char()) {
+                let (global, pattern) = match pattern.strip_prefix('/') {
+                    Some(pattern) => (true, pattern),
+                    None => (false, pattern),
+                };
+                let (search, replace) =
+                    split_unescaped(pattern, self.escape_char()).unwrap_or((pattern, ""));
+                let decode = |text| self.unescape(text).map_or(Cow::Borrowed(text), Cow::Owned);
+                return Token::KeyReplace {
+                    key: self.trim(key),
+                    search: decode(search),
+                    replace: decode(replace),
+                    global,
+                };
+            }
+        }
+
+        if self.flags & TYPE_HINTS != 0 {
+            if let Some(position) = lexed.rfind(':') {
+                let ty = self.trim(&lexed[position + 1..]);
+                if is_identifier(ty) {
+                    return Token::KeyTyped {
+                        key: self.trim(&lexed[..position]),
+                        ty,
+                    };
+                }
+            }
+        }
+
+        Token::Key(self.trim(lexed))
+    }
+
+    fn trim(&self, key: &'a str) -> &'a str {
+        if self.flags & (TRIM_KEYS | CANONICAL_KEYS) != 0 {
+            key.trim()
+        } else {
+            key
+        }
+    }
+
+    /// Removes the escapes from a bareword key or a replacement, returning `None` if nothing
+    /// was escaped.
+    fn unescape(&self, lexed: &str) -> Option<String> {
+        let escape = self.escape_char();
+        let mut chars = lexed.chars().peekable();
+        let mut unescaped = String::with_capacity(lexed.len());
+        let mut decoded = false;
+        while let Some(character) = chars.next() {
+            match chars.peek() {
+                Some(&next) if character == escape => {
+                    unescaped.push(next);
+                    chars.next();
+                    decoded = true;
+                }
+                _ => unescaped.push(character),
+            }
+        }
+
+        if decoded {
+            Some(unescaped)
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self, offset: usize, token: Token<'a>) -> Token<'a> {
+        let token = self.canonical(token);
+        let result = match (&self.validator, &token) {
+            (Some(validator), &Token::Key(key))
+            | (Some(validator), &Token::PrefixedKey { name: key, .. })
+            | (Some(validator), &Token::KeyDefault { key, .. })
+            | (Some(validator), &Token::KeyTyped { key, .. })
+            | (Some(validator), &Token::KeyReplace { key, .. })
+            | (Some(validator), &Token::KeyRequired { key, .. }) => validator.validate(key),
+            (Some(validator), Token::KeyDecoded { key, .. }) => validator.validate(key),
+            _ => Ok(()),
+        };
+
+        match result {
+            Ok(()) => token,
+            Err(reason) => self.error(offset, reason),
+        }
+    }
+
+    /// Normalizes the name of a key, in canonical or lowercase modes.
+    fn canonical(&self, token: Token<'a>) -> Token<'a> {
+        let canonical = self.flags & CANONICAL_KEYS != 0;
+        let lowercase = self.flags & LOWERCASE_KEYS != 0;
+        let lower = |key: String| {
+            if lowercase && key.chars().any(char::is_uppercase) {
+                key.to_lowercase()
+            } else {
+                key
+            }
+        };
+
+        match token {
+            Token::Key(raw) if canonical || lowercase => {
+                let key = if canonical { raw.trim() } else { raw };
+                let decoded = if canonical { self.unescape(key) } else { None };
+                match decoded {
+                    None if !lowercase || !key.chars().any(char::is_uppercase) => Token::Key(key),
+                    decoded => Token::KeyDecoded {
+                        key: lower(decoded.unwrap_or_else(|| key.into())),
+                        raw: key,
+                    },
+                }
+            }
+            Token::KeyDecoded { key, raw } if canonical || lowercase => {
+                let key = match key.trim() {
+                    trimmed if canonical && trimmed.len() != key.len() => trimmed.into(),
+                    _ => key,
+                };
+                Token::KeyDecoded {
+                    key: lower(key),
+                    raw,
+                }
+            }
+            token => token,
+        }
+    }
+
+    // Text is scanned a byte at a time, so `read` may fall within a character, where no
+    // delimiter can begin.
+    fn at_delimiter(&self) -> bool {
+        match (self.delimiters, self.data.get(self.read..)) {
+            (Some(delimiters), Some(remaining)) => {
+                remaining.starts_with(delimiters.open) || delimiters.literal(remaining).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    fn at_raw(&self) -> bool {
+        match (self.raw, self.data.get(self.read..)) {
+            (Some(raw), Some(remaining)) => !raw.open.is_empty() && remaining.starts_with(raw.open),
+            _ => false,
+        }
+    }
+
+    fn at_raw_string(&self) -> bool {
+        if self.flags & RAW_STRINGS == 0 {
+            return false;
+        }
+
+        let follows_word = self.data[..self.read]
+            .chars()
+            .next_back()
+            .is_some_and(|last| last.is_alphanumeric() || last == '_');
+        match self.data[self.read..].strip_prefix('r') {
+            Some(rest) => !follows_word && rest.trim_start_matches('#').starts_with('"'),
+            None => false,
+        }
+    }
+
+    fn at_include(&self) -> bool {
+        match (self.include, self.data.get(self.read..)) {
+            (Some(directive), Some(remaining)) => {
+                remaining.starts_with(directive) && remaining[directive.len()..].starts_with('(')
+            }
+            _ => false,
+        }
+    }
+
+    fn at_home(&self) -> bool {
+        let before = &self.data[..self.read];
+        self.flags & TILDE_HOME != 0
+            && (before.is_empty()
+                || before.ends_with('/')
+                || (self.flags & STRIP_BOM != 0 && before == "\u{FEFF}"))
+            && self.data[self.read + 1..]
+                .split('/')
+                .next()
+                .is_some_and(|user| {
+                    user.bytes().all(|byte| {
+                        byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' || byte == b'.'
+                    })
+                })
+    }
+
+    fn home(&mut self) -> Token<'a> {
+        self.read += 1;
+        let user = self.data[self.read..].split('/').next().unwrap_or("");
+        self.read += user.len();
+        Token::Home(if user.is_empty() { None } else { Some(user) })
+    }
+
+    fn include(&mut self, directive: &str) -> Token<'a> {
+        let offset = self.read;
+        self.read += directive.len() + 1;
+        let rules = self.rules(ByteSet::new(b")"));
+        let lexed = Lexer::new(&self.data[self.read..], rules).search();
+        self.read += lexed.len();
+        if self.read == self.data.len() {
+            if self.flags & STRICT != 0 {
+                return self.error(offset, "unterminated include".into());
+            }
+
+            return Token::Normal(&self.data[offset..]);
+        }
+
+        self.read += 1;
+        Token::Include(
+            self.unescape(lexed)
+                .map_or(Cow::Borrowed(lexed), Cow::Owned),
+        )
+    }
+
+    /// Consumes a raw block, returning `None` if it is empty.
+    fn raw_block(&mut self, raw: Delimiters<'a>) -> Option<Token<'a>> {
+        let offset = self.read;
+        let inner = &self.data[offset + raw.open.len()..];
+        let token = match inner.find(raw.close) {
+            Some(end) => {
+                self.read += raw.open.len() + end + raw.close.len();
+                Token::Normal(&inner[..end])
+            }
+            None if self.flags & STRICT != 0 => {
+                self.read = self.data.len();
+                return Some(self.error(offset, "unterminated raw block".into()));
+            }
+            None => {
+                self.read = self.data.len();
+                Token::Normal(inner)
+            }
+        };
+
+        match token {
+            Token::Normal("") => None,
+            token => Some(token),
+        }
+    }
+
+    /// Consumes a raw string, returning `None` if it is empty.
+    fn raw_string(&mut self) -> Option<Token<'a>> {
+        let offset = self.read;
+        let rest = &self.data[offset + 1..];
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        let inner = &rest[hashes + 1..];
+        let close = inner.match_indices('"').map(|(end, _)| end).find(|&end| {
+            inner[end + 1..]
+                .bytes()
+                .take_while(|&byte| byte == b'#')
+                .count()
+                >= hashes
+        });
+
+        let token = match close {
+            Some(end) => {
+                self.read += 1 + hashes + 1 + end + 1 + hashes;
+                Token::Normal(&inner[..end])
+            }
+            None if self.flags & STRICT != 0 => {
+                self.read = self.data.len();
+                return Some(self.error(offset, "unterminated raw string".into()));
+            }
+            None => {
+                self.read = self.data.len();
+                Token::Normal(inner)
+            }
+        };
+
+        match token {
+            Token::Normal("") => None,
+            token => Some(token),
+        }
+    }
+
+    fn delimited_key(&mut self, delimiters: Delimiters<'a>) -> Token<'a> {
+        let offset = self.read;
+        let remaining = &self.data[offset..];
+        if let Some(literal) = delimiters.literal(remaining) {
+            self.read += 2 * literal.len_utf8();
+            return Token::Escaped(literal);
+        }
+
+        let inner = &remaining[delimiters.open.len()..];
+        let tripled = delimiters.open.chars().count() > 1
+            && delimiters
+                .open
+                .chars()
+                .last()
+                .is_some_and(|last| inner.starts_with(last));
+
+        match inner.find(delimiters.close) {
+            // A multi-character delimiter followed by its own last character, as in `{{{`.
+            Some(end) if tripled => {
+                self.read += delimiters.open.len() + end + delimiters.close.len();
+                if let Some(first) = delimiters.close.chars().next() {
+                    if self.data[self.read..].starts_with(first) {
+                        self.read += first.len_utf8();
+                    }
+                }
+
+                let reason = format!("unsupported key: {}", &self.data[offset..self.read]);
+                self.error(offset, reason)
+            }
+            Some(end) => {
+                self.read += delimiters.open.len() + end + delimiters.close.len();
+                let key = self.trim(&inner[..end]);
+                self.validate(offset, Token::Key(key))
+            }
+            None if self.flags & STRICT != 0 => {
+                self.read = self.data.len();
+                self.error(offset, "unterminated key".into())
+            }
+            None => {
+                self.read = self.data.len();
+                Token::Normal(remaining)
+            }
+        }
+    }
+
+    fn error(&self, offset: usize, reason: String) -> Token<'a> {
+        Token::Error(TokenError::new(self.data, offset, reason))
+    }
+
+    /// Rejects an include directive at `offset`, for expanders which resolve keys by name.
+    pub(crate) fn include_error(&self, offset: usize, path: &str) -> TokenError {
+        TokenError::new(self.data, offset, format!("unsupported include: {}", path))
+    }
+
+    /// The text of a `Token::Home`, which the expanders that resolve keys by name write as it
+    /// appears in the template, or the failure of a `Token::Include` or `Token::Error` read
+    /// from `span`.
+    pub(crate) fn unresolved<T>(
+        &self,
+        token: Token,
+        span: Range<usize>,
+    ) -> Result<&'a str, ResolveError<T>> {
+        match token {
+            Token::Include(path) => {
+                Err(ResolveError::Invalid(self.include_error(span.start, &path)))
+            }
+            Token::Error(why) => Err(ResolveError::Invalid(why)),
+            _ => Ok(&self.data[span]),
+        }
+    }
+
+    /// Rejects the required key at `offset`, whose value is empty.
+    #[cfg(feature = "async")]
+    pub(crate) fn required_error(&self, offset: usize, key: &str, message: &str) -> TokenError {
+        TokenError::new(self.data, offset, format!("{}: {}", key, message))
+    }
+
+    fn match_prefix(&self) -> Option<usize> {
+        let remaining = &self.data.as_bytes()[self.read..];
+        let tagged = self.prefix_kinds.iter().map(|&(prefix, _)| prefix);
+        let mut matches = self
+            .prefixes
+            .iter()
+            .cloned()
+            .chain(tagged)
+            .filter(|prefix| !prefix.is_empty() && remaining.starts_with(prefix.as_bytes()))
+            .map(|prefix| prefix.len());
+
+        match self.policy {
+            MatchPolicy::Longest => matches.max(),
+            MatchPolicy::First => matches.next(),
+        }
+    }
+
+    /// Tags a plain key with the kind of the prefix at `offset`, of `length` bytes, if the
+    /// prefix has a kind.
+    fn tag(&self, offset: usize, length: usize, token: Token<'a>) -> Token<'a> {
+        let prefix = &self.data[offset..offset + length];
+        let kind = self
+            .prefix_kinds
+            .iter()
+            .find(|&&(tagged, _)| tagged == prefix)
+            .map(|&(_, kind)| kind);
+        match (kind, token) {
+            (Some(kind), Token::Key(name)) => Token::PrefixedKey { kind, name },
+            (_, token) => token,
+        }
+    }
+
+    fn check_return<S: FnMut(&mut Self), F: FnMut(&mut Self) -> Token<'a>>(
+        &mut self,
+        start: usize,
+        mut do_this: S,
+        mut else_apply: F,
+    ) -> Token<'a> {
+        let token = &self.data[start..self.read];
+
+        if token.is_empty() {
+            else_apply(self)
+        } else {
+            do_this(self);
+            Token::Normal(token)
+        }
+    }
+}
+
+/// Returned when configuring a tokenizer which has already read some of its input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IterationStarted {
+    /// The number of bytes that had been read at the time of the attempt.
+    pub read: usize,
+}
+
+impl fmt::Display for IterationStarted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tokenizer has already read {} bytes", self.read)
+    }
+}
+
+impl Error for IterationStarted {}
+
+/// Returned by `Tokenizer::expand_bounded`, `Tokenizer::expand_limited`, and
+/// `Tokenizer::expand_recursive`.
+///
+/// Variants may be added in minor releases, as further limits are introduced.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum BoundedError<T> {
+    /// The output grew beyond the given number of bytes.
+    OutputTooLarge(usize),
+    /// The template contained more than the given number of keys.
+    TooManyKeys {
+        /// The maximum number of keys.
+        limit: usize,
+        /// The name of the first key beyond the limit.
+        key: String,
+    },
+    /// Values were nested within one another more than the given number of times.
+    TooDeep {
+        /// The maximum depth.
+        limit: usize,
+        /// The name of the first key beyond the limit.
+        key: String,
+    },
+    /// The `map` closure failed.
+    Map(T),
+}
+
+impl<T: fmt::Display> fmt::Display for BoundedError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BoundedError::OutputTooLarge(limit) => {
+                write!(f, "expansion exceeded {} bytes", limit)
+            }
+            BoundedError::TooManyKeys { limit, ref key } => {
+                write!(f, "expansion exceeded {} keys at {}", limit, key)
+            }
+            BoundedError::TooDeep { limit, ref key } => {
+                write!(f, "expansion nested deeper than {} at {}", limit, key)
+            }
+            BoundedError::Map(ref why) => why.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Debug + fmt::Display> Error for BoundedError<T> {}
+
+/// Returned by `Tokenizer::expand_located`, locating where the `map` closure failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandFailure<T> {
+    /// The byte offset of the construct that the closure failed on.
+    pub at: usize,
+    /// The name of the key that the closure failed on, if the construct was a key.
+    pub key: Option<String>,
+    /// The error returned by the closure.
+    pub source: T,
+}
+
+impl<T: fmt::Display> fmt::Display for ExpandFailure<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key {
+            Some(ref key) => write!(f, "{} (key {} at byte {})", self.source, key, self.at),
+            None => write!(f, "{} (at byte {})", self.source, self.at),
+        }
+    }
+}
+
+impl<T: fmt::Debug + fmt::Display> Error for ExpandFailure<T> {}
+
+/// Failures of the expanders which resolve keys by name, such as
+/// `Tokenizer::expand_with_fallback` and `Tokenizer::expand_env`, and of
+/// `Tokenizer::expand_recursive`, within a `BoundedError::Map`.
+///
+/// Failures particular to how an expander resolves keys, such as a `PolicyError`, are given
+/// by `ResolveError::Map`.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ResolveError<T> {
+    /// A required key resolved to an empty value.
+    Required {
+        /// The name of the key.
+        key: String,
+        /// The message of the key.
+        message: String,
+    },
+    /// The tokenizer rejected a construct in the template, such as an include directive,
+    /// which cannot be resolved by name.
+    Invalid(TokenError),
+    /// Resolving a key failed.
+    Map(T),
+}
+
+impl<T: fmt::Display> fmt::Display for ResolveError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::Required {
+                ref key,
+                ref message,
+            } => write!(f, "{}: {}", key, message),
+            ResolveError::Invalid(ref why) => write!(f, "invalid template: {}", why),
+            ResolveError::Map(ref why) => why.fmt(f),
+        }
+    }
+}
+
+impl<T: fmt::Debug + fmt::Display> Error for ResolveError<T> {}
+
+/// Trait for providing expansion abstractions to any type which implements it.
+pub trait TokenizerExt<'a>: Iterator<Item = Token<'a>> {
+    /// Retrieve the escape char being used by the tokenizer, or the first byte of a
+    /// multi-byte escape.
+    fn get_escape(&self) -> u8;
+
+    /// Define a new escape character to use instead of `\`.
+    ///
+    /// # Notes
+    ///
+    /// The escape should be set before iterating. Changing it after tokens have been read
+    /// only affects the remainder of the input; use `try_set_escape` to reject that case.
+    fn set_escape(&mut self, escape: u8) -> &mut Self;
+
+    /// Define a new escape character, failing if the tokenizer has already begun reading.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    ///
+    /// let mut tokenizer = Tokenizer::new("a#$b");
+    /// assert!(tokenizer.try_set_escape(b'#').is_ok());
+    /// assert_eq!(tokenizer.next(), Some(Token::Normal("a")));
+    /// assert!(tokenizer.try_set_escape(b'\\').is_err());
+    /// ```
+    fn try_set_escape(&mut self, escape: u8) -> Result<&mut Self, IterationStarted> {
+        match self.read() {
+            0 => Ok(self.set_escape(escape)),
+            read => Err(IterationStarted { read }),
+        }
+    }
+
+    /// Whether the inner string is empty or not.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The length of the inner string.
+    fn len(&self) -> usize;
+
+    /// The number of bytes that have been read from the inner string.
+    fn read(&self) -> usize;
+
+    /// Whether `expand` collapses runs of whitespace within `Token::Normal` text.
+    fn normalizes_whitespace(&self) -> bool {
+        false
+    }
+
+    /// Abstraction for handling consumption and expansion of tokens.
+    ///
+    /// # Notes
+    ///
+    /// If the `map` closure returns `Ok(false)` or `Err(why)`, expansion will stop early.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    ///
+    /// let url = "https://$domain/$repo/$name/${name}_${version}_$arch.deb";
+    /// assert_eq!(
+    ///     Tokenizer::new(url).expand(|buf, key| {
+    ///         match key {
+    ///             Token::Normal(text)       => buf.push_str(text),
+    ///             Token::Key("name")        => buf.push_str("system76"),
+    ///             Token::Key("version")     => buf.push_str("1.0.0"),
+    ///             Token::Key("arch")        => buf.push_str("amd64"),
+    ///             Token::Key("domain")      => buf.push_str("apt.pop-os.org"),
+    ///             Token::Key("repo")        => buf.push_str("free"),
+    ///             Token::Escaped('n')       => buf.push('\n'),
+    ///             Token::Escaped('t')       => buf.push('\t'),
+    ///             Token::Escaped(character) => buf.push(character),
+    ///             other                     => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     }),
+    ///     Ok("https://apt.pop-os.org/free/system76/system76_1.0.0_amd64.deb".into())
+    /// );
+    /// ```
+    fn expand<T, F>(&mut self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len() * 2);
+        for token in self {
+            let token = normalized(normalize, &mut collapsed, token);
+
+            if !map(&mut output, token)? {
+                break;
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands like `expand`, but also gives `map` the index of the occurrence of each key
+    /// within the template, so that repeated keys may be resolved differently. The index is
+    /// the number of keys which precede the token, so that the first key has the index 0, and
+    /// other tokens have the index of the key which follows them.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("$name, ${name}").expand_indexed(|buf, token, index| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") if index == 0 => buf.push_str("Pop"),
+    ///         Token::Key("name") => buf.push_str("pop"),
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// });
+    /// assert_eq!(expanded, Ok("Pop, pop".into()));
+    /// ```
+    pub fn expand_indexed<T, F>(&mut self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token, usize) -> Result<bool, T>,
+    {
+        let mut keys = 0;
+        self.expand(|buf, token| {
+            let index = keys;
+            if token.key().is_some() {
+                keys += 1;
+            }
+            map(buf, token, index)
+        })
+    }
+
+    /// Expands like `expand`, but on failure returns the output rendered so far alongside the
+    /// error, so that the partial expansion may be salvaged or displayed. The partial output
+    /// includes anything that `map` pushed before it failed.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("${name}-${version}").expand_or_partial(|buf, token| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") => buf.push_str("pop"),
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// });
+    ///
+    /// assert_eq!(
+    ///     expanded,
+    ///     Err(("pop-".into(), "unsupported: Key(\"version\")".into()))
+    /// );
+    /// ```
+    pub fn expand_or_partial<T, F>(&mut self, mut map: F) -> Result<String, (String, T)>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len() * 2);
+        for token in self {
+            let token = normalized(normalize, &mut collapsed, token);
+
+            match map(&mut output, token) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(why) => return Err((output, why)),
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+
+    /// Expands like `expand`, but fails once the output exceeds `max_output_bytes`, guarding
+    /// against small templates which expand into huge outputs.
+    ///
+    /// ```rust
+    /// use token_expander::{BoundedError, Token, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("$a$a$a$a").expand_bounded(
+    ///     |buf, token| {
+    ///         match token {
+    ///             Token::Key("a") => buf.push_str(&"a".repeat(1024)),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     },
+    ///     3000,
+    /// );
+    ///
+    /// assert_eq!(expanded, Err(BoundedError::OutputTooLarge(3000)));
+    /// ```
+    pub fn expand_bounded<T, F>(
+        &mut self,
+        mut map: F,
+        max_output_bytes: usize,
+    ) -> Result<String, BoundedError<T>>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        self.expand(|buf, token| {
+            let proceed = map(buf, token).map_err(BoundedError::Map)?;
+            if buf.len() > max_output_bytes {
+                return Err(BoundedError::OutputTooLarge(max_output_bytes));
+            }
+            Ok(proceed)
+        })
+    }
+
+    /// Expands like `expand`, but fails upon reaching a key once `max_keys` keys have been
+    /// expanded, bounding the work done for untrusted templates. Normal text and escaped
+    /// characters are not counted.
+    ///
+    /// ```rust
+    /// use token_expander::{BoundedError, Token, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("$a/$b/$c").expand_limited(
+    ///     |buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.push_str(text),
+    ///             Token::Key(key) => buf.push_str(&key.to_uppercase()),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     },
+    ///     2,
+    /// );
+    ///
+    /// assert_eq!(expanded, Err(BoundedError::TooManyKeys { limit: 2, key: "c".into() }));
+    /// ```
+    pub fn expand_limited<T, F>(
+        &mut self,
+        mut map: F,
+        max_keys: usize,
+    ) -> Result<String, BoundedError<T>>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut keys = 0;
+        self.expand(|buf, token| {
+            if let Some(key) = token.key() {
+                if keys == max_keys {
+                    return Err(BoundedError::TooManyKeys {
+                        limit: max_keys,
+                        key: key.into(),
+                    });
+                }
+                keys += 1;
+            }
+            map(buf, token).map_err(BoundedError::Map)
+        })
+    }
+
+    /// Expands like `expand`, but produces only the final `tail` bytes of the output, such as
+    /// for log prefixes which are truncated from the left. The output is kept in a ring of
+    /// `tail` bytes, rather than being buffered in full.
+    ///
+    /// The result begins on a character boundary, so it may be shorter than `tail` bytes when
+    /// a multi-byte character straddles the start of the tail.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("${name}: request failed").expand_tail(
+    ///     |buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.push_str(text),
+    ///             Token::Key("name") => buf.push_str("system76"),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     },
+    ///     10,
+    /// );
+    ///
+    /// assert_eq!(expanded, Ok("est failed".into()));
+    /// ```
+    pub fn expand_tail<T, F>(&mut self, mut map: F, tail: usize) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut ring = VecDeque::with_capacity(tail.min(self.len().saturating_mul(2)));
+        let mut value = String::new();
+        self.expand(|_, token| {
+            value.clear();
+            let proceed = map(&mut value, token)?;
+            let bytes = value.as_bytes();
+            if bytes.len() >= tail {
+                ring.clear();
+                ring.extend(&bytes[bytes.len() - tail..]);
+            } else {
+                let excess = (ring.len() + bytes.len()).saturating_sub(tail);
+                ring.drain(..excess);
+                ring.extend(bytes);
+            }
+            Ok(proceed)
+        })?;
+
+        // Drop the continuation bytes of a character which began before the tail.
+        while ring.front().is_some_and(|&byte| byte & 0xC0 == 0x80) {
+            ring.pop_front();
+        }
+
+        Ok(String::from_utf8(ring.into()).expect("the tail begins on a character boundary"))
+    }
+
+    /// Expands like `expand`, but into parts which distinguish the values pushed for keys from
+    /// the surrounding text, for rendering into structured targets, such as styling the
+    /// substituted values.
+    ///
+    /// ```rust
+    /// use token_expander::{OutputPart, Token, Tokenizer};
+    ///
+    /// let parts = Tokenizer::new("Hello, ${name}!").expand_structured(|buf, token| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") => buf.push_str("world"),
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// });
+    ///
+    /// assert_eq!(
+    ///     parts,
+    ///     Ok(vec![
+    ///         OutputPart::Text("Hello, ".into()),
+    ///         OutputPart::Substituted { key: "name".into(), value: "world".into() },
+    ///         OutputPart::Text("!".into()),
+    ///     ])
+    /// );
+    /// ```
+    pub fn expand_structured<T, F>(&mut self, mut map: F) -> Result<Vec<OutputPart>, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut parts = Vec::new();
+        let mut value = String::new();
+        self.expand(|_, token| {
+            let key = token.key().map(String::from);
+            let proceed = map(&mut value, token)?;
+            match (key, parts.last_mut()) {
+                (Some(key), _) => parts.push(OutputPart::Substituted {
+                    key,
+                    value: mem::take(&mut value),
+                }),
+                (None, Some(&mut OutputPart::Text(ref mut text))) => {
+                    text.push_str(&value);
+                    value.clear();
+                }
+                (None, _) if !value.is_empty() => {
+                    parts.push(OutputPart::Text(mem::take(&mut value)))
+                }
+                (None, _) => (),
+            }
+            Ok(proceed)
+        })?;
+
+        Ok(parts)
+    }
+
+    /// Expands like `expand`, but invokes `map` only for the first occurrence of each
+    /// `Token::Key`, reusing the value that it pushed for later occurrences of the same key.
+    /// Other forms of keys, such as a `Token::KeyDefault`, may expand differently from the
+    /// same name, so `map` is invoked for each of them.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut calls = 0;
+    /// let expanded = Tokenizer::new("$name/${name}_1.0.0").expand_cached(|buf, token| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") => {
+    ///             calls += 1;
+    ///             buf.push_str("system76");
+    ///         }
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// });
+    ///
+    /// assert_eq!(expanded, Ok("system76/system76_1.0.0".into()));
+    /// assert_eq!(calls, 1);
+    /// ```
+    pub fn expand_cached<T, F>(&mut self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut cache: HashMap<String, String> = HashMap::new();
+        self.expand(|buf, token| {
+            let key = match token {
+                Token::Key(key) => match cache.get(key) {
+                    Some(value) => {
+                        buf.push_str(value);
+                        return Ok(true);
+                    }
+                    None => key.to_owned(),
+                },
+                _ => return map(buf, token),
+            };
+
+            let start = buf.len();
+            let proceed = map(buf, token)?;
+            cache.insert(key, buf[start..].to_owned());
+            Ok(proceed)
+        })
+    }
+
+    /// Expands like `expand`, but `map` returns the pieces that a key expands to, which are
+    /// concatenated into the output, such as for resolvers which produce structured values.
+    /// Normal text and escaped characters are pushed verbatim, and every other token,
+    /// including errors, is given to `map`.
+    ///
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("${name}_${version}").expand_multi(|token| match token {
+    ///     Token::Key("name") => Ok(vec![Cow::Borrowed("system76")]),
+    ///     Token::Key("version") => Ok(vec!["1".into(), ".".into(), 0.to_string().into()]),
+    ///     other => Err(format!("unsupported: {:?}", other)),
+    /// });
+    ///
+    /// assert_eq!(expanded, Ok("system76_1.0".into()));
+    /// ```
+    pub fn expand_multi<'c, T, F, I>(&mut self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(Token) -> Result<I, T>,
+        I: IntoIterator<Item = Cow<'c, str>>,
+    {
+        self.expand(|buf, token| {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Escaped(character) => buf.push(character),
+                Token::EscapedStr(grapheme) => buf.push_str(grapheme),
+                token => map(token)?
+                    .into_iter()
+                    .for_each(|piece| buf.push_str(&piece)),
+            }
+            Ok(true)
+        })
+    }
+
+    /// Expands like `expand`, but keys of the form `${name=value}` define the value of `name`
+    /// inline, expanding to `value`, which is pushed verbatim. Later occurrences of the key,
+    /// such as `${name}` or `$name`, expand to the value that it was defined with, without
+    /// invoking `map`. A later definition replaces an earlier one, and keys which have not
+    /// been defined are passed to `map`.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let template = "${version=1.0.0}: ${name}_$version.deb";
+    /// let expanded = Tokenizer::new(template).expand_with_store(|buf, token| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") => buf.push_str("system76"),
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// });
+    ///
+    /// assert_eq!(expanded, Ok("1.0.0: system76_1.0.0.deb".into()));
+    /// ```
+    pub fn expand_with_store<T, F>(&mut self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut store: HashMap<String, String> = HashMap::new();
+        self.expand(|buf, token| {
+            match token {
+                Token::Key(key) => match key.split_once('=') {
+                    Some((name, value)) => {
+                        buf.push_str(value);
+                        store.insert(name.to_owned(), value.to_owned());
+                    }
+                    None => match store.get(key) {
+                        Some(value) => buf.push_str(value),
+                        None => return map(buf, token),
+                    },
+                },
+                token => return map(buf, token),
+            }
+
+            Ok(true)
+        })
+    }
+
+    /// Expands keys with the values that `resolve` pushes, given the name of each key and its
+    /// type hint, if it has one. Normal text and escaped characters are pushed verbatim.
+    ///
+    /// With default values enabled, the default of a key is pushed when `resolve` pushes
+    /// nothing for it.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// let expanded = Tokenizer::new("${host}:${port:int}").type_hints(true).expand_typed(
+    ///     |buf, key, ty| {
+    ///         match (key, ty) {
+    ///             ("host", None) => buf.push_str("localhost"),
+    ///             ("port", Some("int")) => buf.push_str(&8080.to_string()),
+    ///             _ => return Err(format!("unsupported: {}", key)),
+    ///         }
+    ///         Ok(())
+    ///     },
+    /// );
+    ///
+    /// assert_eq!(expanded, Ok("localhost:8080".into()));
+    /// ```
+    pub fn expand_typed<T, F>(&mut self, mut resolve: F) -> Result<String, ResolveError<T>>
+    where
+        F: FnMut(&mut String, &str, Option<&str>) -> Result<(), T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            let buf = &mut output;
+            match normalized(normalize, &mut collapsed, token) {
+                Token::Normal(text) | Token::EscapedStr(text) => buf.push_str(text),
+                Token::Escaped(character) => buf.push(character),
+                Token::KeyTyped { key, ty } => {
+                    resolve(buf, key, Some(ty)).map_err(ResolveError::Map)?
+                }
+                Token::KeyDefault { key, default } => {
+                    let start = buf.len();
+                    resolve(buf, key, None).map_err(ResolveError::Map)?;
+                    if buf.len() == start {
+                        buf.push_str(default);
+                    }
+                }
+                Token::KeyRequired { key, message } => {
+                    let start = buf.len();
+                    resolve(buf, key, None).map_err(ResolveError::Map)?;
+                    if buf.len() == start {
+                        return Err(ResolveError::Required {
+                            key: key.into(),
+                            message: message.into_owned(),
+                        });
+                    }
+                }
+                token @ Token::KeyReplace { .. } => {
+                    let start = buf.len();
+                    resolve(buf, token.key().unwrap_or_default(), None)
+                        .map_err(ResolveError::Map)?;
+                    if let Cow::Owned(value) = token.substitute(&buf[start..]) {
+                        buf.truncate(start);
+                        buf.push_str(&value);
+                    }
+                }
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    buf.push_str(self.unresolved(token, span)?)
+                }
+                token => resolve(buf, token.key().unwrap_or_default(), None)
+                    .map_err(ResolveError::Map)?,
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+
+    /// Expands keys with the values of `primary`, such as a cache, and with the values of
+    /// `fallback` for the keys that `primary` has no value for. Normal text and escaped
+    /// characters are pushed verbatim.
+    ///
+    /// With default values enabled, the default of a key is pushed when its value is empty.
+    ///
+    /// ```rust
+    /// use token_expander::{ResolveError, Tokenizer};
+    ///
+    /// let expanded = Tokenizer::new("${name}_${version}").expand_with_fallback(
+    ///     |key| match key {
+    ///         "name" => Some("system76".into()),
+    ///         _ => None,
+    ///     },
+    ///     |key| match key {
+    ///         "version" => Ok("1.0.0".into()),
+    ///         _ => Err(format!("unknown key: {}", key)),
+    ///     },
+    /// );
+    ///
+    /// assert_eq!(expanded, Ok("system76_1.0.0".into()));
+    /// assert_eq!(
+    ///     Tokenizer::new("$arch").expand_with_fallback(|_| None, |key| Err(key.to_owned())),
+    ///     Err(ResolveError::Map("arch".into()))
+    /// );
+    /// ```
+    pub fn expand_with_fallback<T, P, F>(
+        &mut self,
+        mut primary: P,
+        mut fallback: F,
+    ) -> Result<String, ResolveError<T>>
+    where
+        P: FnMut(&str) -> Option<String>,
+        F: FnMut(&str) -> Result<String, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            match normalized(normalize, &mut collapsed, token) {
+                Token::Normal(text) | Token::EscapedStr(text) => output.push_str(text),
+                Token::Escaped(character) => output.push(character),
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    output.push_str(self.unresolved(token, span)?)
+                }
+                token => {
+                    let key = token.key().unwrap_or_default();
+                    let value = match primary(key) {
+                        Some(value) => value,
+                        None => fallback(key).map_err(ResolveError::Map)?,
+                    };
+
+                    match token {
+                        Token::KeyDefault { default, .. } if value.is_empty() => {
+                            output.push_str(default)
+                        }
+                        Token::KeyRequired { key, message } if value.is_empty() => {
+                            return Err(ResolveError::Required {
+                                key: key.into(),
+                                message: message.into_owned(),
+                            })
+                        }
+                        _ => output.push_str(&token.substitute(&value)),
+                    }
+                }
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+
+    /// Expands keys with the values of `map` into a `Rope`, which borrows the text of the
+    /// template and shares each resolved value between its occurrences, rather than copying
+    /// it, so that large values which are substituted many times are stored once. `map` is
+    /// invoked once for each key of a distinct name.
+    ///
+    /// With default values enabled, the default of a key is used when its value is empty.
+    ///
+    /// ```rust
+    /// use token_expander::{ResolveError, Tokenizer};
+    ///
+    /// let rope = Tokenizer::new("$name/${name}_1.0.0").expand_rope(|key| match key {
+    ///     "name" => Ok("system76".into()),
+    ///     _ => Err(format!("unknown key: {}", key)),
+    /// })?;
+    ///
+    /// assert_eq!(rope.to_string(), "system76/system76_1.0.0");
+    /// # Ok::<(), ResolveError<String>>(())
+    /// ```
+    pub fn expand_rope<T, F>(&mut self, mut map: F) -> Result<Rope<'a>, ResolveError<T>>
+    where
+        F: FnMut(&str) -> Result<String, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut values: HashMap<String, Rc<str>> = HashMap::new();
+        let mut rope = Rope::default();
+        while let Some((token, span)) = self.next_spanned() {
+            let piece = match token {
+                // Collapsed text is owned by the rope, rather than borrowed from the template.
+                Token::Normal(text) if normalize => {
+                    let mut collapsed = String::new();
+                    normalized(normalize, &mut collapsed, Token::Normal(text));
+                    Piece::Text(Cow::Owned(collapsed))
+                }
+                Token::Normal(text) | Token::EscapedStr(text) => Piece::Text(Cow::Borrowed(text)),
+                Token::Escaped(character) => Piece::Text(Cow::Owned(character.to_string())),
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    Piece::Text(Cow::Borrowed(self.unresolved(token, span)?))
+                }
+                token => {
+                    let key = token.key().unwrap_or_default();
+                    let value = match values.get(key) {
+                        Some(value) => value.clone(),
+                        None => {
+                            let value: Rc<str> = map(key).map_err(ResolveError::Map)?.into();
+                            values.insert(key.to_owned(), value.clone());
+                            value
+                        }
+                    };
+
+                    match token {
+                        Token::KeyDefault { default, .. } if value.is_empty() => {
+                            Piece::Text(Cow::Borrowed(default))
+                        }
+                        Token::KeyRequired { key, message } if value.is_empty() => {
+                            return Err(ResolveError::Required {
+                                key: key.into(),
+                                message: message.into_owned(),
+                            })
+                        }
+                        _ => match token.substitute(&value) {
+                            Cow::Owned(replaced) => Piece::Value(replaced.into()),
+                            Cow::Borrowed(_) => Piece::Value(value),
+                        },
+                    }
+                }
+            };
+
+            rope.pieces.push(piece);
+        }
+
+        Ok(rope)
+    }
+
+    /// Expands like `expand`, but performs the replacements of `Token::KeyReplace` keys on
+    /// the values that `map` pushes for them, so that `map` is given these keys as a
+    /// `Token::Key`.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut tokenizer = Tokenizer::new("${name}_${version//./_}");
+    /// let expanded = tokenizer.substitutions(true).expand_substituted(|buf, token| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") => buf.push_str("system76"),
+    ///         Token::Key("version") => buf.push_str("1.0.0"),
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// });
+    ///
+    /// assert_eq!(expanded, Ok("system76_1_0_0".into()));
+    /// ```
+    pub fn expand_substituted<T, F>(&mut self, mut map: F) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut value = String::new();
+        self.expand(|buf, token| match token {
+            Token::KeyReplace { key, .. } => {
+                value.clear();
+                let proceed = map(&mut value, Token::Key(key))?;
+                buf.push_str(&token.substitute(&value));
+                Ok(proceed)
+            }
+            token => map(buf, token),
+        })
+    }
+
+    /// Expands like `expand`, and then checks the whole of the output with `validate`, such as
+    /// for requiring that an expanded URL be valid.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let expand = |path: &'static str| {
+    ///     Tokenizer::new("https://${domain}/${path}").expand_then(
+    ///         |buf, token| {
+    ///             match token {
+    ///                 Token::Normal(text) => buf.push_str(text),
+    ///                 Token::Key("domain") => buf.push_str("apt.pop-os.org"),
+    ///                 Token::Key("path") => buf.push_str(path),
+    ///                 other => return Err(format!("unsupported: {:?}", other)),
+    ///             }
+    ///             Ok(true)
+    ///         },
+    ///         |output| {
+    ///             if output.starts_with("https://") && !output[8..].contains("//") {
+    ///                 Ok(())
+    ///             } else {
+    ///                 Err(format!("invalid URL: {}", output))
+    ///             }
+    ///         },
+    ///     )
+    /// };
+    ///
+    /// assert_eq!(expand("free"), Ok("https://apt.pop-os.org/free".into()));
+    /// assert_eq!(
+    ///     expand("/free"),
+    ///     Err("invalid URL: https://apt.pop-os.org//free".into())
+    /// );
+    /// ```
+    pub fn expand_then<T, F, V>(&mut self, map: F, validate: V) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+        V: Fn(&str) -> Result<(), T>,
+    {
+        let output = self.expand(map)?;
+        validate(&output)?;
+        Ok(output)
+    }
+
+    /// Expands like `expand`, but into bytes, so that values which are not valid UTF-8, such
+    /// as Unix paths, are preserved.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("/home/$user").expand_bytes(|buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.extend_from_slice(text.as_bytes()),
+    ///             Token::Key("user") => buf.extend_from_slice(b"caf\xe9"),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     }),
+    ///     Ok(b"/home/caf\xe9".to_vec())
+    /// );
+    /// ```
+    pub fn expand_bytes<T, F>(&mut self, mut map: F) -> Result<Vec<u8>, T>
+    where
+        F: FnMut(&mut Vec<u8>, Token) -> Result<bool, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = Vec::with_capacity(self.len() * 2);
+        for token in self {
+            let token = normalized(normalize, &mut collapsed, token);
+
+            if !map(&mut output, token)? {
+                break;
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+
+    /// Expands like `expand_bytes`, but checks that the expansion is valid UTF-8, so that
+    /// values given as bytes may be assembled into a `String`. Any error returned by `map` is
+    /// passed through, and invalid UTF-8 is converted into the error type with `From`.
+    ///
+    /// ```rust
+    /// use std::str::Utf8Error;
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let expand = |value: &'static [u8]| {
+    ///     Tokenizer::new("name=$name").expand_utf8(|buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.extend_from_slice(text.as_bytes()),
+    ///             _ => buf.extend_from_slice(value),
+    ///         }
+    ///         Ok::<_, Utf8Error>(true)
+    ///     })
+    /// };
+    ///
+    /// assert_eq!(expand(b"caf\xc3\xa9"), Ok("name=café".into()));
+    /// assert!(expand(b"caf\xe9").is_err());
+    /// ```
+    pub fn expand_utf8<T, F>(&mut self, map: F) -> Result<String, T>
+    where
+        T: From<Utf8Error>,
+        F: FnMut(&mut Vec<u8>, Token) -> Result<bool, T>,
+    {
+        String::from_utf8(self.expand_bytes(map)?).map_err(|why| why.utf8_error().into())
+    }
+
+    /// Expands like `expand`, but into a path. Values are pushed as OS strings, which on Unix
+    /// are arbitrary bytes, so that file names which are not valid UTF-8 are preserved.
+    ///
+    /// ```rust
+    /// use std::path::{Path, PathBuf};
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${home}/.cache/${name}").expand_path(|buf, token| {
+    ///         match token {
+    ///             Token::Normal(text) => buf.push(text),
+    ///             Token::Key("home") => buf.push(Path::new("/home/user")),
+    ///             Token::Key("name") => buf.push("pop-shop"),
+    ///             other => return Err(format!("unsupported: {:?}", other)),
+    ///         }
+    ///         Ok(true)
+    ///     }),
+    ///     Ok(PathBuf::from("/home/user/.cache/pop-shop"))
+    /// );
+    /// ```
+    pub fn expand_path<T, F>(&mut self, mut map: F) -> Result<PathBuf, T>
+    where
+        F: FnMut(&mut OsString, Token) -> Result<bool, T>,
+    {
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = OsString::with_capacity(self.len() * 2);
+        for token in self {
+            let token = normalized(normalize, &mut collapsed, token);
+
+            if !map(&mut output, token)? {
+                break;
+            }
+        }
+
+        Ok(PathBuf::from(output))
+    }
+
+    /// Expands like `expand`, but escapes the values that `map` pushes for each key for the
+    /// given `context`, leaving normal text and escaped characters untouched.
+    ///
+    /// ```rust
+    /// use token_expander::{Context, Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("<p>$name</p>").expand_escaping(
+    ///         |buf, token| {
+    ///             match token {
+    ///                 Token::Normal(text) => buf.push_str(text),
+    ///                 Token::Key("name") => buf.push_str("<script>"),
+    ///                 other => return Err(format!("unsupported: {:?}", other)),
+    ///             }
+    ///             Ok(true)
+    ///         },
+    ///         Context::Html
+    ///     ),
+    ///     Ok("<p>&lt;script&gt;</p>".into())
+    /// );
+    /// ```
+    pub fn expand_escaping<T, F>(&mut self, mut map: F, context: Context) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let mut value = String::new();
+        self.expand(|buf, token| match token {
+            Token::Key(_)
+            | Token::PrefixedKey { .. }
+            | Token::KeyDefault { .. }
+            | Token::KeyDecoded { .. }
+            | Token::KeyTyped { .. }
+            | Token::KeyReplace { .. }
+            | Token::KeyRequired { .. } => {
+                value.clear();
+                let proceed = map(&mut value, token)?;
+                context.escape(buf, &value);
+                Ok(proceed)
+            }
+            token => map(buf, token),
+        })
+    }
+
+    /// Expands like `expand`, but passes the value that `map` pushes for each key through
+    /// `transform`, leaving normal text and escaped characters untouched.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${name}: ${value}").expand_transformed(
+    ///         |buf, token| {
+    ///             match token {
+    ///                 Token::Normal(text) => buf.push_str(text),
+    ///                 Token::Key("name") => buf.push_str("  Version "),
+    ///                 Token::Key("value") => buf.push_str("1.0.0\n"),
+    ///                 other => return Err(format!("unsupported: {:?}", other)),
+    ///             }
+    ///             Ok(true)
+    ///         },
+    ///         |value| value.trim().into()
+    ///     ),
+    ///     Ok("Version: 1.0.0".into())
+    /// );
+    /// ```
+    pub fn expand_transformed<T, F, V>(&mut self, mut map: F, mut transform: V) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+        V: FnMut(&str) -> Cow<str>,
+    {
+        let mut value = String::new();
+        self.expand(|buf, token| match token {
+            Token::Key(_)
+            | Token::PrefixedKey { .. }
+            | Token::KeyDefault { .. }
+            | Token::KeyDecoded { .. }
+            | Token::KeyTyped { .. }
+            | Token::KeyReplace { .. }
+            | Token::KeyRequired { .. } => {
+                value.clear();
+                let proceed = map(&mut value, token)?;
+                buf.push_str(&transform(&value));
+                Ok(proceed)
+            }
+            token => map(buf, token),
+        })
+    }
+
+    /// Expands like `expand`, but passes each run of normal text through `text_fn` before it
+    /// is given to `map`, such as for expanding tabs in literal text. Keys and escaped
+    /// characters are untouched.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("Hello, ${name}!").expand_mapping_text(
+    ///         |buf, token| {
+    ///             match token {
+    ///                 Token::Normal(text) => buf.push_str(text),
+    ///                 Token::Key("name") => buf.push_str("world"),
+    ///                 other => return Err(format!("unsupported: {:?}", other)),
+    ///             }
+    ///             Ok(true)
+    ///         },
+    ///         |text| text.replace("Hello", "Goodbye").into()
+    ///     ),
+    ///     Ok("Goodbye, world!".into())
+    /// );
+    /// ```
+    pub fn expand_mapping_text<T, F, V>(&mut self, mut map: F, mut text_fn: V) -> Result<String, T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+        V: FnMut(&str) -> Cow<str>,
+    {
+        self.expand(|buf, token| match token {
+            Token::Normal(text) => map(buf, Token::Normal(&text_fn(text))),
+            token => map(buf, token),
+        })
+    }
+
+    /// Expands into `out`, while appending a `key=value` line to `log` for each key, where the
+    /// value is whatever `map` pushed for that key.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let (mut out, mut log) = (String::new(), String::new());
+    /// Tokenizer::new("${name}_${version}.deb").expand_tee(&mut out, &mut log, |buf, token| {
+    ///     match token {
+    ///         Token::Normal(text) => buf.push_str(text),
+    ///         Token::Key("name") => buf.push_str("system76"),
+    ///         Token::Key("version") => buf.push_str("1.0.0"),
+    ///         other => return Err(format!("unsupported: {:?}", other)),
+    ///     }
+    ///     Ok(true)
+    /// })?;
+    ///
+    /// assert_eq!(out, "system76_1.0.0.deb");
+    /// assert_eq!(log, "name=system76\nversion=1.0.0\n");
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn expand_tee<T, F>(
+        &mut self,
+        out: &mut String,
+        log: &mut String,
+        mut map: F,
+    ) -> Result<(), T>
+    where
+        F: FnMut(&mut String, Token) -> Result<bool, T>,
+    {
+        let expanded = self.expand(|buf, token| {
+            let line = log.len();
+            let keyed = match token.key() {
+                Some(key) => {
+                    log.push_str(key);
+                    log.push('=');
+                    true
+                }
+                None => false,
+            };
+
+            let start = buf.len();
+            let proceed = map(buf, token).inspect_err(|_| log.truncate(line))?;
+            if keyed {
+                log.push_str(&buf[start..]);
+                log.push('\n');
+            }
+
+            Ok(proceed)
+        })?;
+
+        out.push_str(&expanded);
+        Ok(())
+    }
+
+    /// Expands keys by walking a JSON tree, treating each key as a path split on `sep`.
+    ///
+    /// Object fields are looked up by name, and array elements by index. The leaf must be a
+    /// scalar: strings are substituted as-is, numbers and booleans by their JSON
+    /// representation, and `null` as an empty string.
+    ///
+    /// ```rust
+    /// # extern crate serde_json;
+    /// # extern crate token_expander;
+    /// use token_expander::Tokenizer;
+    ///
+    /// # fn main() {
+    /// let root: serde_json::Value =
+    ///     serde_json::from_str(r#"{"user": {"name": "pop", "ids": [7, 76]}}"#).unwrap();
+    /// assert_eq!(
+    ///     Tokenizer::new("${user/name}-${user/ids/1}").expand_json(&root, '/'),
+    ///     Ok("pop-76".into())
+    /// );
+    /// # }
+    /// ```
+    #[cfg(feature = "serde_json")]
+    pub fn expand_json(
+        &mut self,
+        root: &serde_json::Value,
+        sep: char,
+    ) -> Result<String, ResolveError<json::JsonError>> {
+        let push = |buf: &mut String, key: &str| {
+            json::lookup(root, key, sep)
+                .and_then(|leaf| json::push_leaf(buf, leaf, key))
+                .map_err(ResolveError::Map)
+        };
+
+        let normalize = self.normalizes_whitespace();
+        let mut collapsed = String::new();
+        let mut output = String::with_capacity(self.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            let buf = &mut output;
+            match normalized(normalize, &mut collapsed, token) {
+                Token::Normal(text) | Token::EscapedStr(text) => buf.push_str(text),
+                Token::Escaped(character) => buf.push(character),
+                Token::Key(key)
+                | Token::PrefixedKey { name: key, .. }
+                | Token::KeyTyped { key, .. } => push(buf, key)?,
+                Token::KeyDecoded { key, .. } => push(buf, &key)?,
+                token @ Token::KeyReplace { .. } => {
+                    let mut value = String::new();
+                    push(&mut value, token.key().unwrap_or_default())?;
+                    buf.push_str(&token.substitute(&value));
+                }
+                Token::KeyDefault { key, default } => match json::lookup(root, key, sep) {
+                    Ok(leaf) => json::push_leaf(buf, leaf, key).map_err(ResolveError::Map)?,
+                    Err(_) => buf.push_str(default),
+                },
+                Token::KeyRequired { key, message } => match json::lookup(root, key, sep) {
+                    Ok(leaf) => json::push_leaf(buf, leaf, key).map_err(ResolveError::Map)?,
+                    Err(_) => {
+                        return Err(ResolveError::Required {
+                            key: key.into(),
+                            message: message.into_owned(),
+                        })
+                    }
+                },
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    buf.push_str(self.unresolved(token, span)?)
+                }
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+impl<'a> fmt::Debug for Tokenizer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let modes = MODES
+            .iter()
+            .filter(|&&(flag, _)| self.flags & flag != 0)
+            .map(|&(_, name)| name)
+            .collect::<Vec<_>>();
+
+        let sequence;
+        let escape: &dyn fmt::Debug = match self.escape_sequence {
+            Some(EscapeSequence::Bytes(bytes)) => {
+                sequence = String::from_utf8_lossy(bytes);
+                &sequence
+            }
+            _ => &self.escape_char(),
+        };
+
+        f.debug_struct("Tokenizer")
+            .field("data", &self.data)
+            .field("read", &self.read)
+            .field("escape", escape)
+            .field("modes", &modes)
+            .field("prefixes", &self.prefixes)
+            .field("prefix_kinds", &self.prefix_kinds)
+            .field("policy", &self.policy)
+            .field("brace_match", &self.brace_match)
+            .field("delimiters", &self.delimiters)
+            .field("raw", &self.raw)
+            .field("include", &self.include)
+            .field("validator", &self.validator.is_some())
+            .finish()
+    }
+}
+
+impl<'a> PartialEq for Tokenizer<'a> {
+    fn eq(&self, other: &Tokenizer<'a>) -> bool {
+        self.data == other.data
+            && self.read == other.read
+            && self.flags == other.flags
+            && self.escape == other.escape
+            && self.escape_sequence == other.escape_sequence
+            && self.prefixes == other.prefixes
+            && self.prefix_kinds == other.prefix_kinds
+            && self.policy == other.policy
+            && self.brace_match == other.brace_match
+            && self.delimiters == other.delimiters
+            && self.raw == other.raw
+            && self.include == other.include
+            && self.validator == other.validator
+    }
+}
+
+/// A tokenizer over an empty string, with the configuration of `Tokenizer::new`.
+impl<'a> Default for Tokenizer<'a> {
+    fn default() -> Tokenizer<'a> {
+        Tokenizer::new("")
+    }
+}
+
+impl<'a> TokenizerExt<'a> for Tokenizer<'a> {
+    fn get_escape(&self) -> u8 {
+        self.escape
+    }
+
+    fn set_escape(&mut self, escape: u8) -> &mut Self {
+        self.escape = escape;
+        self.escape_sequence = None;
+        self
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read(&self) -> usize {
+        self.read
+    }
+
+    fn normalizes_whitespace(&self) -> bool {
+        self.flags & NORMALIZE_WHITESPACE != 0
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let token = self.next_token();
+        // Each construct is consumed within the input, so that iteration ends at its end.
+        debug_assert!(
+            self.read <= self.data.len(),
+            "read {} bytes of {}",
+            self.read,
+            self.data.len()
+        );
+        token
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        if self.flags & ESCAPED != 0 {
+            self.flags ^= ESCAPED;
+            return Some(self.escaped_character());
+        }
+
+        self.skip_bom();
+        if self.read >= self.data.len() {
+            return None;
+        }
+
+        let mut start = self.read;
+        let bytes = self.data.as_bytes();
+        let escape_len = self.escape_len();
+        while self.read < self.data.len() {
+            match bytes[self.read] {
+                byte if byte == self.escape && self.at_escape(self.read) => {
+                    return Some(self.check_return(
+                        start,
+                        |tokenizer| {
+                            tokenizer.read += escape_len;
+                            tokenizer.flags |= ESCAPED;
+                        },
+                        |tokenizer| {
+                            tokenizer.read += escape_len;
+                            tokenizer.escaped_character()
+                        },
+                    ));
+                }
+                _ if self.at_raw() => {
+                    if start != self.read {
+                        return Some(Token::Normal(&self.data[start..self.read]));
+                    }
+
+                    let raw = self.raw.expect("raw delimiters are defined");
+                    match self.raw_block(raw) {
+                        Some(token) => return Some(token),
+                        None => start = self.read,
+                    }
+                }
+                b'r' if self.at_raw_string() => {
+                    if start != self.read {
+                        return Some(Token::Normal(&self.data[start..self.read]));
+                    }
+
+                    match self.raw_string() {
+                        Some(token) => return Some(token),
+                        None => start = self.read,
+                    }
+                }
+                b'~' if self.at_home() => {
+                    return Some(self.check_return(start, |_| {}, |tokenizer| tokenizer.home()));
+                }
+                _ if self.at_include() => {
+                    return Some(self.check_return(
+                        start,
+                        |_| {},
+                        |tokenizer| match tokenizer.include {
+                            Some(directive) => tokenizer.include(directive),
+                            None => unreachable!(),
+                        },
+                    ));
+                }
+                b'}' if self.flags & STRICT_BRACES != 0 => {
+                    return Some(self.check_return(
+                        start,
+                        |_| {},
+                        |tokenizer| {
+                            let offset = tokenizer.read;
+                            tokenizer.read += 1;
+                            tokenizer.error(offset, "stray closing brace".into())
+                        },
+                    ));
+                }
+                _ if self.at_delimiter() => {
+                    return Some(self.check_return(
+                        start,
+                        |_| {},
+                        |tokenizer| match tokenizer.delimiters {
+                            Some(delimiters) => tokenizer.delimited_key(delimiters),
+                            None => unreachable!(),
+                        },
+                    ));
+                }
+                _ => match self.match_prefix() {
+                    Some(prefix) if bytes.get(self.read + prefix) == Some(&b'{') => {
+                        return Some(self.check_return(
+                            start,
+                            |_| {},
+                            |tokenizer| {
+                                let offset = tokenizer.read;
+                                tokenizer.read += prefix + 1;
+                                if tokenizer.flags & QUOTED_KEYS != 0 {
+                                    if let Some(token) = tokenizer.quoted_key(offset) {
+                                        return tokenizer.tag(offset, prefix, token);
+                                    }
+                                }
+                                let lexed = tokenizer.braced_text();
+                                tokenizer.read += lexed.len();
+                                if tokenizer.read == tokenizer.data.len() {
+                                    // The closing brace is absent from an unterminated key.
+                                    if tokenizer.flags & STRICT != 0 {
+                                        return tokenizer.error(offset, "unterminated key".into());
+                                    }
+                                    if tokenizer.flags & RECOVER_LITERAL != 0 {
+                                        return Token::Normal(&tokenizer.data[offset..]);
+                                    }
+                                } else {
+                                    tokenizer.read += 1;
+                                }
+                                if tokenizer.flags & WHITESPACE_KEYS_LITERAL != 0
+                                    && !lexed.is_empty()
+                                    && lexed.chars().all(char::is_whitespace)
+                                {
+                                    return Token::Normal(&tokenizer.data[offset..tokenizer.read]);
+                                }
+                                let token = tokenizer.braced_key(lexed);
+                                let token = tokenizer.validate(offset, token);
+                                tokenizer.tag(offset, prefix, token)
+                            },
+                        ));
+                    }
+                    // As in shells, a prefix followed by whitespace is literal text.
+                    Some(prefix)
+                        if self.data[self.read + prefix..]
+                            .chars()
+                            .next()
+                            .is_some_and(char::is_whitespace) =>
+                    {
+                        self.read += prefix
+                    }
+                    Some(prefix)
+                        if self.flags & DOLLAR_REQUIRES_LETTER != 0
+                            && !self.data[self.read + prefix..]
+                                .chars()
+                                .next()
+                                .is_some_and(|c| c.is_alphabetic() || c == '_') =>
+                    {
+                        self.read += prefix
+                    }
+                    Some(prefix) => {
+                        return Some(self.check_return(
+                            start,
+                            |_| {},
+                            |tokenizer| {
+                                let offset = tokenizer.read;
+                                tokenizer.read += prefix;
+                                let rules = tokenizer.rules(tokenizer.terminators());
+                                let mut lexed =
+                                    Lexer::new(&tokenizer.data[tokenizer.read..], rules).search();
+                                let rest = &tokenizer.data[tokenizer.read + lexed.len()..];
+                                if !lexed.is_empty() && tokenizer.trailing_mark(rest) {
+                                    lexed = &tokenizer.data
+                                        [tokenizer.read..tokenizer.read + lexed.len() + 1];
+                                }
+                                tokenizer.read += lexed.len();
+                                let token = match tokenizer.unescape(lexed) {
+                                    Some(key) => Token::KeyDecoded { key, raw: lexed },
+                                    None => Token::Key(lexed),
+                                };
+                                let token = tokenizer.validate(offset, token);
+                                tokenizer.tag(offset, prefix, token)
+                            },
+                        ));
+                    }
+                    None => self.read += 1,
+                },
+            }
+        }
+
+        self.read = self.data.len();
+        let remaining = &self.data[start..];
+        if remaining.is_empty() {
+            None
+        } else {
+            Some(Token::Normal(remaining))
+        }
+    }
+}
+
+/// Tokenizes `data` with the default configuration, yielding malformed constructs as text.
+///
+/// This never panics, for any input. Prefixes which don't begin a key, as in a trailing `$` or
+/// an empty `${}`, and unterminated keys, as in `${name`, are yielded as `Token::Normal` text,
+/// merged with any text surrounding them, rather than as empty or unterminated keys.
+///
+/// ```rust
+/// use token_expander::{tokenize_lossy, Token};
+///
+/// assert_eq!(
+///     tokenize_lossy("cost: 5$, ${} ${name} ${version"),
+///     vec![Token::Normal("cost: 5$, ${} "), Token::Key("name"), Token::Normal(" ${version")]
+/// );
+/// ```
+pub fn tokenize_lossy(data: &str) -> Vec<Token<'_>> {
+    let mut tokenizer = Tokenizer::new(data);
+    tokenizer.strict(true);
+
+    let mut tokens = Vec::new();
+    let mut text: Option<Range<usize>> = None;
+    for SpannedToken { token, span, .. } in tokenizer.spanned() {
+        match token {
+            Token::Normal(_) | Token::Key("") | Token::Error(_) => {
+                text = Some(match text {
+                    Some(ref text) if text.end == span.start => text.start..span.end,
+                    _ => span,
+                });
+            }
+            token => {
+                if let Some(text) = text.take() {
+                    tokens.push(Token::Normal(&data[text]));
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    if let Some(text) = text {
+        tokens.push(Token::Normal(&data[text]));
+    }
+
+    tokens
+}
+
+/// Expands each of `templates` with the default configuration, as `TokenizerExt::expand`
+/// does, reusing one `map` for all of them, such as for rendering a list of paths with the
+/// same values. Expansion stops at the first template which fails.
+///
+/// ```rust
+/// use token_expander::{expand_all, Token};
+///
+/// let expanded = expand_all(vec!["$name.deb", "${name}.rpm"], |buf, token| {
+///     match token {
+///         Token::Normal(text) => buf.push_str(text),
+///         Token::Key("name") => buf.push_str("system76"),
+///         other => return Err(format!("unsupported: {:?}", other)),
+///     }
+///     Ok(true)
+/// });
+///
+/// assert_eq!(expanded, Ok(vec!["system76.deb".into(), "system76.rpm".into()]));
+/// ```
+pub fn expand_all<'a, I, F, T>(templates: I, mut map: F) -> Result<Vec<String>, T>
+where
+    I: IntoIterator<Item = &'a str>,
+    F: FnMut(&mut String, Token) -> Result<bool, T>,
+{
+    templates
+        .into_iter()
+        .map(|template| Tokenizer::new(template).expand(&mut map))
+        .collect()
+}
+
+/// Checks whether a `value` contains a `$` prefix, as in `$name` or `${name}`, or the `escape`
+/// character, either of which would be interpreted if the value were expanded again.
+///
+/// Values which are substituted into a template that is later expanded a second time, or
+/// which are themselves expanded, should be rejected when this returns true, so that an
+/// untrusted value cannot reference other keys. Escaped prefixes are reported as well, since
+/// their escape is itself a marker.
+///
+/// ```rust
+/// use token_expander::contains_nested_markers;
+///
+/// assert!(!contains_nested_markers("system76 1.0.0", b'\\'));
+/// assert!(contains_nested_markers("${HOME}", b'\\'));
+/// assert!(contains_nested_markers("5#", b'#'));
+/// ```
+pub fn contains_nested_markers(value: &str, escape: u8) -> bool {
+    value.bytes().any(|byte| byte == b'$' || byte == escape)
+}
+
+/// Splits `text` at the first `/` which is not escaped.
+fn split_unescaped(text: &str, escape: char) -> Option<(&str, &str)> {
+    let mut chars = text.char_indices();
+    while let Some((position, character)) = chars.next() {
+        if character == escape {
+            chars.next();
+        } else if character == '/' {
+            return Some((&text[..position], &text[position + 1..]));
+        }
+    }
+    None
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|character| character.is_ascii_alphanumeric() || character == '_')
+}
+
+/// The token to expand in place of `token`, which is `Token::Normal` text with its runs of
+/// whitespace collapsed into `buf` when whitespace is normalized.
+fn normalized<'b>(normalize: bool, buf: &'b mut String, token: Token<'b>) -> Token<'b> {
+    match token {
+        Token::Normal(text) if normalize => {
+            collapse_whitespace(buf, text);
+            Token::Normal(buf)
+        }
+        token => token,
+    }
+}
+
+fn collapse_whitespace(buf: &mut String, text: &str) {
+    buf.clear();
+    let mut previous_whitespace = false;
+    for character in text.chars() {
+        let whitespace = character.is_whitespace();
+        if !(whitespace && previous_whitespace) {
+            buf.push(character);
+        }
+        previous_whitespace = whitespace;
+    }
+}
+
+/// An iterator over the tokens of a borrowed `Tokenizer`, created by `Tokenizer::iter`.
+#[derive(Debug, Clone)]
+pub struct TokenizerIter<'t, 'a: 't> {
+    tokenizer: Tokenizer<'a>,
+    marker: PhantomData<&'t Tokenizer<'a>>,
+}
+
+impl<'t, 'a> Iterator for TokenizerIter<'t, 'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        self.tokenizer.next()
+    }
+}
+
+impl<'t, 'a> IntoIterator for &'t Tokenizer<'a> {
+    type Item = Token<'a>;
+    type IntoIter = TokenizerIter<'t, 'a>;
+
+    fn into_iter(self) -> TokenizerIter<'t, 'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn tokens() {
+        let url = "https://${domain}/$repo/$name/${name}_${version}_$arch.deb";
+        assert_eq!(
+            Tokenizer::new(url).collect::<Vec<_>>(),
+            vec![
+                Token::Normal("https://"),
+                Token::Key("domain"),
+                Token::Normal("/"),
+                Token::Key("repo"),
+                Token::Normal("/"),
+                Token::Key("name"),
+                Token::Normal("/"),
+                Token::Key("name"),
+                Token::Normal("_"),
+                Token::Key("version"),
+                Token::Normal("_"),
+                Token::Key("arch"),
+                Token::Normal(".deb"),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_bounds() {
+        let raw = Delimiters {
+            open: "{%",
+            close: "%}",
+        };
+        let configs: &[fn(&mut Tokenizer)] = &[
+            |_| (),
+            |tokenizer| {
+                tokenizer.strict(true);
+            },
+            |tokenizer| {
+                tokenizer.default_values(true).collapse_escapes(true);
+            },
+            |tokenizer| {
+                tokenizer.decode_escapes(true).set_escape_sequence(b"\\\\");
+            },
+        ];
+        let templates = [
+            "$", "a$", "${", "${a", "${a:-", "\\", "a\\", "\\\\", "\\u{", "\\u{e9", "$a\\", "é\\",
+            "{%", "{%a", "a{%%",
+        ];
+
+        for config in configs {
+            for template in &templates {
+                let mut tokenizer = Tokenizer::new(template);
+                tokenizer.set_raw_delimiters(Some(raw));
+                config(&mut tokenizer);
+
+                assert!(tokenizer.by_ref().take(template.len() + 1).count() <= template.len());
+                assert_eq!(tokenizer.read(), template.len(), "{:?}", template);
+                assert_eq!(tokenizer.next(), None, "{:?}", template);
+                assert_eq!(tokenizer.read(), template.len(), "{:?}", template);
+            }
+        }
+    }
+
+    #[test]
+    fn adjacent_keys() {
+        let spans = |template| {
+            Tokenizer::new(template)
+                .spanned()
+                .map(|token| (token.token, token.span))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            spans("$a$b"),
+            vec![(Token::Key("a"), 0..2), (Token::Key("b"), 2..4)]
+        );
+        assert_eq!(
+            spans("$a${b}"),
+            vec![(Token::Key("a"), 0..2), (Token::Key("b"), 2..6)]
+        );
+        assert_eq!(
+            spans("${a}$b$c"),
+            vec![
+                (Token::Key("a"), 0..4),
+                (Token::Key("b"), 4..6),
+                (Token::Key("c"), 6..8)
+            ]
+        );
+    }
+
+    #[test]
+    #[allow(clippy::redundant_guards)]
+    fn expander() {
+        let url = "https://app.domain.org/${name}/${name}_${version}.deb";
+        assert_eq!(
+            Tokenizer::new(url).expand(|buf, key| {
+                match key {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Key("name") => buf.push_str("system76"),
+                    Token::Key("version") => buf.push_str("1.0.0"),
+                    Token::Escaped(_) => panic!("didn't expect an escaped character"),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+
+                Ok(true)
+            }),
+            Ok("https://app.domain.org/system76/system76_1.0.0.deb".into())
+        );
+
+        assert_eq!(
+            Tokenizer::new("https://app.domain.org/package_version.deb").expand(
+                |buf, key| -> Result<bool, String> {
+                    match key {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Key(key) if key == "foo" => {
+                            buf.push_str("bar");
+                        }
+                        _ => (),
+                    }
+
+                    Ok(true)
+                }
+            ),
+            Ok("https://app.domain.org/package_version.deb".into())
+        );
+    }
+
+    #[test]
+    fn escaper() {
+        let pattern = "foo\\${bar}";
+        assert_eq!(
+            Tokenizer::new(pattern).collect::<Vec<_>>(),
+            vec![
+                Token::Normal("foo"),
+                Token::Escaped('$'),
+                Token::Normal("{bar}"),
+            ]
+        );
+
+        assert_eq!(
+            Tokenizer::new("foo###${bar}")
+                .set_escape(b'#')
+                .collect::<Vec<_>>(),
+            vec![
+                Token::Normal("foo"),
+                Token::Escaped('#'),
+                Token::Escaped('$'),
+                Token::Normal("{bar}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn borrowed_iter() {
+        let tokenizer = Tokenizer::new("foo\\$${bar}/$baz");
+        let first = tokenizer.iter().collect::<Vec<_>>();
+        let second = tokenizer.iter().collect::<Vec<_>>();
+        assert_eq!(
+            first,
+            vec![
+                Token::Normal("foo"),
+                Token::Escaped('$'),
+                Token::Key("bar"),
+                Token::Normal("/"),
+                Token::Key("baz"),
+            ]
+        );
+        assert_eq!(first, second);
+        assert_eq!((&tokenizer).into_iter().collect::<Vec<_>>(), first);
+        assert_eq!(tokenizer.read(), 0);
+    }
+
+    #[test]
+    fn escape_builder() {
+        let mut tokenizer = Tokenizer::new("a#${b}");
+        tokenizer.set_escape(b'#').set_escape(b'%').set_escape(b'#');
+        assert_eq!(tokenizer.get_escape(), b'#');
+        assert_eq!(tokenizer.next(), Some(Token::Normal("a")));
+
+        assert_eq!(
+            tokenizer.try_set_escape(b'\\').err(),
+            Some(IterationStarted { read: 2 })
+        );
+        assert_eq!(tokenizer.get_escape(), b'#');
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![Token::Escaped('$'), Token::Normal("{b}")]
+        );
+
+        let mut fresh = Tokenizer::new("a%${b}");
+        assert_eq!(
+            fresh
+                .try_set_escape(b'%')
+                .map(|tokenizer| tokenizer.collect::<Vec<_>>()),
+            Ok(vec![
+                Token::Normal("a"),
+                Token::Escaped('$'),
+                Token::Normal("{b}"),
+            ])
+        );
+    }
+
+    #[test]
+    fn match_policy() {
+        let template = "$$pid/$${user}/$home";
+        assert_eq!(
+            Tokenizer::new(template)
+                .set_prefixes(&["$", "$$"])
+                .collect::<Vec<_>>(),
+            vec![
+                Token::Key("pid"),
+                Token::Normal("/"),
+                Token::Key("user"),
+                Token::Normal("/"),
+                Token::Key("home"),
+            ]
+        );
+
+        assert_eq!(
+            Tokenizer::new(template)
+                .set_prefixes(&["$", "$$"])
+                .set_match_policy(MatchPolicy::First)
+                .collect::<Vec<_>>(),
+            vec![
+                Token::Key(""),
+                Token::Key("pid"),
+                Token::Normal("/"),
+                Token::Key(""),
+                Token::Key("user"),
+                Token::Normal("/"),
+                Token::Key("home"),
+            ]
+        );
+
+        assert_eq!(
+            Tokenizer::new(template)
+                .set_prefixes(&["$$", "$"])
+                .set_match_policy(MatchPolicy::First)
+                .collect::<Vec<_>>(),
+            Tokenizer::new(template)
+                .set_prefixes(&["$", "$$"])
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn whitespace_normalization() {
+        fn render(buf: &mut String, token: Token) -> Result<bool, ()> {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Key(key) => buf.push_str(&key.to_uppercase()),
+                Token::Escaped(character) => buf.push(character),
+                other => panic!("unexpected token: {:?}", other),
+            }
+            Ok(true)
+        }
+
+        let template = "a   b\t\tc \\  \\ d${ x  y }\t  \n end  ";
+        assert_eq!(
+            Tokenizer::new(template).expand(render),
+            Ok("a   b\t\tc    d X  Y \t  \n end  ".into())
+        );
+        assert_eq!(
+            Tokenizer::new(template)
+                .normalize_whitespace(true)
+                .expand(render),
+            Ok("a b\tc    d X  Y \tend ".into())
+        );
+        assert_eq!(
+            Tokenizer::new(template)
+                .normalize_whitespace(true)
+                .normalize_whitespace(false)
+                .expand(render),
+            Tokenizer::new(template).expand(render)
+        );
+    }
+
+    #[test]
+    fn estimate() {
+        let values = [
+            ("domain", "apt.pop-os.org"),
+            ("repo", "free"),
+            ("name", "system76"),
+            ("version", "1.0.0"),
+            ("arch", "amd64"),
+        ];
+        let value = |key: &str| {
+            values
+                .iter()
+                .find(|&&(name, _)| name == key)
+                .map_or("", |&(_, value)| value)
+        };
+
+        let tokenizer =
+            Tokenizer::new("https://${domain}/$repo/$name/\\$${name}_${version}_$arch.deb");
+        let estimate = tokenizer.estimate_len(|key| value(key).len());
+        let output = tokenizer.clone().expand(|buf, token| {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Key(key) => buf.push_str(value(key)),
+                Token::Escaped(character) => buf.push(character),
+                other => panic!("unexpected token: {:?}", other),
+            }
+            Ok::<_, ()>(true)
+        });
+
+        assert_eq!(
+            output,
+            Ok("https://apt.pop-os.org/free/system76/$system76_1.0.0_amd64.deb".into())
+        );
+        assert_eq!(estimate, output.unwrap().len());
+        assert_eq!(tokenizer.estimate_len(|_| 0), "https://///$__.deb".len());
+    }
+
+    #[test]
+    fn consecutive_escapes() {
+        let collect = |template, collapse| {
+            Tokenizer::new(template)
+                .collapse_escapes(collapse)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(collect(r"\\", false), vec![Token::Escaped('\\')]);
+        assert_eq!(
+            collect(r"\\\\", false),
+            vec![Token::Escaped('\\'), Token::Escaped('\\')]
+        );
+        assert_eq!(
+            collect(r"\\\\\\", false),
+            vec![
+                Token::Escaped('\\'),
+                Token::Escaped('\\'),
+                Token::Escaped('\\'),
+            ]
+        );
+        assert_eq!(
+            collect(r"a\\\\\$b", false),
+            vec![
+                Token::Normal("a"),
+                Token::Escaped('\\'),
+                Token::Escaped('\\'),
+                Token::Escaped('$'),
+                Token::Normal("b"),
+            ]
+        );
+
+        assert_eq!(collect(r"\\", true), vec![Token::Escaped('\\')]);
+        assert_eq!(collect(r"\\\\", true), vec![Token::Escaped('\\')]);
+        assert_eq!(collect(r"\\\\\\", true), vec![Token::Escaped('\\')]);
+        assert_eq!(
+            collect(r"a\\\\\$b", true),
+            vec![
+                Token::Normal("a"),
+                Token::Escaped('\\'),
+                Token::Escaped('$'),
+                Token::Normal("b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn references() {
+        let tokenizer = Tokenizer::new("${name}/$version/${arch:-amd64}/\\$escaped");
+        assert!(tokenizer.references("name"));
+        assert!(tokenizer.references("version"));
+        assert!(!tokenizer.references("arch"));
+        assert!(tokenizer.references("arch:-amd64"));
+        assert!(!tokenizer.references("escaped"));
+        assert!(!tokenizer.references("missing"));
+        assert_eq!(tokenizer.read(), 0);
+
+        let mut defaults = tokenizer.clone();
+        defaults.default_values(true);
+        assert!(defaults.references("arch"));
+
+        // The search begins from the tokenizer's current position.
+        let mut partial = tokenizer.clone();
+        partial.next();
+        assert!(!partial.references("name"));
+        assert!(partial.references("version"));
+
+        // Keys after the first reference are never lexed.
+        let lexed = Rc::new(RefCell::new(Vec::new()));
+        let mut counted = Tokenizer::new("$a/$b/$c/$b");
+        counted.with_key_validator({
+            let lexed = lexed.clone();
+            move |key| {
+                lexed.borrow_mut().push(key.to_owned());
+                Ok(())
+            }
+        });
+        assert!(counted.references("b"));
+        assert_eq!(*lexed.borrow(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn key_validator() {
+        fn no_dashes(name: &str) -> Result<(), String> {
+            if name.contains('-') {
+                Err(format!("'{}' contains a '-'", name))
+            } else {
+                Ok(())
+            }
+        }
+
+        let expand = |template| {
+            Tokenizer::new(template)
+                .with_key_validator(no_dashes)
+                .expand(|buf, token| {
+                    match token {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Key(key) => buf.push_str(&key.to_uppercase()),
+                        Token::Escaped(character) => buf.push(character),
+                        Token::Error(why) => return Err(why.reason),
+                        other => panic!("unexpected token: {:?}", other),
+                    }
+                    Ok(true)
+                })
+        };
+
+        assert_eq!(expand("${name}_$version"), Ok("NAME_VERSION".into()));
+        assert_eq!(
+            expand("${name}_${pkg-version}"),
+            Err("'pkg-version' contains a '-'".into())
+        );
+        assert_eq!(expand("$pkg-version"), Ok("PKG-version".into()));
+
+        assert_eq!(
+            Tokenizer::new("a${b-c}")
+                .with_key_validator(no_dashes)
+                .default_values(true)
+                .collect::<Vec<_>>(),
+            vec![
+                Token::Normal("a"),
+                Token::Error(TokenError {
+                    offset: 1,
+                    line: 1,
+                    column: 2,
+                    reason: "'b-c' contains a '-'".into(),
+                }),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("${b:-c-d}")
+                .with_key_validator(no_dashes)
+                .default_values(true)
+                .next(),
+            Some(Token::KeyDefault {
+                key: "b",
+                default: "c-d"
+            })
+        );
+
+        // Validators may capture their policy, and are shared with clones of the tokenizer.
+        let allowed = ["name".to_owned(), "version".to_owned()];
+        let mut tokenizer = Tokenizer::new("${name}/${arch}");
+        tokenizer.with_key_validator(move |name| {
+            if allowed.iter().any(|allowed| allowed == name) {
+                Ok(())
+            } else {
+                Err(format!("'{}' is not allowed", name))
+            }
+        });
+        assert_eq!(tokenizer.clone(), tokenizer);
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Token::Key("name"),
+                Token::Normal("/"),
+                Token::Error(TokenError::new(
+                    "${name}/${arch}",
+                    8,
+                    "'arch' is not allowed".into()
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_key() {
+        assert_eq!(
+            Tokenizer::new("prefix${x}suffix").single_key(),
+            Some(("prefix", "x", "suffix"))
+        );
+        assert_eq!(Tokenizer::new("$x/").single_key(), Some(("", "x", "/")));
+        assert_eq!(Tokenizer::new("a/$x").single_key(), Some(("a/", "x", "")));
+        assert_eq!(Tokenizer::new("${x}").single_key(), Some(("", "x", "")));
+
+        assert_eq!(Tokenizer::new("").single_key(), None);
+        assert_eq!(Tokenizer::new("no keys").single_key(), None);
+        assert_eq!(Tokenizer::new("${x}/${y}").single_key(), None);
+        assert_eq!(Tokenizer::new("${x}${y}").single_key(), None);
+        assert_eq!(Tokenizer::new("\\$${x}").single_key(), None);
+        assert_eq!(Tokenizer::new("${x}\\n").single_key(), None);
+    }
+
+    #[test]
+    fn tee() {
+        let (mut out, mut log) = (String::from("url: "), String::new());
+        let result = Tokenizer::new("https://$domain/${name}/${name}_\\$$version.deb").expand_tee(
+            &mut out,
+            &mut log,
+            |buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Escaped(character) => buf.push(character),
+                    Token::Key("domain") => buf.push_str("apt.pop-os.org"),
+                    Token::Key("name") => buf.push_str("system76"),
+                    Token::Key(_) => (),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+                Ok(true)
+            },
+        );
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(out, "url: https://apt.pop-os.org/system76/system76_$.deb");
+        assert_eq!(
+            log,
+            "domain=apt.pop-os.org\nname=system76\nname=system76\nversion=\n"
+        );
+    }
+
+    #[test]
+    fn tee_error() {
+        let (mut out, mut log) = (String::new(), String::new());
+        let result =
+            Tokenizer::new("$name/$missing").expand_tee(&mut out, &mut log, |buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Key("name") => buf.push_str("system76"),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+                Ok(true)
+            });
+
+        assert_eq!(result, Err("unsupported: Key(\"missing\")".into()));
+        assert_eq!(out, "");
+        assert_eq!(log, "name=system76\n");
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(
+            Tokenizer::windows(r"%SystemRoot%\System32;%PATH%").collect::<Vec<_>>(),
+            vec![
+                Token::Key("SystemRoot"),
+                Token::Normal(r"\System32;"),
+                Token::Key("PATH"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::windows("a%%b").collect::<Vec<_>>(),
+            vec![Token::Normal("a"), Token::Escaped('%'), Token::Normal("b")]
+        );
+        assert_eq!(
+            Tokenizer::windows("$HOME: %VAR").collect::<Vec<_>>(),
+            vec![Token::Normal("$HOME: "), Token::Normal("%VAR")]
+        );
+        assert_eq!(
+            Tokenizer::windows("%A%%B%^%C").collect::<Vec<_>>(),
+            vec![
+                Token::Key("A"),
+                Token::Key("B"),
+                Token::Escaped('%'),
+                Token::Normal("C"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::windows("é%A%ü").collect::<Vec<_>>(),
+            vec![Token::Normal("é"), Token::Key("A"), Token::Normal("ü")]
+        );
+
+        let empty = Delimiters {
+            open: "",
+            close: "",
+        };
+        assert_eq!(
+            Tokenizer::new("a$b")
+                .set_delimiters(Some(empty))
+                .collect::<Vec<_>>(),
+            vec![Token::Normal("a"), Token::Key("b")]
+        );
+    }
+
+    #[test]
+    fn mustache() {
+        assert_eq!(
+            Tokenizer::mustache("{{name}} {{  version\t}}/{{ arch }}").collect::<Vec<_>>(),
+            vec![
+                Token::Key("name"),
+                Token::Normal(" "),
+                Token::Key("version"),
+                Token::Normal("/"),
+                Token::Key("arch"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::mustache("{{a}}{{b}}").collect::<Vec<_>>(),
+            vec![Token::Key("a"), Token::Key("b")]
+        );
+        assert_eq!(
+            Tokenizer::mustache("fn main() { $x }").collect::<Vec<_>>(),
+            vec![Token::Normal("fn main() { $x }")]
+        );
+        assert_eq!(
+            Tokenizer::mustache("a {{{ raw }}} b").collect::<Vec<_>>(),
+            vec![
+                Token::Normal("a "),
+                Token::Error(TokenError {
+                    offset: 2,
+                    line: 1,
+                    column: 3,
+                    reason: "unsupported key: {{{ raw }}}".into(),
+                }),
+                Token::Normal(" b"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::mustache("{{ open").collect::<Vec<_>>(),
+            vec![Token::Normal("{{ open")]
+        );
+        assert_eq!(
+            Tokenizer::new("${ name }").trim_keys(true).next(),
+            Some(Token::Key("name"))
+        );
+    }
+
+    #[test]
+    fn error_location() {
+        let template = "[package]\nname = \"${name}\"\n\tversion = \"${version\"\n";
+        let error = |tokenizer: &mut Tokenizer| {
+            tokenizer.find_map(|token| match token {
+                Token::Error(why) => Some(why),
+                _ => None,
+            })
+        };
+
+        assert_eq!(error(&mut Tokenizer::new(template)), None);
+        assert_eq!(
+            error(Tokenizer::new(template).strict(true)),
+            Some(TokenError {
+                offset: 39,
+                line: 3,
+                column: 13,
+                reason: "unterminated key".into(),
+            })
+        );
+        assert_eq!(
+            error(Tokenizer::new("é\nü ${ü").strict(true)).map(|why| why.to_string()),
+            Some("unterminated key at 2:3".into())
+        );
+        assert_eq!(
+            error(Tokenizer::windows("%A%\n %B").strict(true)).map(|why| (why.line, why.column)),
+            Some((2, 2))
+        );
+        assert_eq!(
+            error(
+                Tokenizer::new("a\n\nb $c-d").with_key_validator(|key| match key {
+                    "c" => Err("reserved".into()),
+                    _ => Ok(()),
+                })
+            ),
+            Some(TokenError::new("a\n\nb $c-d", 5, "reserved".into()))
+        );
+        assert_eq!(
+            TokenError::new("a\n\nb $c-d", 5, "reserved".into()),
+            TokenError {
+                offset: 5,
+                line: 3,
+                column: 3,
+                reason: "reserved".into()
+            }
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let expand = |template, context| {
+            Tokenizer::new(template).expand_escaping(
+                |buf, token| {
+                    match token {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Escaped(character) => buf.push(character),
+                        Token::Key("file") => buf.push_str("my file.txt"),
+                        Token::Key("quoted") => buf.push_str("it's"),
+                        Token::Key("safe") => buf.push_str("/usr/bin/env"),
+                        Token::Key("query") => buf.push_str("a&b=c d/é"),
+                        Token::Key("html") => buf.push_str("<b class=\"x\">&</b>"),
+                        other => return Err(format!("unsupported: {:?}", other)),
+                    }
+                    Ok(true)
+                },
+                context,
+            )
+        };
+
+        assert_eq!(
+            expand(r"cat ${file} ${safe} ${quoted} & \${file}", Context::Shell),
+            Ok(r"cat 'my file.txt' /usr/bin/env 'it'\''s' & ${file}".into())
+        );
+        assert_eq!(
+            expand("https://a.org/?q=$query&x=1", Context::Url),
+            Ok("https://a.org/?q=a%26b%3Dc%20d%2F%C3%A9&x=1".into())
+        );
+        assert_eq!(
+            expand("<i title=\"$html\">$html</i>", Context::Html),
+            Ok("<i title=\"&lt;b class=&quot;x&quot;&gt;&amp;&lt;/b&gt;\">\
+                &lt;b class=&quot;x&quot;&gt;&amp;&lt;/b&gt;</i>"
+                .into())
+        );
+    }
+
+    #[test]
+    fn key_spans() {
+        let template = "https://${domain}/\\$x/$repo.deb";
+        assert_eq!(
+            Tokenizer::new(template).key_spans().collect::<Vec<_>>(),
+            vec![("domain", 8..17), ("repo", 22..27)]
+        );
+        assert_eq!(&template[8..17], "${domain}");
+        assert_eq!(&template[22..27], "$repo");
+
+        assert_eq!(
+            Tokenizer::new("${ a :-b}")
+                .default_values(true)
+                .trim_keys(true)
+                .clone()
+                .key_spans()
+                .collect::<Vec<_>>(),
+            vec![("a", 0..9)]
+        );
+    }
+
+    #[test]
+    fn strict_braces() {
+        let stray = |data, offset| {
+            Token::Error(TokenError::new(data, offset, "stray closing brace".into()))
+        };
+
+        assert_eq!(
+            Tokenizer::new("a}b")
+                .strict_braces(true)
+                .collect::<Vec<_>>(),
+            vec![Token::Normal("a"), stray("a}b", 1), Token::Normal("b")]
+        );
+        assert_eq!(
+            Tokenizer::new("${x}}")
+                .strict_braces(true)
+                .collect::<Vec<_>>(),
+            vec![Token::Key("x"), stray("${x}}", 4)]
+        );
+        assert_eq!(
+            Tokenizer::new("a\\}b")
+                .strict_braces(true)
+                .collect::<Vec<_>>(),
+            vec![Token::Normal("a"), Token::Escaped('}'), Token::Normal("b")]
+        );
+        assert_eq!(
+            Tokenizer::new("a}b").collect::<Vec<_>>(),
+            vec![Token::Normal("a}b")]
+        );
+    }
+
+    #[test]
+    fn transformed() {
+        let expanded = Tokenizer::new("name=$name, arch=${arch} \\$name").expand_transformed(
+            |buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Escaped(character) => buf.push(character),
+                    Token::Key("name") => buf.push_str("system76"),
+                    Token::Key("arch") => buf.push_str("amd64"),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+                Ok(true)
+            },
+            |value| value.to_uppercase().into(),
+        );
+
+        assert_eq!(expanded, Ok("name=SYSTEM76, arch=AMD64 $name".into()));
+    }
+
+    #[test]
+    fn mapping_text() {
+        let expanded = Tokenizer::new("a\tb\\\t${c}\t").expand_mapping_text(
+            |buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Escaped(character) => buf.push(character),
+                    Token::Key("c") => buf.push_str("\tc"),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+                Ok(true)
+            },
+            |text| text.replace('\t', "    ").into(),
+        );
+
+        assert_eq!(expanded, Ok("a    b\t\tc    ".into()));
+    }
+
+    #[test]
+    fn trace_tokens() {
+        let mut trace = Vec::new();
+        let template = r"a\$b${c:-d}$e}";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.default_values(true).strict_braces(true);
+        let tokens = tokenizer
+            .clone()
+            .trace_tokens(|token, offset| trace.push((token.clone(), offset)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(tokens, tokenizer.collect::<Vec<_>>());
+        assert_eq!(
+            trace,
+            vec![
+                (Token::Normal("a"), 0),
+                (Token::Escaped('$'), 1),
+                (Token::Normal("b"), 3),
+                (
+                    Token::KeyDefault {
+                        key: "c",
+                        default: "d"
+                    },
+                    4
+                ),
+                (Token::Key("e"), 11),
+                (
+                    Token::Error(TokenError::new(template, 13, "stray closing brace".into())),
+                    13
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_strings() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .raw_strings(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens(r#"a r"$x" $y"#),
+            vec![
+                Token::Normal("a "),
+                Token::Normal("$x"),
+                Token::Normal(" "),
+                Token::Key("y"),
+            ]
+        );
+        assert_eq!(tokens(r##"r#"a"b"#"##), vec![Token::Normal(r#"a"b"#)]);
+        assert_eq!(
+            tokens(r###"r##"a"#b"##c"###),
+            vec![Token::Normal(r##"a"#b"##), Token::Normal("c")]
+        );
+        assert_eq!(tokens(r#"r""$x"#), vec![Token::Key("x")]);
+        assert_eq!(
+            tokens(r#"for "$x""#),
+            vec![
+                Token::Normal(r#"for ""#),
+                Token::Key("x"),
+                Token::Normal("\"")
+            ]
+        );
+        assert_eq!(
+            tokens(r#"\r"$x""#),
+            vec![
+                Token::Escaped('r'),
+                Token::Normal("\""),
+                Token::Key("x"),
+                Token::Normal("\"")
+            ]
+        );
+        assert_eq!(tokens(r##"r#"a"b"##), vec![Token::Normal(r#"a"b"#)]);
+
+        let template = r#"${x} r"a"#;
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.raw_strings(true).strict(true);
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Token::Key("x"),
+                Token::Normal(" "),
+                Token::Error(TokenError::new(
+                    template,
+                    5,
+                    "unterminated raw string".into()
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_blocks() {
+        let raw = Delimiters {
+            open: "{{raw}}",
+            close: "{{/raw}}",
+        };
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .set_raw_delimiters(Some(raw))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("a{{raw}}${x} \\$y {{/raw}}$z"),
+            vec![
+                Token::Normal("a"),
+                Token::Normal("${x} \\$y "),
+                Token::Key("z")
+            ]
+        );
+        assert_eq!(
+            tokens("a{{raw}}{{/raw}}b$c"),
+            vec![Token::Normal("a"), Token::Normal("b"), Token::Key("c")]
+        );
+        assert_eq!(
+            tokens("\\{{raw}}$x"),
+            vec![
+                Token::Escaped('{'),
+                Token::Normal("{raw}}"),
+                Token::Key("x")
+            ]
+        );
+        assert_eq!(
+            tokens("$x{{raw}}${y}"),
+            vec![Token::Key("x"), Token::Normal("${y}")]
+        );
+        assert_eq!(
+            tokens("é{{raw}}$x{{/raw}}ü"),
+            vec![Token::Normal("é"), Token::Normal("$x"), Token::Normal("ü")]
+        );
+
+        let spans = Tokenizer::new("a{{raw}}{{/raw}}b{{raw}}$c{{/raw}}\\$d")
+            .set_raw_delimiters(Some(raw))
+            .clone()
+            .spanned()
+            .map(|token| token.span)
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![0..1, 1..17, 17..34, 34..36, 36..37]);
+
+        let template = "$x{{raw}}${y}";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.set_raw_delimiters(Some(raw)).strict(true);
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Token::Key("x"),
+                Token::Error(TokenError::new(
+                    template,
+                    2,
+                    "unterminated raw block".into()
+                ))
+            ]
+        );
+    }
+
+    #[test]
+    fn equality() {
+        let configured = |escape| {
+            let mut tokenizer = Tokenizer::mustache("{{ name }}");
+            tokenizer.set_escape(escape).strict(true);
+            tokenizer
+        };
+
+        assert_eq!(configured(b'#'), configured(b'#'));
+        assert_ne!(configured(b'#'), configured(b'\\'));
+        assert_ne!(configured(b'#'), *configured(b'#').strict(false));
+        assert_ne!(Tokenizer::new("$a"), Tokenizer::new("$b"));
+
+        let mut started = configured(b'#');
+        started.next();
+        assert_ne!(started, configured(b'#'));
+
+        let mut validated = configured(b'#');
+        validated.with_key_validator(|_| Ok(()));
+        assert_ne!(validated, configured(b'#'));
+
+        assert!(format!("{:?}", configured(b'#'))
+            .contains("escape: '#', modes: [\"trim_keys\", \"strict\"]"));
+    }
+
+    #[test]
+    fn default() {
+        assert!(Tokenizer::default().next().is_none());
+        assert_eq!(Tokenizer::default(), Tokenizer::new(""));
+        assert_eq!(Tokenizer::default().get_escape(), b'\\');
+    }
+
+    #[test]
+    fn expand_with_store() {
+        let template = "$a/${a=1}/$a/${b}/${a=2}/${a}/${=x}";
+        let expanded = Tokenizer::new(template).expand_with_store(|buf, token| {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Key(key) => {
+                    buf.push('<');
+                    buf.push_str(key);
+                    buf.push('>');
+                }
+                other => return Err(format!("unsupported: {:?}", other)),
+            }
+            Ok(true)
+        });
+
+        assert_eq!(expanded, Ok("<a>/1/1/<b>/2/2/x".into()));
+    }
+
+    #[test]
+    fn expand_indexed() {
+        let expanded = Tokenizer::new(r"$a/${b}\$/${a}").expand_indexed(|buf, token, index| {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Escaped(character) => buf.push(character),
+                Token::Key(key) => buf.push_str(key),
+                other => return Err(format!("unsupported: {:?}", other)),
+            }
+            buf.push_str(&index.to_string());
+            Ok(true)
+        });
+
+        assert_eq!(expanded, Ok("a0/1b1$2/2a2".into()));
+    }
+
+    #[test]
+    fn expand_multi() {
+        let owner = String::from("pop");
+        let expanded = Tokenizer::new(r"${path}\$ /$user").expand_multi(|token| match token {
+            Token::Key("path") => Ok(vec![
+                "/home".into(),
+                Cow::Borrowed("/"),
+                owner.as_str().into(),
+            ]),
+            Token::Key("user") => Ok(vec![Cow::Borrowed(owner.as_str())]),
+            other => Err(format!("unsupported: {:?}", other)),
+        });
+        assert_eq!(expanded, Ok("/home/pop$ /pop".into()));
+
+        let expanded = Tokenizer::new("a${b}c").expand_multi(|_| Ok::<_, ()>(Vec::new()));
+        assert_eq!(expanded, Ok("ac".into()));
+        assert_eq!(
+            Tokenizer::new("$a").expand_multi(|_| Err::<Vec<Cow<str>>, _>("failed")),
+            Err("failed")
+        );
+    }
+
+    #[test]
+    fn expand_or_partial() {
+        let expand = |template| {
+            Tokenizer::new(template).expand_or_partial(|buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Key("a") => buf.push_str("one"),
+                    Token::Key("b") => buf.push_str("two"),
+                    Token::Key(key) => return Err(format!("missing: {}", key)),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+                Ok(true)
+            })
+        };
+
+        assert_eq!(
+            expand("${a}/${b}/${c}/${a}"),
+            Err(("one/two/".into(), "missing: c".into()))
+        );
+        assert_eq!(expand("${a}/${b}"), Ok("one/two".into()));
+    }
+
+    #[test]
+    fn expand_bytes() {
+        let expanded = Tokenizer::new("${dir}/\\$file: ${data}").expand_bytes(|buf, token| {
+            match token {
+                Token::Normal(text) => buf.extend_from_slice(text.as_bytes()),
+                Token::Escaped(character) => {
+                    buf.extend_from_slice(character.encode_utf8(&mut [0; 4]).as_bytes())
+                }
+                Token::Key("dir") => buf.extend_from_slice(b"/tmp/\xff\xfe"),
+                Token::Key("data") => buf.extend_from_slice(&[0, 0x80, 0xc3]),
+                other => return Err(format!("unsupported: {:?}", other)),
+            }
+            Ok(true)
+        });
+
+        assert_eq!(expanded, Ok(b"/tmp/\xff\xfe/$file: \x00\x80\xc3".to_vec()));
+    }
+
+    #[test]
+    fn expand_utf8() {
+        let expand = |template| {
+            Tokenizer::new(template).expand_utf8(|buf, token| {
+                match token {
+                    Token::Normal(text) => buf.extend_from_slice(text.as_bytes()),
+                    Token::Key("valid") => buf.extend_from_slice(b"\xe2\x82\xac5"),
+                    Token::Key("invalid") => buf.extend_from_slice(b"\xe2\x82"),
+                    _ => (),
+                }
+                Ok(true)
+            })
+        };
+
+        assert_eq!(expand("cost: $valid"), Ok("cost: \u{20ac}5".to_string()));
+        let error: Utf8Error = expand("cost: $invalid").unwrap_err();
+        assert_eq!(error.valid_up_to(), 6);
+        assert_eq!(error.error_len(), None);
+    }
+
+    #[test]
+    fn segments() {
+        let template = r"https://${domain}/$repo/\$HOME\/${a:-b}$c\.d-${}$";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.default_values(true);
+        assert_eq!(
+            tokenizer.clone().segments(),
+            vec![
+                Segment::Literal(Cow::Borrowed("https://")),
+                Segment::Key("domain"),
+                Segment::Literal(Cow::Borrowed("/")),
+                Segment::Key("repo"),
+                Segment::Literal(Cow::Owned("/$HOME/".into())),
+                Segment::Key("a"),
+                Segment::Key(r"c\.d"),
+                Segment::Literal(Cow::Borrowed("-")),
+                Segment::Key(""),
+                Segment::Key(""),
+            ]
+        );
+
+        tokenizer.with_key_validator(|key| match key {
+            "" => Err("empty".into()),
+            _ => Ok(()),
+        });
+        assert_eq!(
+            tokenizer.segments().last(),
+            Some(&Segment::Literal(Cow::Borrowed("-${}$")))
+        );
+    }
+
+    #[test]
+    fn decoded_text() {
+        let template = r"\t${a}\$5\u{e9} \\n$b$c/\n";
+        assert_eq!(
+            Tokenizer::new(template).decoded_text().collect::<Vec<_>>(),
+            vec![
+                Segment::Literal(Cow::Borrowed("\t")),
+                Segment::Key("a"),
+                Segment::Literal(Cow::Owned("$5\u{e9} \\n".into())),
+                Segment::Key("b"),
+                Segment::Key("c"),
+                Segment::Literal(Cow::Owned("/\n".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_counts() {
+        let url = "https://$domain/$repo/$name/${name}_${version}_$arch.deb";
+        let counts = Tokenizer::new(url).key_counts();
+
+        let mut expected = HashMap::new();
+        expected.insert("domain", 1);
+        expected.insert("repo", 1);
+        expected.insert("name", 2);
+        expected.insert("version", 1);
+        expected.insert("arch", 1);
+        assert_eq!(counts, expected);
+    }
+
+    #[test]
+    fn canonical_keys() {
+        let tokens = |template, lowercase| {
+            Tokenizer::new(template)
+                .canonical_keys(true)
+                .lowercase_keys(lowercase)
+                .default_values(true)
+                .quoted_keys(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("${ Name }/$name", true),
+            vec![
+                Token::KeyDecoded {
+                    key: "name".into(),
+                    raw: "Name",
+                },
+                Token::Normal("/"),
+                Token::Key("name"),
+            ]
+        );
+        assert_eq!(
+            tokens(r#"${ name }/${"  a\"b "}/${ a :- b }/$Na\.me"#, false),
+            vec![
+                Token::Key("name"),
+                Token::Normal("/"),
+                Token::KeyDecoded {
+                    key: "a\"b".into(),
+                    raw: r#"  a\"b "#,
+                },
+                Token::Normal("/"),
+                Token::KeyDefault {
+                    key: "a",
+                    default: "b",
+                },
+                Token::Normal("/"),
+                Token::KeyDecoded {
+                    key: "Na.me".into(),
+                    raw: r"Na\.me",
+                },
+            ]
+        );
+        assert_eq!(
+            tokens(r"$Na\.me", true),
+            vec![Token::KeyDecoded {
+                key: "na.me".into(),
+                raw: r"Na\.me",
+            }]
+        );
+        assert_eq!(
+            Tokenizer::new("${ Name }")
+                .lowercase_keys(true)
+                .collect::<Vec<_>>(),
+            vec![Token::KeyDecoded {
+                key: " name ".into(),
+                raw: " Name ",
+            }]
+        );
+    }
+
+    #[test]
+    fn structure_hash() {
+        let hash = |template| {
+            Tokenizer::new(template)
+                .default_values(true)
+                .structure_hash()
+        };
+
+        let base = hash("https://${domain}/$repo/${name:-pop}.deb");
+        assert_eq!(base, hash("http://${domain}:80/$repo/${name:-pop}.rpm"));
+        assert_eq!(base, hash("a\\$b${domain}/$repo/${name:-pop}!"));
+        assert_ne!(base, hash("${domain}/$repo/${name:-pop}.deb"));
+        assert_ne!(base, hash("https://${domain}/$repo/${name:-os}.deb"));
+        assert_ne!(base, hash("https://${domain}/$repo/${names:-pop}.deb"));
+        assert_ne!(base, hash("https://${domain}/$repo/${name}.deb"));
+        assert_eq!(
+            Tokenizer::new("a$b").structure_hash(),
+            Tokenizer::new("a${b}").structure_hash()
+        );
+    }
+
+    #[test]
+    fn prefix_kinds() {
+        const ENV: PrefixKind = PrefixKind("env");
+        const CONFIG: PrefixKind = PrefixKind("config");
+        const CONSTANT: PrefixKind = PrefixKind("constant");
+        let kinds = [("$", ENV), ("@", CONFIG), ("#", CONSTANT)];
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .set_prefixes(&[])
+                .set_prefix_kinds(&kinds)
+                .default_values(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("$a/@b/#c"),
+            vec![
+                Token::PrefixedKey {
+                    kind: ENV,
+                    name: "a"
+                },
+                Token::Normal("/"),
+                Token::PrefixedKey {
+                    kind: CONFIG,
+                    name: "b"
+                },
+                Token::Normal("/"),
+                Token::PrefixedKey {
+                    kind: CONSTANT,
+                    name: "c"
+                },
+            ]
+        );
+        assert_eq!(
+            tokens(r"@{b}:#{c:-1}:\#c:$a\.b"),
+            vec![
+                Token::PrefixedKey {
+                    kind: CONFIG,
+                    name: "b"
+                },
+                Token::Normal(":"),
+                Token::KeyDefault {
+                    key: "c",
+                    default: "1"
+                },
+                Token::Normal(":"),
+                Token::Escaped('#'),
+                Token::Normal("c:"),
+                Token::KeyDecoded {
+                    key: "a.b".into(),
+                    raw: r"a\.b"
+                },
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::new("$a@b");
+        tokenizer.set_prefix_kinds(&kinds[1..]);
+        assert_eq!(
+            tokenizer.clone().collect::<Vec<_>>(),
+            vec![
+                Token::Key("a"),
+                Token::PrefixedKey {
+                    kind: CONFIG,
+                    name: "b"
+                },
+            ]
+        );
+        let mut expected = HashMap::new();
+        expected.insert("a", 1);
+        expected.insert("b", 1);
+        assert_eq!(tokenizer.key_counts(), expected);
+    }
+
+    #[test]
+    fn defaults() {
+        let mut tokenizer = Tokenizer::new("${a:-1}/${b:-$c}/$d/${a:-2}/${e}");
+        let defaults = tokenizer.default_values(true).clone().defaults();
+
+        let mut expected = HashMap::new();
+        expected.insert("a", "2");
+        expected.insert("b", "$c");
+        assert_eq!(defaults, expected);
+        assert!(Tokenizer::new("${a:-1}").defaults().is_empty());
+    }
+
+    #[test]
+    fn escape_sites() {
+        let template = r"a\n${b}\$c/é\n";
+        assert_eq!(
+            Tokenizer::new(template).escape_sites(),
+            vec![('n', 1), ('$', 7), ('n', 13)]
+        );
+        assert_eq!(
+            Tokenizer::new(template)
+                .decode_escapes(true)
+                .clone()
+                .escape_sites(),
+            vec![('\n', 1), ('$', 7), ('\n', 13)]
+        );
+        assert!(Tokenizer::new("${a}").escape_sites().is_empty());
+    }
+
+    #[test]
+    fn delimiter_positions() {
+        assert_eq!(
+            Tokenizer::new(r"é${a}}\${b $c\").delimiter_positions(),
+            vec![
+                (2, DelimKind::OpenBrace),
+                (5, DelimKind::CloseBrace),
+                (6, DelimKind::CloseBrace),
+                (7, DelimKind::Escape),
+                (12, DelimKind::Prefix),
+                (14, DelimKind::Escape),
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::new("@@{x}^^$^y");
+        tokenizer
+            .set_prefixes(&["$", "@@"])
+            .set_escape_sequence(b"^^");
+        assert_eq!(
+            tokenizer.delimiter_positions(),
+            vec![
+                (0, DelimKind::OpenBrace),
+                (4, DelimKind::CloseBrace),
+                (5, DelimKind::Escape),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_path() {
+        use std::ffi::OsStr;
+        use std::path::{Component, Path};
+
+        let expanded = Tokenizer::new("${root}/$dir/${name}.deb").expand_path(|buf, token| {
+            match token {
+                Token::Normal(text) => buf.push(text),
+                Token::Key("root") => buf.push(Path::new("/var/cache")),
+                Token::Key("dir") => buf.push("apt/archives"),
+                Token::Key("name") => buf.push("system76_1.0.0_amd64"),
+                other => return Err(format!("unsupported: {:?}", other)),
+            }
+            Ok(true)
+        });
+
+        let expected = [
+            "/",
+            "var",
+            "cache",
+            "apt",
+            "archives",
+            "system76_1.0.0_amd64.deb",
+        ];
+        let path = expanded.unwrap();
+        assert_eq!(
+            path.components()
+                .map(Component::as_os_str)
+                .collect::<Vec<_>>(),
+            expected.iter().map(OsStr::new).collect::<Vec<_>>()
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+            let expanded = Tokenizer::new("/tmp/${name}").expand_path(|buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push(text),
+                    Token::Key("name") => buf.push(OsStr::from_bytes(b"caf\xe9")),
+                    other => return Err(format!("unsupported: {:?}", other)),
+                }
+                Ok(true)
+            });
+
+            let path = expanded.unwrap();
+            assert_eq!(path.file_name(), Some(OsStr::from_bytes(b"caf\xe9")));
+            assert_eq!(path.into_os_string().into_vec(), b"/tmp/caf\xe9".to_vec());
+        }
+    }
+
+    #[test]
+    fn escaped_combining_mark() {
+        assert_eq!(
+            Tokenizer::new("\\e\u{301}\u{302}$a").collect::<Vec<_>>(),
+            vec![
+                Token::Escaped('e'),
+                Token::Normal("\u{301}\u{302}"),
+                Token::Key("a")
+            ]
+        );
+    }
+
+    #[cfg(feature = "unicode-segmentation")]
+    #[test]
+    fn escape_grapheme() {
+        let template = "x\\e\u{301}\u{302}\\$a\\";
+        let tokens = Tokenizer::new(template)
+            .escape_grapheme(true)
+            .clone()
+            .spanned()
+            .map(|spanned| (spanned.token, spanned.source))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (Token::Normal("x"), "x"),
+                (Token::EscapedStr("e\u{301}\u{302}"), "\\e\u{301}\u{302}"),
+                (Token::Escaped('$'), "\\$"),
+                (Token::Normal("a"), "a"),
+                (Token::Escaped('\\'), "\\"),
+            ]
+        );
+    }
+
+    #[test]
+    fn bounded() {
+        let expand = |name: &str, limit| {
+            Tokenizer::new("${name}_${version}.deb").expand_bounded(
+                |buf, token| {
+                    match token {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Key("name") => buf.push_str(name),
+                        Token::Key("version") => buf.push_str("1.0.0"),
+                        other => return Err(format!("unsupported: {:?}", other)),
+                    }
+                    Ok(true)
+                },
+                limit,
+            )
+        };
+
+        assert_eq!(expand("system76", 18), Ok("system76_1.0.0.deb".into()));
+        assert_eq!(
+            expand("system76", 17),
+            Err(BoundedError::OutputTooLarge(17))
+        );
+        assert_eq!(
+            expand(&"x".repeat(4096), 1024),
+            Err(BoundedError::OutputTooLarge(1024))
+        );
+        assert_eq!(
+            Tokenizer::new("$missing").expand_bounded(|_, _| Err("missing"), 1024),
+            Err(BoundedError::Map("missing"))
+        );
+    }
+
+    #[test]
+    fn escapes_used() {
+        let template = "\\n${a}\\t\\$b\\t\\n";
+        assert_eq!(Tokenizer::new(template).escapes_used(), vec!['n', 't', '$']);
+        assert_eq!(
+            Tokenizer::new(template)
+                .decode_escapes(true)
+                .clone()
+                .escapes_used(),
+            vec!['n', 't', '$']
+        );
+        assert_eq!(Tokenizer::new("$a").escapes_used(), vec![]);
+    }
+
+    #[test]
+    fn tilde_home() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .tilde_home(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("~/foo"),
+            vec![Token::Home(None), Token::Normal("/foo")]
+        );
+        assert_eq!(
+            tokens("~bob/bar"),
+            vec![Token::Home(Some("bob")), Token::Normal("/bar")]
+        );
+        assert_eq!(tokens("a~b"), vec![Token::Normal("a~b")]);
+        assert_eq!(tokens("~"), vec![Token::Home(None)]);
+        assert_eq!(
+            tokens("${root}/~pop.os/x/~"),
+            vec![
+                Token::Key("root"),
+                Token::Normal("/"),
+                Token::Home(Some("pop.os")),
+                Token::Normal("/x/"),
+                Token::Home(None),
+            ]
+        );
+        assert_eq!(
+            tokens("~$user/~ a/x~/~b:c"),
+            vec![
+                Token::Normal("~"),
+                Token::Key("user"),
+                Token::Normal("/~ a/x~/~b:c"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("~/foo").collect::<Vec<_>>(),
+            vec![Token::Normal("~/foo")]
+        );
+
+        let tokenizer = Tokenizer::new("~bob/a").tilde_home(true).clone();
+        assert_eq!(tokenizer.estimate_len(|_| 0), 6);
+        assert_eq!(
+            TokenOwned::from(Token::Home(Some("bob"))).as_token(),
+            Token::Home(Some("bob"))
+        );
+        assert_eq!(Token::Home(Some("bob")).as_source(b'\\'), "~bob");
+        assert_eq!(
+            Tokenizer::new("~/a").tilde_home(true).expand_env(),
+            Ok("~/a".into())
+        );
+    }
+
+    #[test]
+    fn strip_bom() {
+        let tokens = |template, strip| {
+            Tokenizer::new(template)
+                .strip_bom(strip)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("\u{FEFF}a=${a}", true),
+            vec![Token::Normal("a="), Token::Key("a")]
+        );
+        assert_eq!(
+            tokens("\u{FEFF}a=${a}", false),
+            vec![Token::Normal("\u{FEFF}a="), Token::Key("a")]
+        );
+        assert_eq!(
+            tokens("a=${a}", true),
+            vec![Token::Normal("a="), Token::Key("a")]
+        );
+        assert_eq!(tokens("\u{FEFF}$a", true), vec![Token::Key("a")]);
+        assert_eq!(tokens("\u{FEFF}", true), vec![]);
+        assert_eq!(tokens("a\u{FEFF}", true), vec![Token::Normal("a\u{FEFF}")]);
+
+        let mut tokenizer = Tokenizer::new("\u{FEFF}${a}");
+        let spans = tokenizer.strip_bom(true).clone().spanned();
+        assert_eq!(
+            spans.map(|token| token.span).collect::<Vec<_>>(),
+            vec![3..7]
+        );
+    }
+
+    #[test]
+    fn trailing_marks() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .trailing_marks(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tokens("$value!"), vec![Token::Key("value!")]);
+        assert_eq!(tokens("$present?"), vec![Token::Key("present?")]);
+        assert_eq!(tokens("$a?/b"), vec![Token::Key("a?"), Token::Normal("/b")]);
+        assert_eq!(tokens("$a?b"), vec![Token::Key("a"), Token::Normal("?b")]);
+        assert_eq!(tokens("$a??"), vec![Token::Key("a?"), Token::Normal("?")]);
+        assert_eq!(tokens("$?"), vec![Token::Key(""), Token::Normal("?")]);
+        assert_eq!(tokens("${a?}"), vec![Token::Key("a?")]);
+        assert_eq!(
+            Tokenizer::new("$a?").collect::<Vec<_>>(),
+            vec![Token::Key("a"), Token::Normal("?")]
+        );
+
+        let bareword = |template| {
+            Tokenizer::new(template)
+                .trailing_marks(true)
+                .clone()
+                .canonicalize_keys(KeyForm::Bareword)
+        };
+        assert_eq!(bareword("${a?}/${b}?"), "$a?/${b}?");
+        assert_eq!(bareword("${a?}b"), "${a?}b");
+    }
+
+    #[test]
+    fn dollar_requires_letter() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .dollar_requires_letter(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tokens("$5.00"), vec![Token::Normal("$5.00")]);
+        assert_eq!(tokens("$item"), vec![Token::Key("item")]);
+        assert_eq!(tokens("$_x"), vec![Token::Key("_x")]);
+        assert_eq!(tokens("${5}"), vec![Token::Key("5")]);
+        assert_eq!(
+            tokens("$5.00 for $item/$, or $-"),
+            vec![
+                Token::Normal("$5.00 for "),
+                Token::Key("item"),
+                Token::Normal("/$, or $-"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("$5").collect::<Vec<_>>(),
+            vec![Token::Key("5")]
+        );
+    }
+
+    #[test]
+    fn recover_literal() {
+        let tokens = |template, recover| {
+            Tokenizer::new(template)
+                .recover_literal(recover)
+                .quoted_keys(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(tokens("${abc", false), vec![Token::Key("abc")]);
+        assert_eq!(tokens("${abc", true), vec![Token::Normal("${abc")]);
+        assert_eq!(
+            tokens("$a ${b} cost: ${", true),
+            vec![
+                Token::Key("a "),
+                Token::Key("b"),
+                Token::Normal(" cost: "),
+                Token::Normal("${"),
+            ]
+        );
+        assert_eq!(tokens(r#"${"a b"#, false), vec![Token::Key("a b")]);
+        assert_eq!(tokens(r#"${"a b"#, true), vec![Token::Normal(r#"${"a b"#)]);
+
+        let template = "x${abc";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.recover_literal(true).strict(true);
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Token::Normal("x"),
+                Token::Error(TokenError::new(template, 1, "unterminated key".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn preserve_unknown_escapes() {
+        let tokens = |template, preserve| {
+            Tokenizer::new(template)
+                .decode_escapes(true)
+                .preserve_unknown_escapes(preserve)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens(r"a\zb", true),
+            vec![Token::Normal("a"), Token::Normal(r"\z"), Token::Normal("b")]
+        );
+        assert_eq!(
+            tokens(r"a\zb", false),
+            vec![Token::Normal("a"), Token::Escaped('z'), Token::Normal("b")]
+        );
+        assert_eq!(
+            tokens(r"\z\n\u{41}\u\é\{", true),
+            vec![
+                Token::Normal(r"\z"),
+                Token::Escaped('\n'),
+                Token::Escaped('A'),
+                Token::Normal(r"\u"),
+                Token::Normal(r"\é"),
+                Token::Escaped('{'),
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::new(r"a\zb");
+        tokenizer
+            .decode_escapes(true)
+            .preserve_unknown_escapes(true);
+        assert_eq!(
+            tokenizer
+                .spanned()
+                .map(|token| token.span)
+                .collect::<Vec<_>>(),
+            vec![0..1, 1..3, 3..4]
+        );
+    }
+
+    #[test]
+    fn escape_sequence() {
+        let template = "^^$a ^${b}^^^^";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.set_escape_sequence(b"^^");
+        assert_eq!(
+            tokenizer.clone().collect::<Vec<_>>(),
+            vec![
+                Token::Escaped('$'),
+                Token::Normal("a ^"),
+                Token::Key("b"),
+                Token::Escaped('^'),
+                Token::Normal("^"),
+            ]
+        );
+        assert_eq!(
+            tokenizer
+                .clone()
+                .spanned()
+                .map(|token| token.source)
+                .collect::<Vec<_>>(),
+            vec!["^^$", "a ^", "${b}", "^^^", "^"]
+        );
+        assert_eq!(tokenizer.escape_len(), 2);
+        assert_eq!(tokenizer.set_escape(b'^').escape_len(), 1);
+
+        let mut single = Tokenizer::new(template);
+        single.set_escape_sequence(b"^");
+        assert_eq!(single, *Tokenizer::new(template).set_escape(b'^'));
+
+        // Sequences which end partway through a character are ignored.
+        for &(template, sequence) in &[("a§$b", &b"a\xc2"[..]), ("§$b", b"\xa7$"), ("$b", b"")] {
+            let mut tokenizer = Tokenizer::new(template);
+            tokenizer.set_escape_sequence(sequence);
+            assert_eq!(tokenizer, Tokenizer::new(template));
+            assert_eq!(tokenizer.last(), Some(Token::Key("b")));
+        }
+    }
+
+    #[test]
+    fn escape_char() {
+        let template = "§$a ¢$b/${c§}d}/$e§.f/§§§§ §";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.set_escape_char('§');
+        assert_eq!(
+            tokenizer.clone().collect::<Vec<_>>(),
+            vec![
+                Token::Escaped('$'),
+                Token::Normal("a ¢"),
+                Token::Key("b"),
+                Token::Normal("/"),
+                Token::Key("c§}d"),
+                Token::Normal("/"),
+                Token::KeyDecoded {
+                    key: "e.f".into(),
+                    raw: "e§.f"
+                },
+                Token::Normal("/"),
+                Token::Escaped('§'),
+                Token::Escaped('§'),
+                Token::Normal(" "),
+                Token::Escaped('§'),
+            ]
+        );
+        assert_eq!(
+            tokenizer
+                .clone()
+                .spanned()
+                .map(|token| token.source)
+                .take(3)
+                .collect::<Vec<_>>(),
+            vec!["§$", "a ¢", "$b"]
+        );
+        assert_eq!(tokenizer.escape_len(), 2);
+        assert_eq!(format!("{:?}", tokenizer).matches("escape: '§'").count(), 1);
+
+        tokenizer.collapse_escapes(true).decode_escapes(true);
+        assert_eq!(
+            tokenizer.rebind("§§§§§n").collect::<Vec<_>>(),
+            vec![Token::Escaped('§'), Token::Escaped('\n')]
+        );
+
+        assert_eq!(
+            Tokenizer::new("a^^")
+                .set_escape_sequence(b"^^")
+                .collect::<Vec<_>>(),
+            vec![Token::Normal("a"), Token::EscapedStr("^^")]
+        );
+
+        let mut sequence = Tokenizer::new(template);
+        sequence.set_escape_sequence("§".as_bytes());
+        assert_eq!(sequence, *Tokenizer::new(template).set_escape_char('§'));
+        assert_eq!(
+            *Tokenizer::new(template).set_escape_char('#'),
+            *Tokenizer::new(template).set_escape(b'#')
+        );
+    }
+
+    #[test]
+    fn peek_len() {
+        let cases = [
+            ("foo bar$x", 7),
+            ("$name/", 5),
+            ("${name}/", 7),
+            ("${arch:-amd64}", 14),
+            ("\\$", 2),
+            ("\\é", 3),
+            ("\\u{e9}x", 6),
+            ("$a\\b/", 4),
+        ];
+
+        for &(template, expected) in &cases {
+            let mut tokenizer = Tokenizer::new(template);
+            tokenizer.default_values(true).decode_escapes(true);
+            assert_eq!(tokenizer.peek_len(), Some(expected), "{:?}", template);
+            tokenizer.next();
+            assert_eq!(tokenizer.read(), expected, "{:?}", template);
+        }
+
+        let mut tokenizer = Tokenizer::new("a\\$");
+        assert_eq!(tokenizer.peek_len(), Some(1));
+        tokenizer.next();
+        assert_eq!(tokenizer.peek_len(), Some(2));
+        tokenizer.next();
+        assert_eq!(tokenizer.peek_len(), None);
+    }
+
+    #[test]
+    fn escape_pending() {
+        let mut tokenizer = Tokenizer::new("${a}\\n\\\\");
+        assert!(!tokenizer.escape_pending());
+        assert_eq!(tokenizer.next(), Some(Token::Key("a")));
+        assert!(!tokenizer.escape_pending());
+
+        // An escape directly after a key is read alongside its character.
+        assert_eq!(tokenizer.next(), Some(Token::Escaped('n')));
+        assert!(!tokenizer.escape_pending());
+
+        let mut tokenizer = Tokenizer::new("text\\");
+        assert_eq!(tokenizer.next(), Some(Token::Normal("text")));
+        assert!(tokenizer.escape_pending());
+        assert_eq!(tokenizer.read(), tokenizer.len());
+        assert_eq!(tokenizer.next(), Some(Token::Escaped('\\')));
+        assert!(!tokenizer.escape_pending());
+        assert_eq!(tokenizer.next(), None);
+    }
+
+    #[test]
+    fn prefix_whitespace() {
+        let tokens = |template| Tokenizer::new(template).collect::<Vec<_>>();
+
+        assert_eq!(tokens("$ x"), vec![Token::Normal("$ x")]);
+        assert_eq!(tokens("$\tx"), vec![Token::Normal("$\tx")]);
+        assert_eq!(tokens("$\nx"), vec![Token::Normal("$\nx")]);
+        assert_eq!(
+            tokens("a $\u{a0}$b"),
+            vec![Token::Normal("a $\u{a0}"), Token::Key("b")]
+        );
+        assert_eq!(
+            tokens("cost: 5 $ ${x}"),
+            vec![Token::Normal("cost: 5 $ "), Token::Key("x")]
+        );
+    }
+
+    #[test]
+    fn fallback() {
+        let mut fallbacks = Vec::new();
+        let expanded = Tokenizer::new("$name/${arch:-amd64}/${version}_$name")
+            .default_values(true)
+            .expand_with_fallback(
+                |key| match key {
+                    "name" => Some("system76".into()),
+                    "arch" => Some(String::new()),
+                    _ => None,
+                },
+                |key| {
+                    fallbacks.push(key.to_owned());
+                    match key {
+                        "version" => Ok("1.0.0".into()),
+                        _ => Err(format!("unknown key: {}", key)),
+                    }
+                },
+            );
+
+        assert_eq!(expanded, Ok("system76/amd64/1.0.0_system76".into()));
+        assert_eq!(fallbacks, vec!["version"]);
+
+        let expanded = Tokenizer::new("$name/$missing").expand_with_fallback(
+            |key| match key {
+                "name" => Some("system76".into()),
+                _ => None,
+            },
+            |key| Err(format!("unknown key: {}", key)),
+        );
+        assert_eq!(
+            expanded,
+            Err(ResolveError::Map("unknown key: missing".into()))
+        );
+
+        let template = "${name}/@include(arch.tmpl)";
+        let expanded = Tokenizer::new(template)
+            .set_include_directive(Some("@include"))
+            .expand_with_fallback(
+                |_| Some("system76".into()),
+                |_| Ok::<_, String>(String::new()),
+            );
+        assert_eq!(
+            expanded,
+            Err(ResolveError::Invalid(TokenError::new(
+                template,
+                8,
+                "unsupported include: arch.tmpl".into()
+            )))
+        );
+    }
+
+    #[test]
+    fn expand_located() {
+        let expand = |template| {
+            Tokenizer::new(template).expand_located(|buf, token| {
+                match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    Token::Key("name") => buf.push_str("system76"),
+                    Token::Key(key) => return Err(format!("no value for {}", key)),
+                    Token::Escaped('$') => buf.push('$'),
+                    Token::Escaped(character) => {
+                        return Err(format!("unknown escape {}", character))
+                    }
+                    other => panic!("unexpected token: {:?}", other),
+                }
+                Ok(true)
+            })
+        };
+
+        assert_eq!(expand("${name}-\\$"), Ok("system76-$".into()));
+        assert_eq!(
+            expand("$name/\\$/$arch.deb"),
+            Err(ExpandFailure {
+                at: 9,
+                key: Some("arch".into()),
+                source: "no value for arch".into(),
+            })
+        );
+        assert_eq!(
+            expand("${name}\\q"),
+            Err(ExpandFailure {
+                at: 7,
+                key: None,
+                source: "unknown escape q".into(),
+            })
+        );
+        assert_eq!(
+            expand("x$arch").unwrap_err().to_string(),
+            "no value for arch (key arch at byte 1)"
+        );
+    }
+
+    #[test]
+    fn escaped_bareword() {
+        let template = "$foo\\.bar/$a\\\\b\\$$c";
+        assert_eq!(
+            Tokenizer::new(template).collect::<Vec<_>>(),
+            vec![
+                Token::KeyDecoded {
+                    key: "foo.bar".into(),
+                    raw: "foo\\.bar"
+                },
+                Token::Normal("/"),
+                Token::KeyDecoded {
+                    key: "a\\b$".into(),
+                    raw: "a\\\\b\\$"
+                },
+                Token::Key("c"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new(template).key_spans().collect::<Vec<_>>(),
+            vec![("foo\\.bar", 0..9), ("a\\\\b\\$", 10..17), ("c", 17..19)]
+        );
+
+        let expanded = Tokenizer::new("$host\\.name.conf").expand(|buf, token| {
+            match token.key() {
+                Some("host.name") => buf.push_str("pop-os"),
+                Some(key) => return Err(format!("unknown key: {}", key)),
+                None => match token {
+                    Token::Normal(text) => buf.push_str(text),
+                    other => panic!("unexpected token: {:?}", other),
+                },
+            }
+            Ok(true)
+        });
+        assert_eq!(expanded, Ok("pop-os.conf".into()));
+    }
+
+    #[test]
+    fn catch_all() {
+        let describe = |token: &Token| match *token {
+            Token::Normal(_) => "normal",
+            Token::Key(_) => "key",
+            _ => "other",
+        };
+
+        let tokens = Tokenizer::new("a${b}\\c").collect::<Vec<_>>();
+        assert_eq!(
+            tokens.iter().map(describe).collect::<Vec<_>>(),
+            vec!["normal", "key", "other"]
+        );
+    }
+
+    #[test]
+    fn whitespace_keys_literal() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .whitespace_keys_literal(true)
+                .trim_keys(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("a${ }b"),
+            vec![
+                Token::Normal("a"),
+                Token::Normal("${ }"),
+                Token::Normal("b")
+            ]
+        );
+        assert_eq!(tokens("${  }"), vec![Token::Normal("${  }")]);
+        assert_eq!(tokens("${\t\n}"), vec![Token::Normal("${\t\n}")]);
+        assert_eq!(tokens("${ x }"), vec![Token::Key("x")]);
+        assert_eq!(tokens("${}"), vec![Token::Key("")]);
+        assert_eq!(
+            Tokenizer::new("${ }").trim_keys(true).collect::<Vec<_>>(),
+            vec![Token::Key("")]
+        );
+    }
+
+    #[test]
+    fn quoted_keys() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .quoted_keys(true)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens(r#"${"my key"}/${"a: {b}"}"#),
+            vec![
+                Token::Key("my key"),
+                Token::Normal("/"),
+                Token::Key("a: {b}"),
+            ]
+        );
+        assert_eq!(
+            tokens(r#"${"say \"hi\""}!"#),
+            vec![
+                Token::KeyDecoded {
+                    key: r#"say "hi""#.into(),
+                    raw: r#"say \"hi\""#,
+                },
+                Token::Normal("!"),
+            ]
+        );
+        assert_eq!(tokens(r#"${"a} $b"#), vec![Token::Key("a} $b")]);
+        assert_eq!(tokens(r#"${"a}b""#), vec![Token::Key("a}b")]);
+        assert_eq!(
+            tokens(r#"${"a"b}c"#),
+            vec![Token::Key(r#""a"b"#), Token::Normal("c")]
+        );
+
+        let template = r#"${"a}b$c"#;
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer.quoted_keys(true).strict(true);
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Token::Error(TokenError::new(
+                    template,
+                    0,
+                    "unterminated quoted key".into()
+                )),
+                Token::Normal("b"),
+                Token::Key("c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn as_source() {
+        let source = |template, escape| {
+            let mut tokenizer = Tokenizer::new(template);
+            tokenizer
+                .set_escape(escape)
+                .required_values(true)
+                .substitutions(true);
+            tokenizer
+                .map(|token| token.as_source(escape).into_owned())
+                .collect::<String>()
+        };
+
+        for template in [
+            r"a\$b${c}\\",
+            r"${path//\//\\}",
+            r"${name:?set {name\}}",
+            "${a:-b}${}",
+        ] {
+            assert_eq!(source(template, b'\\'), template);
+        }
+        assert_eq!(source("%$a%%${b}", b'%'), "%$a%%${b}");
+        assert_eq!(source("$a.$b", b'\\'), "${a}.${b}");
+        assert_eq!(source("${a", b'\\'), "${a}");
+        assert_eq!(
+            Token::Include("a.tmpl".into()).as_source(b'\\'),
+            Cow::Borrowed("")
+        );
+    }
+
+    #[test]
+    fn with_config_from() {
+        let mut profile = Tokenizer::new("${ignored}");
+        profile
+            .set_escape(b'#')
+            .set_prefixes(&["@"])
+            .default_values(true);
+
+        let tokenizer = Tokenizer::new("#@@{a:-b}$c").with_config_from(&profile);
+        assert_eq!(tokenizer, profile.rebind("#@@{a:-b}$c"));
+        assert_eq!(
+            tokenizer.collect::<Vec<_>>(),
+            vec![
+                Token::Escaped('@'),
+                Token::KeyDefault {
+                    key: "a",
+                    default: "b"
+                },
+                Token::Normal("$c"),
+            ]
+        );
+
+        let mut tokenizer = Tokenizer::new("a$b@c");
+        assert_eq!(tokenizer.next(), Some(Token::Normal("a")));
+        assert_eq!(
+            tokenizer.with_config_from(&profile).collect::<Vec<_>>(),
+            vec![Token::Normal("$b"), Token::Key("c")]
+        );
+    }
+
+    #[test]
+    fn presets() {
+        const TEMPLATE: &str = "%A%^%{{ b }}{0}$c%20/${d}";
+        type Constructor = fn(&'static str) -> Tokenizer<'static>;
+        const PRESETS: [(Tokenizer<'static>, Constructor); 5] = [
+            (Tokenizer::SHELL, Tokenizer::new),
+            (Tokenizer::WINDOWS, Tokenizer::windows),
+            (Tokenizer::MUSTACHE, Tokenizer::mustache),
+            (Tokenizer::URL, Tokenizer::url),
+            (Tokenizer::POSITIONAL, Tokenizer::positional),
+        ];
+
+        for (preset, constructor) in PRESETS.iter() {
+            let tokenizer = Tokenizer::new(TEMPLATE).with_config_from(preset);
+            assert_eq!(tokenizer, constructor(TEMPLATE));
+            assert_eq!(
+                tokenizer.collect::<Vec<_>>(),
+                constructor(TEMPLATE).collect::<Vec<_>>()
+            );
+        }
+
+        const ANGLED: Tokenizer<'static> = Tokenizer {
+            delimiters: Some(Delimiters {
+                open: "<",
+                close: Delimiters::MUSTACHE.close,
+            }),
+            ..Tokenizer::MUSTACHE
+        };
+        assert_eq!(
+            Tokenizer::new("< a }}{{b}}")
+                .with_config_from(&ANGLED)
+                .collect::<Vec<_>>(),
+            vec![Token::Key("a"), Token::Normal("{{b}}")]
+        );
+    }
+
+    #[test]
+    fn url() {
+        let tokens = |template| Tokenizer::url(template).collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens("$host:$port/$path"),
+            vec![
+                Token::Key("host"),
+                Token::Normal(":"),
+                Token::Key("port"),
+                Token::Normal("/"),
+                Token::Key("path"),
+            ]
+        );
+        assert_eq!(
+            tokens("?$q=value&a=$a%20b+c"),
+            vec![
+                Token::Normal("?"),
+                Token::Key("q"),
+                Token::Normal("=value&a="),
+                Token::Key("a%20b+c"),
+            ]
+        );
+        assert_eq!(
+            tokens("$user@$host:8080"),
+            vec![
+                Token::Key("user"),
+                Token::Normal("@"),
+                Token::Key("host"),
+                Token::Normal(":8080"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("$a%20b+c").collect::<Vec<_>>(),
+            vec![Token::Key("a"), Token::Normal("%20b+c")]
+        );
+    }
+
+    #[test]
+    fn brace_match() {
+        let tokens = |template, brace_match| {
+            Tokenizer::new(template)
+                .default_values(true)
+                .balanced_braces(true)
+                .set_brace_match(brace_match)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("${a:-b}c}", BraceMatch::Lazy),
+            vec![
+                Token::KeyDefault {
+                    key: "a",
+                    default: "b"
+                },
+                Token::Normal("c}"),
+            ]
+        );
+        assert_eq!(
+            tokens("${a:-b}c}", BraceMatch::Greedy),
+            vec![Token::KeyDefault {
+                key: "a",
+                default: "b}c"
+            }]
+        );
+        assert_eq!(
+            tokens("${a:-${b}}${c}", BraceMatch::Lazy),
+            vec![
+                Token::KeyDefault {
+                    key: "a",
+                    default: "${b}"
+                },
+                Token::Key("c"),
+            ]
+        );
+        assert_eq!(
+            tokens("${a:-${b}}${c}", BraceMatch::Greedy),
+            tokens("${a:-${b}}${c}", BraceMatch::Lazy)
+        );
+        assert_eq!(
+            tokens("${a:-${b}}c}", BraceMatch::Greedy),
+            vec![Token::KeyDefault {
+                key: "a",
+                default: "${b}}c"
+            }]
+        );
+        assert_eq!(
+            tokens(r"${a:-\{}b}", BraceMatch::Lazy),
+            vec![
+                Token::KeyDefault {
+                    key: "a",
+                    default: r"\{"
+                },
+                Token::Normal("b}"),
+            ]
+        );
+        assert_eq!(
+            tokens("${a:-{b}", BraceMatch::Lazy),
+            vec![Token::KeyDefault {
+                key: "a",
+                default: "{b}"
+            }]
+        );
 
-    fn read(&self) -> usize {
-        self.read
+        // Without balanced braces, the first closing brace ends the key.
+        assert_eq!(
+            Tokenizer::new("${a:-${b}}")
+                .default_values(true)
+                .set_brace_match(BraceMatch::Greedy)
+                .collect::<Vec<_>>(),
+            vec![
+                Token::KeyDefault {
+                    key: "a",
+                    default: "${b"
+                },
+                Token::Normal("}"),
+            ]
+        );
     }
-}
 
-impl<'a> Iterator for Tokenizer<'a> {
-    type Item = Token<'a>;
+    #[test]
+    fn required_values() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .required_values(true)
+                .trim_keys(true)
+                .collect::<Vec<_>>()
+        };
 
-    fn next(&mut self) -> Option<Token<'a>> {
-        if self.flags & ESCAPED != 0 {
-            self.flags ^= ESCAPED;
-            return Some(self.escaped_character());
-        }
+        assert_eq!(
+            tokens(r"${a :? a is \} unset }b"),
+            vec![
+                Token::KeyRequired {
+                    key: "a",
+                    message: Cow::Owned("a is } unset".into())
+                },
+                Token::Normal("b"),
+            ]
+        );
+        assert_eq!(
+            tokens("${a:?}${b?}"),
+            vec![
+                Token::KeyRequired {
+                    key: "a",
+                    message: "".into()
+                },
+                Token::Key("b?"),
+            ]
+        );
 
-        if self.read >= self.data.len() {
-            return None;
-        }
+        let expanded = Tokenizer::new("${a:?a is unset}")
+            .required_values(true)
+            .expand_with_fallback(|_| None, |_| Ok::<_, String>(String::new()));
+        assert_eq!(
+            expanded,
+            Err(ResolveError::Required {
+                key: "a".into(),
+                message: "a is unset".into(),
+            })
+        );
+    }
 
-        let start = self.read;
-        let bytes = self.data.as_bytes();
-        while self.read < self.data.len() {
-            match bytes[self.read] {
-                byte if byte == self.escape => {
-                    return Some(self.check_return(
-                        start,
-                        |tokenizer| {
-                            tokenizer.read += 1;
-                            tokenizer.flags |= ESCAPED;
-                        },
-                        |tokenizer| {
-                            tokenizer.read += 1;
-                            tokenizer.escaped_character()
-                        },
-                    ));
-                }
-                b'$' if bytes.get(self.read + 1) == Some(&b'{') => {
-                    return Some(self.check_return(
-                        start,
-                        |_| {},
-                        |tokenizer| {
-                            tokenizer.read += 2;
-                            let rules = LexerRules::new(b"}", tokenizer.escape);
-                            let lexed =
-                                Lexer::new(&tokenizer.data[tokenizer.read..], rules).search();
-                            tokenizer.read += lexed.len() + 1;
-                            Token::Key(lexed)
-                        },
-                    ));
-                }
-                b'$' => {
-                    return Some(self.check_return(
-                        start,
-                        |_| {},
-                        |tokenizer| {
-                            tokenizer.read += 1;
-                            const PATTERN: &[u8] = br#"~!@#$%^&*()+-=[]\{}|;':",./<>?"#;
-                            let rules = LexerRules::new(PATTERN, tokenizer.escape);
-                            let lexed =
-                                Lexer::new(&tokenizer.data[tokenizer.read..], rules).search();
-                            tokenizer.read += lexed.len();
-                            Token::Key(lexed)
-                        },
-                    ));
-                }
-                _ => self.read += 1,
-            }
-        }
+    #[test]
+    fn substitutions() {
+        let expand = |template: &str, value: &'static str| {
+            Tokenizer::new(template)
+                .substitutions(true)
+                .expand_substituted(|buf, token| {
+                    match token {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Key("v") => buf.push_str(value),
+                        other => return Err(format!("unsupported: {:?}", other)),
+                    }
+                    Ok(true)
+                })
+        };
 
-        self.read = self.data.len();
-        let remaining = &self.data[start..];
-        if remaining.is_empty() {
-            None
-        } else {
-            Some(Token::Normal(remaining))
-        }
-    }
-}
+        assert_eq!(expand("${v/a/o}!", "banana"), Ok("bonana!".into()));
+        assert_eq!(expand("${v//a/o}!", "banana"), Ok("bonono!".into()));
+        assert_eq!(expand("${v//x/o}!", "banana"), Ok("banana!".into()));
+        assert_eq!(expand("${v//an}", "banana"), Ok("ba".into()));
+        assert_eq!(expand(r"${v//\//\\}", "a/b/c"), Ok(r"a\b\c".into()));
+        assert_eq!(expand("${v//}", "banana"), Ok("banana".into()));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        assert_eq!(
+            Tokenizer::new("${v/a/b/c}").substitutions(true).next(),
+            Some(Token::KeyReplace {
+                key: "v",
+                search: Cow::Borrowed("a"),
+                replace: Cow::Borrowed("b/c"),
+                global: false,
+            })
+        );
+        assert_eq!(
+            Tokenizer::new("${v/a/b}").collect::<Vec<_>>(),
+            vec![Token::Key("v/a/b")]
+        );
+    }
 
     #[test]
-    fn tokens() {
-        let url = "https://${domain}/$repo/$name/${name}_${version}_$arch.deb";
+    fn type_hints() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .type_hints(true)
+                .default_values(true)
+                .trim_keys(true)
+                .collect::<Vec<_>>()
+        };
+
         assert_eq!(
-            Tokenizer::new(url).collect::<Vec<_>>(),
+            tokens("${port:int}"),
+            vec![Token::KeyTyped {
+                key: "port",
+                ty: "int"
+            }]
+        );
+        assert_eq!(
+            tokens("${ name : str }"),
+            vec![Token::KeyTyped {
+                key: "name",
+                ty: "str"
+            }]
+        );
+        assert_eq!(
+            tokens("${x:-d}"),
+            vec![Token::KeyDefault {
+                key: "x",
+                default: "d"
+            }]
+        );
+        assert_eq!(
+            tokens("${a:b:u8}${c:+d}${e:1x}${f:}"),
             vec![
-                Token::Normal("https://"),
-                Token::Key("domain"),
-                Token::Normal("/"),
-                Token::Key("repo"),
-                Token::Normal("/"),
-                Token::Key("name"),
-                Token::Normal("/"),
-                Token::Key("name"),
-                Token::Normal("_"),
-                Token::Key("version"),
-                Token::Normal("_"),
-                Token::Key("arch"),
-                Token::Normal(".deb"),
+                Token::KeyTyped {
+                    key: "a:b",
+                    ty: "u8"
+                },
+                Token::Key("c:+d"),
+                Token::Key("e:1x"),
+                Token::Key("f:"),
+            ]
+        );
+        assert_eq!(
+            Tokenizer::new("${port:int}").collect::<Vec<_>>(),
+            vec![Token::Key("port:int")]
+        );
+
+        let mut types = Vec::new();
+        let expanded = Tokenizer::new("$name:${port:int}/${path:-/}")
+            .type_hints(true)
+            .default_values(true)
+            .expand_typed(|buf, key, ty| {
+                types.push((key.to_owned(), ty.map(String::from)));
+                match key {
+                    "name" => buf.push_str("pop"),
+                    "port" => buf.push_str("80"),
+                    _ => (),
+                }
+                Ok::<(), String>(())
+            });
+
+        assert_eq!(expanded, Ok("pop:80//".into()));
+        assert_eq!(
+            types,
+            vec![
+                ("name".into(), None),
+                ("port".into(), Some("int".into())),
+                ("path".into(), None)
             ]
         );
+
+        let expanded = Tokenizer::new("${port:?no port}")
+            .required_values(true)
+            .expand_typed(|_, _, _| Ok::<(), String>(()));
+        assert_eq!(
+            expanded,
+            Err(ResolveError::Required {
+                key: "port".into(),
+                message: "no port".into(),
+            })
+        );
     }
 
     #[test]
-    fn expander() {
-        let url = "https://app.domain.org/${name}/${name}_${version}.deb";
+    fn tail() {
+        let template = "[$level] ${message} for $user, ${message}\\n";
+        let map = |buf: &mut String, token: Token| {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Escaped('n') => buf.push('\n'),
+                Token::Key("level") => buf.push_str("warn"),
+                Token::Key("message") => buf.push_str("disk almost full: 97%"),
+                Token::Key("user") => buf.push_str("pöp 📦"),
+                other => return Err(format!("unsupported: {:?}", other)),
+            }
+            Ok(true)
+        };
+
+        let full = Tokenizer::new(template).expand(map).unwrap();
+        for tail in (0..full.len() + 4).chain(Some(usize::MAX)) {
+            let expected = (full.len().saturating_sub(tail)..=full.len())
+                .find(|&start| full.is_char_boundary(start))
+                .map_or("", |start| &full[start..]);
+            assert_eq!(
+                Tokenizer::new(template).expand_tail(map, tail).as_deref(),
+                Ok(expected),
+                "{}",
+                tail
+            );
+        }
+    }
+
+    #[test]
+    fn limited() {
+        let expand = |template, max_keys| {
+            Tokenizer::new(template).expand_limited(
+                |buf, token| {
+                    match token {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Escaped(character) => buf.push(character),
+                        Token::Key(key) => buf.push_str(&key.to_uppercase()),
+                        other => return Err(format!("unsupported: {:?}", other)),
+                    }
+                    Ok(true)
+                },
+                max_keys,
+            )
+        };
+
+        assert_eq!(expand("$a-${b}-\\$c", 2), Ok("A-B-$c".into()));
+        assert_eq!(expand("no keys", 0), Ok("no keys".into()));
         assert_eq!(
-            Tokenizer::new(url).expand(|buf, key| {
-                match key {
+            expand("$a-${b}-$c-$d", 2),
+            Err(BoundedError::TooManyKeys {
+                limit: 2,
+                key: "c".into()
+            })
+        );
+        assert_eq!(
+            expand("$a", 0).unwrap_err().to_string(),
+            "expansion exceeded 0 keys at a"
+        );
+    }
+
+    #[test]
+    fn structured() {
+        let parts = Tokenizer::new("https://${domain}/\\$free/$name.deb").expand_structured(
+            |buf, token| {
+                match token {
                     Token::Normal(text) => buf.push_str(text),
+                    Token::Escaped(character) => buf.push(character),
+                    Token::Key("domain") => buf.push_str("apt.pop-os.org"),
                     Token::Key("name") => buf.push_str("system76"),
-                    Token::Key("version") => buf.push_str("1.0.0"),
-                    Token::Key(other) => return Err(format!("unsupported key: {}", other)),
-                    Token::Escaped(_) => panic!("didn't expect an escaped character"),
+                    other => return Err(format!("unsupported: {:?}", other)),
                 }
-
                 Ok(true)
-            }),
-            Ok("https://app.domain.org/system76/system76_1.0.0.deb".into())
+            },
         );
 
         assert_eq!(
-            Tokenizer::new("https://app.domain.org/package_version.deb").expand(
-                |buf, key| -> Result<bool, String> {
-                    match key {
+            parts,
+            Ok(vec![
+                OutputPart::Text("https://".into()),
+                OutputPart::Substituted {
+                    key: "domain".into(),
+                    value: "apt.pop-os.org".into()
+                },
+                OutputPart::Text("/$free/".into()),
+                OutputPart::Substituted {
+                    key: "name".into(),
+                    value: "system76".into()
+                },
+                OutputPart::Text(".deb".into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn cached() {
+        let mut calls = HashMap::new();
+        let expanded = Tokenizer::new("$a-${a}-$b-\\$a-${a}").expand_cached(|buf, token| {
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Escaped(character) => buf.push(character),
+                Token::Key(key) => {
+                    *calls.entry(key.to_owned()).or_insert(0) += 1;
+                    buf.push_str(&key.to_uppercase());
+                }
+                other => panic!("unexpected token: {:?}", other),
+            }
+            Ok::<_, ()>(true)
+        });
+
+        assert_eq!(expanded, Ok("A-A-B-$a-A".into()));
+        assert_eq!(calls.get("a"), Some(&1));
+        assert_eq!(calls.get("b"), Some(&1));
+
+        let expand = |template| {
+            Tokenizer::new(template)
+                .default_values(true)
+                .substitutions(true)
+                .expand_cached(|buf, token| {
+                    match token {
                         Token::Normal(text) => buf.push_str(text),
-                        Token::Key(key) if key == "foo" => {
-                            buf.push_str("bar");
+                        Token::Key("a") => buf.push('A'),
+                        Token::KeyDefault { default, .. } => buf.push_str(default),
+                        Token::Key("v") | Token::KeyReplace { .. } => {
+                            buf.push_str(&token.substitute("banana"))
                         }
-                        _ => (),
+                        other => panic!("unexpected token: {:?}", other),
                     }
+                    Ok::<_, ()>(true)
+                })
+        };
+        assert_eq!(expand("${a:-x}/$a/${a}"), Ok("x/A/A".into()));
+        assert_eq!(expand("$a/${a:-x}"), Ok("A/x".into()));
+        assert_eq!(expand("${v/a/o}-$v"), Ok("bonana-banana".into()));
+    }
 
+    #[test]
+    fn expand_then() {
+        let expand = |path: &'static str| {
+            Tokenizer::new("https://${domain}/${path}").expand_then(
+                |buf, token| {
+                    match token {
+                        Token::Normal(text) => buf.push_str(text),
+                        Token::Key("domain") => buf.push_str("apt.pop-os.org"),
+                        Token::Key("path") => buf.push_str(path),
+                        other => return Err(format!("unsupported: {:?}", other)),
+                    }
                     Ok(true)
-                }
-            ),
-            Ok("https://app.domain.org/package_version.deb".into())
+                },
+                |output| {
+                    if output["https://".len()..].contains("//") {
+                        Err(format!("double slash in {}", output))
+                    } else {
+                        Ok(())
+                    }
+                },
+            )
+        };
+
+        assert_eq!(expand("free"), Ok("https://apt.pop-os.org/free".into()));
+        assert_eq!(
+            expand("/free"),
+            Err("double slash in https://apt.pop-os.org//free".into())
         );
     }
 
     #[test]
-    fn escaper() {
-        let pattern = "foo\\${bar}";
+    fn key_forms() {
+        let keys = |template: &str| {
+            Tokenizer::new(template)
+                .filter_map(|token| token.key().map(String::from))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("$name/${name}"), vec!["name", "name"]);
+
+        let braced = |template| Tokenizer::new(template).canonicalize_keys(KeyForm::Braced);
+        assert_eq!(braced("$a/${b}/$c"), "${a}/${b}/${c}");
+        assert_eq!(braced("\\$a $b\\.c"), "\\$a ${b.c}");
+        assert_eq!(braced("${a"), "${a");
+
+        let bareword = |template| Tokenizer::new(template).canonicalize_keys(KeyForm::Bareword);
+        assert_eq!(bareword("${a}/$b/${c}"), "$a/$b/$c");
+        assert_eq!(bareword("${a}_${b}"), "${a}_$b");
+        assert_eq!(bareword("${a.b}${c d}${}${e\\f}"), "${a.b}${c d}${}${e\\f}");
+        assert_eq!(bareword("${a}\\$"), "${a}\\$");
+
+        // Rewriting in either direction preserves the keys of the template.
+        for template in &["$a/${b}_$c.d", "${x}${y}", "$a\\.b ${ c }"] {
+            for &form in &[KeyForm::Braced, KeyForm::Bareword] {
+                let rewritten = Tokenizer::new(template).canonicalize_keys(form);
+                assert_eq!(keys(&rewritten), keys(template), "{}", rewritten);
+            }
+        }
+    }
+
+    #[test]
+    fn lossy() {
+        let cases: &[(&str, &[Token])] = &[
+            ("", &[]),
+            ("$", &[Token::Normal("$")]),
+            ("a$", &[Token::Normal("a$")]),
+            ("$$", &[Token::Normal("$$")]),
+            ("$.", &[Token::Normal("$.")]),
+            ("$}", &[Token::Normal("$}")]),
+            ("\\", &[Token::Escaped('\\')]),
+            ("a\\", &[Token::Normal("a"), Token::Escaped('\\')]),
+            ("\\é", &[Token::Escaped('é')]),
+            ("${}", &[Token::Normal("${}")]),
+            ("${", &[Token::Normal("${")]),
+            ("${a", &[Token::Normal("${a")]),
+            ("é${é", &[Token::Normal("é${é")]),
+            ("}{}", &[Token::Normal("}{}")]),
+            ("${a}}", &[Token::Key("a"), Token::Normal("}")]),
+            ("${${a}}", &[Token::Key("${a"), Token::Normal("}")]),
+            ("$ a", &[Token::Normal("$ a")]),
+            ("x$\\", &[Token::Normal("x"), Token::Key("\\")]),
+        ];
+
+        for &(template, expected) in cases {
+            assert_eq!(tokenize_lossy(template), expected, "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn expand_all() {
+        let mut calls = 0;
+        let mut map = |buf: &mut String, token: Token| {
+            calls += 1;
+            match token {
+                Token::Normal(text) => buf.push_str(text),
+                Token::Escaped(character) => buf.push(character),
+                Token::Key("name") => buf.push_str("system76"),
+                Token::Key("version") => buf.push_str("1.0.0"),
+                other => return Err(format!("unsupported: {:?}", other)),
+            }
+            Ok(true)
+        };
+
+        let templates = ["${name}_${version}.deb", r"\$name", "$version"];
         assert_eq!(
-            Tokenizer::new(pattern).collect::<Vec<_>>(),
+            super::expand_all(templates.iter().cloned(), &mut map),
+            Ok(vec![
+                "system76_1.0.0.deb".into(),
+                "$name".into(),
+                "1.0.0".into()
+            ])
+        );
+        assert_eq!(
+            super::expand_all(vec!["$name", "$other", "$version"], &mut map),
+            Err("unsupported: Key(\"other\")".into())
+        );
+        assert_eq!(calls, 9);
+        assert_eq!(
+            super::expand_all(Vec::new(), |_, _| Ok::<_, ()>(true)),
+            Ok(vec![])
+        );
+    }
+
+    /// Tokenizes random combinations of the characters involved in constructs, with several
+    /// configurations, asserting that nothing panics, and that all of the input is read.
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn fuzz() {
+        const ALPHABET: &[&str] = &[
+            "$", "{", "}", "\\", ":", "-", "%", "a", " ", "é", "📦", "{{", "}}", "\n", "^", "@i(",
+            ")", "\"", "§", "¢", "r", "#", "~", "/", "?",
+        ];
+
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        let mut template = String::new();
+        for _ in 0..20_000 {
+            template.clear();
+            for _ in 0..random() % 16 {
+                template.push_str(ALPHABET[random() % ALPHABET.len()]);
+            }
+
+            tokenize_lossy(&template);
+            for mut tokenizer in [
+                Tokenizer::new(&template),
+                Tokenizer::windows(&template),
+                Tokenizer::mustache(&template),
+                Tokenizer::positional(&template),
+            ] {
+                tokenizer
+                    .default_values(true)
+                    .decode_escapes(random() % 2 == 0)
+                    .collapse_escapes(random() % 2 == 0)
+                    .type_hints(random() % 2 == 0)
+                    .substitutions(random() % 2 == 0)
+                    .whitespace_keys_literal(random() % 2 == 0)
+                    .required_values(random() % 2 == 0)
+                    .balanced_braces(random() % 2 == 0)
+                    .quoted_keys(random() % 2 == 0)
+                    .raw_strings(random() % 2 == 0)
+                    .preserve_unknown_escapes(random() % 2 == 0)
+                    .recover_literal(random() % 2 == 0)
+                    .dollar_requires_letter(random() % 2 == 0)
+                    .tilde_home(random() % 2 == 0)
+                    .trailing_marks(random() % 2 == 0)
+                    .set_brace_match(if random() % 2 == 0 {
+                        BraceMatch::Lazy
+                    } else {
+                        BraceMatch::Greedy
+                    })
+                    .strict_braces(random() % 2 == 0);
+                match random() % 4 {
+                    0 => tokenizer.set_escape_sequence(b"^^"),
+                    1 => tokenizer.set_escape_char('§'),
+                    _ => &mut tokenizer,
+                };
+                if random() % 2 == 0 {
+                    tokenizer.set_raw_delimiters(Some(Delimiters {
+                        open: "{%",
+                        close: "%}",
+                    }));
+                }
+                if random() % 2 == 0 {
+                    tokenizer.set_include_directive(Some("@i"));
+                }
+                if random() % 2 == 0 {
+                    tokenizer
+                        .set_prefix_kinds(&[("%", PrefixKind("percent")), ("a", PrefixKind("a"))]);
+                }
+                let mut end = 0;
+                for token in tokenizer.clone().spanned() {
+                    assert!(token.span.start >= end, "{:?}", template);
+                    assert!(token.span.end <= template.len(), "{:?}", template);
+                    end = token.span.end;
+                }
+                tokenizer.by_ref().for_each(drop);
+                assert_eq!(tokenizer.read(), template.len(), "{:?}", template);
+            }
+        }
+    }
+
+    /// Templates whose keys are all braced, from pieces which exercise escapes and the
+    /// sections of braced keys.
+    #[cfg(feature = "proptest")]
+    fn braced_template() -> impl proptest::strategy::Strategy<Value = String> {
+        use proptest::collection::vec;
+        use proptest::sample::select;
+        use proptest::strategy::Strategy;
+
+        const PIECES: &[&str] = &[
+            "a",
+            " ",
+            "é",
+            "/",
+            ":",
+            "{",
+            "}",
+            r"\$",
+            r"\\",
+            r"\{",
+            r"\é",
+            "${}",
+            "${a:-b c}",
+            r"${a:?x\}y}",
+            r"${a/b\/c/d}",
+            r"${a//\\/}",
+            "${port:int}",
+        ];
+        const KEYS: &[&str] = &["a", "b_c", "é", " ", "📦"];
+
+        let key = vec(select(KEYS), 1..4).prop_map(|key| format!("${{{}}}", key.concat()));
+        let piece = prop_oneof![2 => select(PIECES).prop_map(String::from), 1 => key];
+        vec(piece, 0..12).prop_map(|pieces| pieces.concat())
+    }
+
+    #[cfg(feature = "proptest")]
+    proptest! {
+        /// Concatenating the source of the tokens of a template whose keys are all braced
+        /// reproduces the template.
+        #[test]
+        fn round_trip(
+            template in braced_template(),
+            modes in proptest::array::uniform4(proptest::bool::ANY),
+        ) {
+            let mut tokenizer = Tokenizer::new(&template);
+            tokenizer
+                .default_values(modes[0])
+                .required_values(modes[1])
+                .substitutions(modes[2])
+                .type_hints(modes[3]);
+            let source = tokenizer
+                .map(|token| token.as_source(b'\\').into_owned())
+                .collect::<String>();
+            prop_assert_eq!(source, template);
+        }
+    }
+
+    #[test]
+    fn include() {
+        let tokens = |template| {
+            Tokenizer::new(template)
+                .set_include_directive(Some("@include"))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            tokens("a@include(foo.tmpl)b"),
             vec![
-                Token::Normal("foo"),
-                Token::Escaped('$'),
-                Token::Normal("{bar}"),
+                Token::Normal("a"),
+                Token::Include("foo.tmpl".into()),
+                Token::Normal("b"),
+            ]
+        );
+        assert_eq!(
+            tokens("@include(my templates/foo.tmpl)$x"),
+            vec![
+                Token::Include("my templates/foo.tmpl".into()),
+                Token::Key("x"),
             ]
         );
+        assert_eq!(
+            tokens(r"@include(a\(1\).tmpl)"),
+            vec![Token::Include(Cow::Owned("a(1).tmpl".into()))]
+        );
+        assert_eq!(tokens("@include"), vec![Token::Normal("@include")]);
+        assert_eq!(
+            tokens(r"\@include(a)"),
+            vec![Token::Escaped('@'), Token::Normal("include(a)")]
+        );
+        assert_eq!(
+            tokens("a@include("),
+            vec![Token::Normal("a"), Token::Normal("@include(")]
+        );
+        assert_eq!(
+            tokens("@include(foo $x"),
+            vec![Token::Normal("@include(foo $x")]
+        );
 
+        let template = "a@include(foo";
+        let mut tokenizer = Tokenizer::new(template);
+        tokenizer
+            .set_include_directive(Some("@include"))
+            .strict(true);
         assert_eq!(
-            Tokenizer::new("foo###${bar}")
-                .set_escape(b'#')
-                .collect::<Vec<_>>(),
+            tokenizer.collect::<Vec<_>>(),
             vec![
-                Token::Normal("foo"),
-                Token::Escaped('#'),
-                Token::Escaped('$'),
-                Token::Normal("{bar}"),
+                Token::Normal("a"),
+                Token::Error(TokenError::new(template, 1, "unterminated include".into())),
             ]
         );
+
+        let template = "{0}@include(b)";
+        let mut tokenizer = Tokenizer::positional(template);
+        tokenizer.set_include_directive(Some("@include"));
+        assert_eq!(
+            tokenizer.expand_positional(&["a"]),
+            Err(ResolveError::Invalid(TokenError::new(
+                template,
+                3,
+                "unsupported include: b".into()
+            )))
+        );
+    }
+
+    #[test]
+    fn nested_markers() {
+        for &(value, expected) in &[
+            ("", false),
+            ("plain text {braces}", false),
+            ("100% pure", false),
+            ("$USER", true),
+            ("a${b}c", true),
+            ("trailing $", true),
+            (r"\$USER", true),
+            (r"C:\Users", true),
+            ("é$", true),
+        ] {
+            assert_eq!(
+                contains_nested_markers(value, b'\\'),
+                expected,
+                "{:?}",
+                value
+            );
+        }
+
+        assert!(!contains_nested_markers(r"C:\Users", b'#'));
+        assert!(contains_nested_markers("#$", b'#'));
     }
 
     #[test]
@@ -335,5 +7578,20 @@ mod tests {
             Tokenizer::new("A ${ab").collect::<Vec<_>>(),
             vec![Token::Normal("A "), Token::Key("ab")]
         );
+
+        for &(template, ref expected) in &[
+            ("${", vec![Token::Key("")]),
+            ("a${", vec![Token::Normal("a"), Token::Key("")]),
+            ("${}", vec![Token::Key("")]),
+            ("${}a", vec![Token::Key(""), Token::Normal("a")]),
+            ("${\\", vec![Token::Key("\\")]),
+            ("$a\\", vec![Token::Key("a\\")]),
+            ("${\\é}", vec![Token::Key("\\é")]),
+        ] {
+            let mut tokenizer = Tokenizer::new(template);
+            assert_eq!(&tokenizer.by_ref().collect::<Vec<_>>(), expected);
+            assert_eq!(tokenizer.read(), template.len());
+            assert_eq!(tokenizer.next(), None);
+        }
     }
 }
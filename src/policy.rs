@@ -0,0 +1,213 @@
+//! Expansion of keys from a lookup, with a policy for keys which are missing from it.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use {ResolveError, Token, Tokenizer};
+
+/// Decides what a key which is missing from the lookup expands to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MissingKeyPolicy<'p> {
+    /// Abandon expansion with `PolicyError::Missing`, within a `ResolveError::Map`.
+    Error,
+    /// Expand to nothing.
+    Empty,
+    /// Expand to the construct as it was written in the template, such as `${name}`.
+    LeaveVerbatim,
+    /// Expand to the given value.
+    Default(&'p str),
+}
+
+/// Failures particular to expanding keys with a `MissingKeyPolicy`, which are given by
+/// `ResolveError::Map`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyError {
+    /// The key is missing from the lookup, and the policy is `MissingKeyPolicy::Error`.
+    Missing(String),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyError::Missing(ref key) => write!(f, "missing key: {}", key),
+        }
+    }
+}
+
+impl Error for PolicyError {}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands keys with the values returned by `lookup`, deferring to `policy` for keys that
+    /// it returns `None` for.
+    ///
+    /// A key with a default value, when default values are enabled, expands to its default
+    /// rather than consulting the policy, and a required key, when required values are enabled,
+    /// fails with `ResolveError::Required` and the key's message.
+    ///
+    /// ```rust
+    /// use token_expander::{MissingKeyPolicy, Tokenizer};
+    ///
+    /// let lookup = |key: &str| match key {
+    ///     "name" => Some("system76"),
+    ///     _ => None,
+    /// };
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${name}_${version}.deb")
+    ///         .expand_with_policy(lookup, MissingKeyPolicy::LeaveVerbatim),
+    ///     Ok("system76_${version}.deb".into())
+    /// );
+    /// ```
+    pub fn expand_with_policy<F, V>(
+        &mut self,
+        mut lookup: F,
+        policy: MissingKeyPolicy,
+    ) -> Result<String, ResolveError<PolicyError>>
+    where
+        F: FnMut(&str) -> Option<V>,
+        V: AsRef<str>,
+    {
+        let mut output = String::with_capacity(self.data.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            let (key, default) = match token {
+                Token::Normal(text) => {
+                    output.push_str(text);
+                    continue;
+                }
+                Token::Escaped(character) => {
+                    output.push(character);
+                    continue;
+                }
+                Token::EscapedStr(grapheme) => {
+                    output.push_str(grapheme);
+                    continue;
+                }
+                Token::Key(key)
+                | Token::PrefixedKey { name: key, .. }
+                | Token::KeyTyped { key, .. }
+                | Token::KeyReplace { key, .. } => (Cow::Borrowed(key), None),
+                Token::KeyDecoded { ref key, .. } => (Cow::Borrowed(key.as_str()), None),
+                Token::KeyDefault { key, default } => (Cow::Borrowed(key), Some(default)),
+                Token::KeyRequired { key, .. } => (Cow::Borrowed(key), None),
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    output.push_str(self.unresolved(token, span)?);
+                    continue;
+                }
+            };
+
+            let value = lookup(&key);
+            if let (None, Token::KeyRequired { message, .. }) = (&value, &token) {
+                return Err(ResolveError::Required {
+                    key: key.into_owned(),
+                    message: message.to_string(),
+                });
+            }
+
+            match (value, default) {
+                (Some(value), _) => output.push_str(&token.substitute(value.as_ref())),
+                (None, Some(default)) => output.push_str(default),
+                (None, None) => match policy {
+                    MissingKeyPolicy::Error => {
+                        return Err(ResolveError::Map(PolicyError::Missing(key.into_owned())))
+                    }
+                    MissingKeyPolicy::Empty => (),
+                    MissingKeyPolicy::LeaveVerbatim => output.push_str(&self.data[span]),
+                    MissingKeyPolicy::Default(value) => output.push_str(value),
+                },
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenError;
+
+    #[test]
+    fn policies() {
+        let expand = |policy| {
+            Tokenizer::new("$name/${version}_\\$arch").expand_with_policy(
+                |key| match key {
+                    "name" => Some(String::from("system76")),
+                    _ => None,
+                },
+                policy,
+            )
+        };
+
+        assert_eq!(
+            expand(MissingKeyPolicy::Error),
+            Err(ResolveError::Map(PolicyError::Missing("version".into())))
+        );
+        assert_eq!(
+            expand(MissingKeyPolicy::Empty),
+            Ok("system76/_$arch".into())
+        );
+        assert_eq!(
+            expand(MissingKeyPolicy::LeaveVerbatim),
+            Ok("system76/${version}_$arch".into())
+        );
+        assert_eq!(
+            expand(MissingKeyPolicy::Default("0.0.0")),
+            Ok("system76/0.0.0_$arch".into())
+        );
+    }
+
+    #[test]
+    fn default_value() {
+        assert_eq!(
+            Tokenizer::new("${version:-1.0.0}")
+                .default_values(true)
+                .expand_with_policy(|_| None::<&str>, MissingKeyPolicy::Error),
+            Ok("1.0.0".into())
+        );
+    }
+
+    #[test]
+    fn include() {
+        let template = "~/@include(a.tmpl)";
+        let expanded = Tokenizer::new(template)
+            .tilde_home(true)
+            .set_include_directive(Some("@include"))
+            .expand_with_policy(|_| None::<&str>, MissingKeyPolicy::Empty);
+        assert_eq!(
+            expanded,
+            Err(ResolveError::Invalid(TokenError::new(
+                template,
+                2,
+                "unsupported include: a.tmpl".into()
+            )))
+        );
+    }
+
+    #[test]
+    fn required_value() {
+        let expand = |template| {
+            Tokenizer::new(template)
+                .required_values(true)
+                .expand_with_policy(
+                    |key| match key {
+                        "name" => Some("system76"),
+                        _ => None,
+                    },
+                    MissingKeyPolicy::Empty,
+                )
+        };
+
+        assert_eq!(
+            expand("${name:?name is unset}.deb"),
+            Ok("system76.deb".into())
+        );
+        assert_eq!(
+            expand(r"$name/${version:?set \{version\} first}"),
+            Err(ResolveError::Required {
+                key: "version".into(),
+                message: "set {version} first".into()
+            })
+        );
+    }
+}
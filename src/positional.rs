@@ -0,0 +1,134 @@
+//! Expansion of numbered placeholders, such as `{0}`, from a slice of values.
+
+use std::error::Error;
+use std::fmt;
+use {Delimiters, KeyValidator, ResolveError, Token, Tokenizer};
+
+/// Failures particular to expanding numbered placeholders, which are given by
+/// `ResolveError::Map`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PositionalError {
+    /// The placeholder's index is beyond the end of the values.
+    OutOfRange(String),
+}
+
+impl fmt::Display for PositionalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PositionalError::OutOfRange(ref index) => {
+                write!(f, "placeholder index out of range: {}", index)
+            }
+        }
+    }
+}
+
+impl Error for PositionalError {}
+
+fn numeric(key: &str) -> Result<(), String> {
+    if !key.is_empty() && key.bytes().all(|byte| byte.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(format!("placeholder is not an index: {}", key))
+    }
+}
+
+impl Tokenizer<'static> {
+    /// The configuration of `Tokenizer::positional`, over empty input.
+    pub const POSITIONAL: Tokenizer<'static> = Tokenizer {
+        prefixes: &[],
+        delimiters: Some(Delimiters::BRACES),
+        validator: Some(KeyValidator::Fn(numeric)),
+        ..Tokenizer::SHELL
+    };
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Constructs a tokenizer for `format!`-style templates, where numbered placeholders are
+    /// enclosed by `{` and `}`, and `{{` and `}}` are literal braces.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::positional("{{{0}}}").collect::<Vec<_>>(),
+    ///     vec![Token::Escaped('{'), Token::Key("0"), Token::Escaped('}')]
+    /// );
+    /// ```
+    pub const fn positional(data: &'a str) -> Tokenizer<'a> {
+        Tokenizer {
+            data,
+            ..Tokenizer::POSITIONAL
+        }
+    }
+
+    /// Expands each numbered placeholder with the value at its index within `values`.
+    ///
+    /// ```rust
+    /// use token_expander::Tokenizer;
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::positional("{1}_{0}.deb").expand_positional(&["1.0.0", "system76"]),
+    ///     Ok("system76_1.0.0.deb".into())
+    /// );
+    /// ```
+    pub fn expand_positional(
+        &mut self,
+        values: &[&str],
+    ) -> Result<String, ResolveError<PositionalError>> {
+        let mut output = String::with_capacity(self.data.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            match token {
+                Token::Normal(text) | Token::EscapedStr(text) => output.push_str(text),
+                Token::Escaped(character) => output.push(character),
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    output.push_str(self.unresolved(token, span)?)
+                }
+                token => {
+                    let key = token.key().unwrap_or_default();
+                    match key
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| values.get(index))
+                    {
+                        Some(value) => output.push_str(&token.substitute(value)),
+                        None => {
+                            return Err(ResolveError::Map(PositionalError::OutOfRange(key.into())))
+                        }
+                    }
+                }
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenError;
+
+    #[test]
+    fn positional() {
+        let values = ["a", "b"];
+        let expand = |template| Tokenizer::positional(template).expand_positional(&values);
+
+        assert_eq!(expand("{0}-{1}"), Ok("a-b".into()));
+        assert_eq!(expand("{1}{1}{0}"), Ok("bba".into()));
+        assert_eq!(expand("{{literal}}"), Ok("{literal}".into()));
+        assert_eq!(expand("{{{0}}}"), Ok("{a}".into()));
+        assert_eq!(
+            expand("{0}{5}"),
+            Err(ResolveError::Map(PositionalError::OutOfRange("5".into())))
+        );
+        assert_eq!(
+            expand("{name}"),
+            Err(ResolveError::Invalid(TokenError::new(
+                "{name}",
+                0,
+                "placeholder is not an index: name".into()
+            )))
+        );
+    }
+}
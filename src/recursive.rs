@@ -0,0 +1,248 @@
+//! Expansion of keys whose values are templates of their own.
+
+use std::borrow::Cow;
+use {BoundedError, ResolveError, Token, Tokenizer};
+
+/// Bounds on a `Tokenizer::expand_recursive`, so that untrusted templates and values cannot
+/// expand without limit.
+#[derive(Debug, Clone, Copy, PartialEq, SmartDefault)]
+pub struct ExpandLimits {
+    /// How many times values may be nested within one another. Keys of the template are at
+    /// depth 0, and keys within their values at depth 1.
+    #[default = 8]
+    pub max_depth: usize,
+    /// The number of bytes that the output may grow to.
+    #[default = 1048576]
+    pub max_total_bytes: usize,
+    /// The number of keys which may be expanded, across the template and all values.
+    #[default = 1024]
+    pub max_keys: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands keys with the values of `map`, which are expanded in turn as templates of their
+    /// own, with this tokenizer's configuration, within the bounds of `limits`. Exceeding a
+    /// limit fails with `BoundedError::TooDeep`, `BoundedError::OutputTooLarge`, or
+    /// `BoundedError::TooManyKeys`, and other failures are given by `BoundedError::Map`.
+    ///
+    /// With default values enabled, the default of a key is expanded when its value expands to
+    /// nothing, and with required values enabled, a required key which expands to nothing
+    /// fails.
+    ///
+    /// ```rust
+    /// use token_expander::{BoundedError, ExpandLimits, ResolveError, Tokenizer};
+    ///
+    /// let map = |key: &str| match key {
+    ///     "home" => Ok("/home/$user".into()),
+    ///     "user" => Ok("pop".into()),
+    ///     "loop" => Ok("${loop}".into()),
+    ///     _ => Err(format!("unknown key: {}", key)),
+    /// };
+    ///
+    /// let limits = ExpandLimits::default();
+    /// assert_eq!(
+    ///     Tokenizer::new("${home}/.config").expand_recursive(map, limits),
+    ///     Ok("/home/pop/.config".into())
+    /// );
+    /// assert_eq!(
+    ///     Tokenizer::new("$loop").expand_recursive(map, limits),
+    ///     Err(BoundedError::TooDeep { limit: 8, key: "loop".into() })
+    /// );
+    /// assert_eq!(
+    ///     Tokenizer::new("$name").expand_recursive(map, limits),
+    ///     Err(BoundedError::Map(ResolveError::Map("unknown key: name".into())))
+    /// );
+    /// ```
+    pub fn expand_recursive<T, F>(
+        &mut self,
+        mut map: F,
+        limits: ExpandLimits,
+    ) -> Result<String, BoundedError<ResolveError<T>>>
+    where
+        F: FnMut(&str) -> Result<String, T>,
+    {
+        let mut output = String::with_capacity(self.data.len() * 2);
+        let mut keys = 0;
+        let mut expansion = Recursion {
+            map: &mut map,
+            limits,
+            keys: &mut keys,
+        };
+        expansion.expand(self, &mut output, 0)?;
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+/// The state shared by the passes of a `Tokenizer::expand_recursive`.
+struct Recursion<'m, F: 'm> {
+    map: &'m mut F,
+    limits: ExpandLimits,
+    keys: &'m mut usize,
+}
+
+impl<'m, F> Recursion<'m, F> {
+    fn expand<T>(
+        &mut self,
+        tokenizer: &mut Tokenizer,
+        output: &mut String,
+        depth: usize,
+    ) -> Result<(), BoundedError<ResolveError<T>>>
+    where
+        F: FnMut(&str) -> Result<String, T>,
+    {
+        while let Some((token, span)) = tokenizer.next_spanned() {
+            match token {
+                Token::Normal(text) | Token::EscapedStr(text) => output.push_str(text),
+                Token::Escaped(character) => output.push(character),
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    output.push_str(
+                        tokenizer
+                            .unresolved(token, span)
+                            .map_err(BoundedError::Map)?,
+                    )
+                }
+                token => {
+                    let key = token.key().unwrap_or_default();
+                    if depth > self.limits.max_depth {
+                        return Err(BoundedError::TooDeep {
+                            limit: self.limits.max_depth,
+                            key: key.into(),
+                        });
+                    }
+                    if *self.keys == self.limits.max_keys {
+                        return Err(BoundedError::TooManyKeys {
+                            limit: self.limits.max_keys,
+                            key: key.into(),
+                        });
+                    }
+                    *self.keys += 1;
+
+                    let value =
+                        (self.map)(key).map_err(|why| BoundedError::Map(ResolveError::Map(why)))?;
+                    let start = output.len();
+                    self.expand(&mut tokenizer.rebind(&value), output, depth + 1)?;
+                    match token {
+                        Token::KeyDefault { default, .. } if output.len() == start => {
+                            self.expand(&mut tokenizer.rebind(default), output, depth + 1)?
+                        }
+                        Token::KeyRequired { key, message } if output.len() == start => {
+                            return Err(BoundedError::Map(ResolveError::Required {
+                                key: key.into(),
+                                message: message.into_owned(),
+                            }))
+                        }
+                        Token::KeyReplace { .. } => {
+                            if let Cow::Owned(value) = token.substitute(&output[start..]) {
+                                output.truncate(start);
+                                output.push_str(&value);
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+
+            if output.len() > self.limits.max_total_bytes {
+                return Err(BoundedError::OutputTooLarge(self.limits.max_total_bytes));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(key: &str) -> Result<String, String> {
+        match key {
+            "name" => Ok("system76".into()),
+            "package" => Ok("${name}_$version".into()),
+            "version" => Ok("1.0.0".into()),
+            "large" => Ok("x".repeat(64)),
+            "chain" => Ok("$package/$package".into()),
+            "empty" => Ok(String::new()),
+            _ => Err(format!("unknown key: {}", key)),
+        }
+    }
+
+    #[test]
+    fn nested() {
+        let limits = ExpandLimits::default();
+        assert_eq!(
+            Tokenizer::new("${package}.deb").expand_recursive(map, limits),
+            Ok("system76_1.0.0.deb".into())
+        );
+        assert_eq!(
+            Tokenizer::new("${empty:-$name}/${package/_/-}")
+                .default_values(true)
+                .substitutions(true)
+                .expand_recursive(map, limits),
+            Ok("system76/system76-1.0.0".into())
+        );
+        assert_eq!(
+            Tokenizer::new("${empty:?is empty}")
+                .required_values(true)
+                .expand_recursive(map, limits),
+            Err(BoundedError::Map(ResolveError::Required {
+                key: "empty".into(),
+                message: "is empty".into(),
+            }))
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        let limits = ExpandLimits {
+            max_depth: 1,
+            ..ExpandLimits::default()
+        };
+        assert_eq!(
+            Tokenizer::new("$package").expand_recursive(map, limits),
+            Ok("system76_1.0.0".into())
+        );
+        assert_eq!(
+            Tokenizer::new("$chain").expand_recursive(map, limits),
+            Err(BoundedError::TooDeep {
+                limit: 1,
+                key: "name".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn max_total_bytes() {
+        let limits = ExpandLimits {
+            max_total_bytes: 100,
+            ..ExpandLimits::default()
+        };
+        assert!(Tokenizer::new("$large")
+            .expand_recursive(map, limits)
+            .is_ok());
+        assert_eq!(
+            Tokenizer::new("$large/$large").expand_recursive(map, limits),
+            Err(BoundedError::OutputTooLarge(100))
+        );
+    }
+
+    #[test]
+    fn max_keys() {
+        let limits = ExpandLimits {
+            max_keys: 3,
+            ..ExpandLimits::default()
+        };
+        assert_eq!(
+            Tokenizer::new("$package").expand_recursive(map, limits),
+            Ok("system76_1.0.0".into())
+        );
+        assert_eq!(
+            Tokenizer::new("$package/$name").expand_recursive(map, limits),
+            Err(BoundedError::TooManyKeys {
+                limit: 3,
+                key: "name".into(),
+            })
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//! Expansions which share each resolved value, rather than copying it into the output.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::rc::Rc;
+
+/// A piece of a `Rope`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Piece<'a> {
+    /// Text of the template.
+    Text(Cow<'a, str>),
+    /// A resolved value, shared by every key that it was substituted for.
+    Value(Rc<str>),
+}
+
+impl<'a> Piece<'a> {
+    fn as_str(&self) -> &str {
+        match *self {
+            Piece::Text(ref text) => text,
+            Piece::Value(ref value) => value,
+        }
+    }
+}
+
+/// The pieces of an expansion, created by `Tokenizer::expand_rope`, in which each resolved
+/// value is stored once, however many times it is substituted. The pieces are concatenated
+/// by `to_string`, or may be written out individually.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rope<'a> {
+    pub(crate) pieces: Vec<Piece<'a>>,
+}
+
+impl<'a> Rope<'a> {
+    /// The pieces of the expansion, in order.
+    pub fn pieces(&self) -> impl Iterator<Item = &str> {
+        self.pieces.iter().map(Piece::as_str)
+    }
+
+    /// The length of the expansion, once concatenated.
+    pub fn len(&self) -> usize {
+        self.pieces().map(str::len).sum()
+    }
+
+    /// Whether the expansion is empty.
+    pub fn is_empty(&self) -> bool {
+        self.pieces().all(str::is_empty)
+    }
+}
+
+impl<'a> fmt::Display for Rope<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.pieces().try_for_each(|piece| f.write_str(piece))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ResolveError, Tokenizer};
+
+    #[test]
+    fn shared() {
+        let mut calls = 0;
+        let large = "x".repeat(4096);
+        let rope = Tokenizer::new(r"${v}-$v/${v}\$").expand_rope(|key| {
+            calls += 1;
+            match key {
+                "v" => Ok(large.clone()),
+                _ => Err(format!("unknown key: {}", key)),
+            }
+        });
+
+        let rope = rope.unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(rope.to_string(), format!("{0}-{0}/{0}$", large));
+        assert_eq!(rope.len(), large.len() * 3 + 3);
+
+        let shared = rope
+            .pieces
+            .iter()
+            .filter_map(|piece| match *piece {
+                Piece::Value(ref value) => Some(value),
+                Piece::Text(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(shared.len(), 3);
+        assert!(shared.iter().all(|value| Rc::ptr_eq(value, shared[0])));
+        assert_eq!(Rc::strong_count(shared[0]), 3);
+    }
+
+    #[test]
+    fn keys() {
+        let rope = Tokenizer::new("${a:-none}/${b:?required}")
+            .default_values(true)
+            .required_values(true)
+            .expand_rope(|_| Ok::<_, String>(String::new()));
+        assert_eq!(
+            rope,
+            Err(ResolveError::Required {
+                key: "b".into(),
+                message: "required".into(),
+            })
+        );
+
+        let rope = Tokenizer::new("${a:-none}")
+            .default_values(true)
+            .expand_rope(|_| Ok::<_, String>(String::new()));
+        assert_eq!(rope.map(|rope| rope.to_string()), Ok("none".into()));
+        let rope = Tokenizer::new("a  \t b/$v")
+            .normalize_whitespace(true)
+            .expand_rope(|_| Ok::<_, String>("c  d".into()));
+        assert_eq!(rope.map(|rope| rope.to_string()), Ok("a b/c  d".into()));
+        assert!(Tokenizer::new("")
+            .expand_rope(|_| Ok::<_, String>(String::new()))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+//! Tokenizers whose configuration may no longer be changed.
+
+use {Spanned, Token, Tokenizer, TokenizerExt};
+
+/// A tokenizer whose configuration is fixed, created by `Tokenizer::seal`, for handing to code
+/// which should iterate over tokens without changing how they are parsed.
+///
+/// Only iteration and read-only accessors are available, so configuration cannot be changed:
+///
+/// ```rust,compile_fail
+/// use token_expander::{Tokenizer, TokenizerExt};
+///
+/// let mut sealed = Tokenizer::new("${a}").seal();
+/// sealed.set_escape(b'#');
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealedTokenizer<'a> {
+    tokenizer: Tokenizer<'a>,
+}
+
+impl<'a> SealedTokenizer<'a> {
+    /// The escape character in use, or the first character of an escape sequence of several
+    /// bytes.
+    pub fn escape_char(&self) -> char {
+        self.tokenizer.escape_char()
+    }
+
+    /// The length of the inner string.
+    pub fn len(&self) -> usize {
+        self.tokenizer.len()
+    }
+
+    /// Whether the inner string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.tokenizer.is_empty()
+    }
+
+    /// The number of bytes that have been read from the inner string.
+    pub fn read(&self) -> usize {
+        self.tokenizer.read()
+    }
+
+    /// Whether the escape byte has been read, so that the next token will be the escaped
+    /// character which follows it.
+    pub fn escape_pending(&self) -> bool {
+        self.tokenizer.escape_pending()
+    }
+
+    /// Iterates over tokens alongside the source text that each was parsed from.
+    pub fn spanned(self) -> Spanned<'a> {
+        self.tokenizer.spanned()
+    }
+}
+
+impl<'a> Iterator for SealedTokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        self.tokenizer.next()
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Fixes the configuration of this tokenizer, such as its escape, prefixes, and modes,
+    /// returning a tokenizer which may only be iterated over and inspected.
+    ///
+    /// ```rust
+    /// use token_expander::{Token, Tokenizer, TokenizerExt};
+    ///
+    /// let mut tokenizer = Tokenizer::new("#$a${b}");
+    /// tokenizer.set_escape(b'#');
+    /// let sealed = tokenizer.seal();
+    /// assert_eq!(sealed.escape_char(), '#');
+    /// assert_eq!(
+    ///     sealed.collect::<Vec<_>>(),
+    ///     vec![Token::Escaped('$'), Token::Normal("a"), Token::Key("b")]
+    /// );
+    /// ```
+    pub fn seal(self) -> SealedTokenizer<'a> {
+        SealedTokenizer { tokenizer: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iterates_identically() {
+        let mut tokenizer = Tokenizer::new(r"a\$b${c:-d}$e ^f");
+        tokenizer.default_values(true).set_escape(b'^');
+        let mut sealed = tokenizer.clone().seal();
+
+        assert_eq!(
+            sealed.clone().collect::<Vec<_>>(),
+            tokenizer.collect::<Vec<_>>()
+        );
+        assert_eq!((sealed.len(), sealed.read()), (16, 0));
+        assert!(!sealed.is_empty());
+        assert!(Tokenizer::new("").seal().is_empty());
+        assert_eq!(sealed.escape_char(), '^');
+        assert_eq!(
+            Tokenizer::new("§$a")
+                .set_escape_char('§')
+                .clone()
+                .seal()
+                .escape_char(),
+            '§'
+        );
+        assert_eq!(sealed.next(), Some(Token::Normal(r"a\")));
+        assert_eq!(sealed.read(), 2);
+        assert_eq!(
+            sealed.spanned().map(|token| token.span).collect::<Vec<_>>(),
+            vec![2..4, 4..11, 11..16]
+        );
+    }
+}
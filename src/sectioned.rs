@@ -0,0 +1,189 @@
+//! Expansion of keys of the form `section.key` from a two-level map, such as an INI file.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use {ResolveError, Token, Tokenizer};
+
+/// Failures particular to expanding keys from a map of sections, which are given by
+/// `ResolveError::Map`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SectionError {
+    /// The key's section is missing from the map.
+    MissingSection(String),
+    /// The key is missing from its section.
+    MissingKey {
+        /// The name of the section.
+        section: String,
+        /// The name of the key within the section.
+        key: String,
+    },
+}
+
+impl fmt::Display for SectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SectionError::MissingSection(ref section) => write!(f, "missing section: {}", section),
+            SectionError::MissingKey {
+                ref section,
+                ref key,
+            } => write!(f, "missing key: {} in section: {}", key, section),
+        }
+    }
+}
+
+impl Error for SectionError {}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands keys with the values of `map`, in which each key is split on the first `sep`
+    /// into the name of its section and its name within that section, so that `${db.host}`
+    /// expands to the `host` of the `db` section.
+    ///
+    /// A key without a separator is looked up in the default section, which is named by the
+    /// empty string. A missing section or key fails, unless the key has a default value, when
+    /// default values are enabled, and a required key fails with `ResolveError::Required`.
+    ///
+    /// ```rust
+    /// use std::collections::HashMap;
+    /// use token_expander::Tokenizer;
+    ///
+    /// let mut map = HashMap::new();
+    /// map.insert("", HashMap::from([("user", "pop")]));
+    /// map.insert("db", HashMap::from([("host", "localhost")]));
+    ///
+    /// assert_eq!(
+    ///     Tokenizer::new("${user}@${db.host}").expand_sectioned(&map, '.'),
+    ///     Ok("pop@localhost".into())
+    /// );
+    /// ```
+    pub fn expand_sectioned(
+        &mut self,
+        map: &HashMap<&str, HashMap<&str, &str>>,
+        sep: char,
+    ) -> Result<String, ResolveError<SectionError>> {
+        let mut output = String::with_capacity(self.data.len() * 2);
+        while let Some((token, span)) = self.next_spanned() {
+            let key = match token {
+                Token::Normal(text) => {
+                    output.push_str(text);
+                    continue;
+                }
+                Token::Escaped(character) => {
+                    output.push(character);
+                    continue;
+                }
+                Token::EscapedStr(grapheme) => {
+                    output.push_str(grapheme);
+                    continue;
+                }
+                Token::Key(key)
+                | Token::PrefixedKey { name: key, .. }
+                | Token::KeyDefault { key, .. }
+                | Token::KeyTyped { key, .. }
+                | Token::KeyReplace { key, .. }
+                | Token::KeyRequired { key, .. } => Cow::Borrowed(key),
+                Token::KeyDecoded { ref key, .. } => Cow::Borrowed(key.as_str()),
+                token @ Token::Home(_) | token @ Token::Include(_) | token @ Token::Error(_) => {
+                    output.push_str(self.unresolved(token, span)?);
+                    continue;
+                }
+            };
+
+            let (section, name) = key.split_once(sep).unwrap_or(("", &key));
+            let names = map.get(section);
+            match (names.and_then(|names| names.get(name)), &token) {
+                (Some(value), _) => output.push_str(&token.substitute(value)),
+                (None, &Token::KeyDefault { default, .. }) => output.push_str(default),
+                (None, Token::KeyRequired { message, .. }) => {
+                    return Err(ResolveError::Required {
+                        key: key.into_owned(),
+                        message: message.to_string(),
+                    })
+                }
+                (None, _) if names.is_none() => {
+                    return Err(ResolveError::Map(SectionError::MissingSection(
+                        section.into(),
+                    )))
+                }
+                (None, _) => {
+                    return Err(ResolveError::Map(SectionError::MissingKey {
+                        section: section.into(),
+                        key: name.into(),
+                    }))
+                }
+            }
+        }
+
+        output.shrink_to_fit();
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> HashMap<&'static str, HashMap<&'static str, &'static str>> {
+        let mut map = HashMap::new();
+        map.insert("", HashMap::from([("name", "pop")]));
+        map.insert(
+            "db",
+            HashMap::from([("host", "localhost"), ("port", "5432")]),
+        );
+        map
+    }
+
+    #[test]
+    fn sections() {
+        let map = map();
+        assert_eq!(
+            Tokenizer::new("${db.host}:${db.port}/$name").expand_sectioned(&map, '.'),
+            Ok("localhost:5432/pop".into())
+        );
+        assert_eq!(
+            Tokenizer::new("${db:host}").expand_sectioned(&map, ':'),
+            Ok("localhost".into())
+        );
+        assert_eq!(
+            Tokenizer::new("${db.user:-postgres}@${cache.host:-none}")
+                .default_values(true)
+                .expand_sectioned(&map, '.'),
+            Ok("postgres@none".into())
+        );
+    }
+
+    #[test]
+    fn missing() {
+        let map = map();
+        assert_eq!(
+            Tokenizer::new("${cache.host}").expand_sectioned(&map, '.'),
+            Err(ResolveError::Map(SectionError::MissingSection(
+                "cache".into()
+            )))
+        );
+        assert_eq!(
+            Tokenizer::new("${db.user}").expand_sectioned(&map, '.'),
+            Err(ResolveError::Map(SectionError::MissingKey {
+                section: "db".into(),
+                key: "user".into(),
+            }))
+        );
+        assert_eq!(
+            Tokenizer::new("${host}").expand_sectioned(&map, '.'),
+            Err(ResolveError::Map(SectionError::MissingKey {
+                section: "".into(),
+                key: "host".into(),
+            }))
+        );
+        assert_eq!(
+            Tokenizer::new("${db.user:?set a user}")
+                .required_values(true)
+                .expand_sectioned(&map, '.'),
+            Err(ResolveError::Required {
+                key: "db.user".into(),
+                message: "set a user".into(),
+            })
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Expansion of keys from a type which provides values by name, such as a configuration struct.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use {MissingKeyPolicy, PolicyError, ResolveError, Tokenizer};
+
+/// A source of values for keys, which downstream types, such as configuration structs, may
+/// implement to be expanded with `Tokenizer::expand_source`.
+pub trait KeySource {
+    /// The value of `key`, or `None` if the source has no such key.
+    fn get(&self, key: &str) -> Option<Cow<'_, str>>;
+}
+
+impl<S: KeySource + ?Sized> KeySource for &S {
+    fn get(&self, key: &str) -> Option<Cow<'_, str>> {
+        (**self).get(key)
+    }
+}
+
+impl<H: BuildHasher> KeySource for HashMap<String, String, H> {
+    fn get(&self, key: &str) -> Option<Cow<'_, str>> {
+        HashMap::get(self, key).map(|value| Cow::Borrowed(value.as_str()))
+    }
+}
+
+impl<'m, H: BuildHasher> KeySource for HashMap<&'m str, &'m str, H> {
+    fn get(&self, key: &str) -> Option<Cow<'_, str>> {
+        HashMap::get(self, key).map(|&value| Cow::Borrowed(value))
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands keys with the values of `source`, failing with `PolicyError::Missing`, within a
+    /// `ResolveError::Map`, for a key which the source does not have.
+    ///
+    /// ```rust
+    /// use std::borrow::Cow;
+    /// use token_expander::{KeySource, Tokenizer};
+    ///
+    /// struct Package {
+    ///     name: String,
+    ///     version: (u32, u32),
+    /// }
+    ///
+    /// impl KeySource for Package {
+    ///     fn get(&self, key: &str) -> Option<Cow<str>> {
+    ///         match key {
+    ///             "name" => Some(Cow::Borrowed(&self.name)),
+    ///             "version" => Some(Cow::Owned(format!("{}.{}", self.version.0, self.version.1))),
+    ///             _ => None,
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let package = Package { name: "system76".into(), version: (1, 0) };
+    /// assert_eq!(
+    ///     Tokenizer::new("${name}_${version}.deb").expand_source(&package),
+    ///     Ok("system76_1.0.deb".into())
+    /// );
+    /// ```
+    pub fn expand_source<S: KeySource + ?Sized>(
+        &mut self,
+        source: &S,
+    ) -> Result<String, ResolveError<PolicyError>> {
+        self.expand_with_policy(|key| source.get(key), MissingKeyPolicy::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Config {
+        user: &'static str,
+        port: u16,
+    }
+
+    impl KeySource for Config {
+        fn get(&self, key: &str) -> Option<Cow<'_, str>> {
+            match key {
+                "user" => Some(Cow::Borrowed(self.user)),
+                "port" => Some(Cow::Owned(self.port.to_string())),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn structs() {
+        let config = Config {
+            user: "pop",
+            port: 8080,
+        };
+
+        assert_eq!(
+            Tokenizer::new("$user@localhost:${port}").expand_source(&config),
+            Ok("pop@localhost:8080".into())
+        );
+        assert_eq!(
+            Tokenizer::new("${host:-localhost}:$port")
+                .default_values(true)
+                .expand_source(&config),
+            Ok("localhost:8080".into())
+        );
+        assert_eq!(
+            Tokenizer::new("$user@$host").expand_source(&config),
+            Err(ResolveError::Map(PolicyError::Missing("host".into())))
+        );
+    }
+
+    #[test]
+    fn maps() {
+        let mut owned = HashMap::new();
+        owned.insert(String::from("name"), String::from("system76"));
+        assert_eq!(
+            Tokenizer::new("${name}.deb").expand_source(&owned),
+            Ok("system76.deb".into())
+        );
+
+        let mut borrowed = HashMap::new();
+        borrowed.insert("name", "system76");
+        let source: &dyn KeySource = &borrowed;
+        assert_eq!(
+            Tokenizer::new("${name}.deb").expand_source(&source),
+            Ok("system76.deb".into())
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Tokens which are paired with the source that they were parsed from.
+
+use std::ops::Range;
+use {Token, Tokenizer};
+
+/// A token, and the range of bytes within the source that it was parsed from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken<'a> {
+    /// The parsed token.
+    pub token: Token<'a>,
+    /// The byte range of the construct within the source, including any prefix, braces, or
+    /// escape character.
+    pub span: Range<usize>,
+    /// The source text that the token was parsed from.
+    pub source: &'a str,
+}
+
+impl<'a> SpannedToken<'a> {
+    /// For an escaped character, the character and the source sequence that produced it.
+    ///
+    /// With escape decoding enabled, this distinguishes a `\n` in the source from the newline
+    /// that it was decoded into.
+    pub fn escape(&self) -> Option<(char, &'a str)> {
+        match self.token {
+            Token::Escaped(character) => Some((character, self.source)),
+            _ => None,
+        }
+    }
+}
+
+/// An iterator of `SpannedToken`, created by `Tokenizer::spanned`.
+#[derive(new, Debug, Clone)]
+pub struct Spanned<'a> {
+    tokenizer: Tokenizer<'a>,
+}
+
+impl<'a> Iterator for Spanned<'a> {
+    type Item = SpannedToken<'a>;
+
+    fn next(&mut self) -> Option<SpannedToken<'a>> {
+        let data = self.tokenizer.data;
+        self.tokenizer
+            .next_spanned()
+            .map(|(token, span)| SpannedToken {
+                token,
+                source: &data[span.clone()],
+                span,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_source() {
+        let tokens = Tokenizer::new(r"a\tb\u{e9}${c}\\")
+            .decode_escapes(true)
+            .clone()
+            .spanned()
+            .collect::<Vec<_>>();
+
+        assert_eq!(tokens[1].escape(), Some(('\t', r"\t")));
+        assert_eq!(tokens[1].span, 1..3);
+        assert_eq!(tokens[3].escape(), Some(('é', r"\u{e9}")));
+        assert_eq!(tokens[3].span, 4..10);
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| (token.token.clone(), token.source))
+                .collect::<Vec<_>>(),
+            vec![
+                (Token::Normal("a"), "a"),
+                (Token::Escaped('\t'), r"\t"),
+                (Token::Normal("b"), "b"),
+                (Token::Escaped('é'), r"\u{e9}"),
+                (Token::Key("c"), "${c}"),
+                (Token::Escaped('\\'), r"\\"),
+            ]
+        );
+
+        let raw = Tokenizer::new(r"\t").spanned().collect::<Vec<_>>();
+        assert_eq!(raw[0].escape(), Some(('t', r"\t")));
+    }
+}
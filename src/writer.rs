@@ -0,0 +1,316 @@
+//! Expansion of a template as it is written, through `std::io::Write`.
+
+use chunked::split_incomplete;
+use std::io::{self, Write};
+use std::str;
+use {Token, Tokenizer};
+
+/// Expands the template written to it, writing the expansion into an inner writer.
+///
+/// Text is expanded as it arrives, but a construct which may continue in a later write, such
+/// as an unterminated `${`, is buffered until it is complete. Call `finish` after the template
+/// has been written, to expand whatever remains buffered.
+///
+/// ```rust
+/// use std::io::{self, Write};
+/// use token_expander::{ExpandWriter, Token};
+///
+/// # fn main() -> io::Result<()> {
+/// let mut writer = ExpandWriter::new(Vec::new(), |buf: &mut String, token: Token| {
+///     match token {
+///         Token::Normal(text) => buf.push_str(text),
+///         Token::Key("name") => buf.push_str("system76"),
+///         other => return Err(io::Error::other(format!("unsupported: {:?}", other))),
+///     }
+///     Ok(())
+/// });
+///
+/// writer.write_all(b"${na")?;
+/// write!(writer, "me}}_{}.deb", "1.0.0")?;
+/// assert_eq!(writer.finish()?, b"system76_1.0.0.deb");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ExpandWriter<'m, W, F> {
+    inner: W,
+    map: F,
+    config: Tokenizer<'m>,
+    pending: Vec<u8>,
+    output: String,
+}
+
+impl<'m, W: Write, F: FnMut(&mut String, Token) -> io::Result<()>> ExpandWriter<'m, W, F> {
+    /// Expands into `inner` with the default configuration of a `Tokenizer`.
+    pub fn new(inner: W, map: F) -> Self {
+        Self::with_config(inner, map, &Tokenizer::new(""))
+    }
+
+    /// Expands into `inner` with the escape, prefixes, and modes of `config`.
+    pub fn with_config(inner: W, map: F, config: &Tokenizer<'m>) -> Self {
+        ExpandWriter {
+            inner,
+            map,
+            config: config.rebind(""),
+            pending: Vec::new(),
+            output: String::new(),
+        }
+    }
+
+    /// Expands any construct which is still buffered, and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let pending = str::from_utf8(&self.pending)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+
+        self.output.clear();
+        for token in self.config.rebind(pending) {
+            (self.map)(&mut self.output, token)?;
+        }
+
+        self.inner.write_all(self.output.as_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Expands and writes out the complete constructs which are pending, returning the number
+    /// of bytes that were consumed.
+    fn expand_pending(&mut self) -> io::Result<usize> {
+        // A character which is split between writes is completed by the next write.
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(why) if why.error_len().is_none() => why.valid_up_to(),
+            Err(why) => return Err(io::Error::new(io::ErrorKind::InvalidData, why)),
+        };
+
+        let window = str::from_utf8(&self.pending[..valid]).expect("validated as UTF-8");
+        let mut tokenizer = self.config.rebind(window);
+        let mut consumed = valid;
+        self.output.clear();
+        while let Some((token, span)) = tokenizer.next_spanned() {
+            let (complete, incomplete) = split_incomplete(&self.config, window, token, &span);
+            if let Some(token) = complete {
+                (self.map)(&mut self.output, token)?;
+            }
+
+            if let Some(rest) = incomplete {
+                consumed = rest;
+                break;
+            }
+        }
+        drop(tokenizer);
+
+        self.inner.write_all(self.output.as_bytes())?;
+        Ok(consumed)
+    }
+}
+
+impl<'m, W: Write, F: FnMut(&mut String, Token) -> io::Result<()>> Write
+    for ExpandWriter<'m, W, F>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // On failure, nothing of `buf` is kept, so that the write may be retried.
+        let before = self.pending.len();
+        self.pending.extend_from_slice(buf);
+        match self.expand_pending() {
+            Ok(consumed) => {
+                self.pending.drain(..consumed);
+                Ok(buf.len())
+            }
+            Err(why) => {
+                self.pending.truncate(before);
+                Err(why)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Expands into `out` as the template is tokenized, flushing `out` after every
+    /// `flush_every` tokens, and after the last, so that a long expansion is displayed
+    /// progressively, such as on an interactive terminal. A `flush_every` of zero is treated
+    /// as one.
+    ///
+    /// ```rust
+    /// use std::io;
+    /// use token_expander::{Token, Tokenizer};
+    ///
+    /// let mut out = Vec::new();
+    /// Tokenizer::new("${name}_1.0.0.deb")
+    ///     .expand_to_flushing(
+    ///         &mut out,
+    ///         |buf, token| {
+    ///             match token {
+    ///                 Token::Normal(text) => buf.push_str(text),
+    ///                 Token::Key("name") => buf.push_str("system76"),
+    ///                 other => return Err(io::Error::other(format!("unsupported: {:?}", other))),
+    ///             }
+    ///             Ok(())
+    ///         },
+    ///         1,
+    ///     )
+    ///     .unwrap();
+    /// assert_eq!(out, b"system76_1.0.0.deb");
+    /// ```
+    pub fn expand_to_flushing<W, F>(
+        &mut self,
+        mut out: W,
+        mut map: F,
+        flush_every: usize,
+    ) -> io::Result<()>
+    where
+        W: Write,
+        F: FnMut(&mut String, Token) -> io::Result<()>,
+    {
+        let flush_every = flush_every.max(1);
+        let mut output = String::new();
+        let mut unflushed = 0;
+        for token in self {
+            output.clear();
+            map(&mut output, token)?;
+            out.write_all(output.as_bytes())?;
+            unflushed += 1;
+            if unflushed == flush_every {
+                out.flush()?;
+                unflushed = 0;
+            }
+        }
+
+        if unflushed != 0 {
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(buf: &mut String, token: Token) -> io::Result<()> {
+        match token {
+            Token::Normal(text) => buf.push_str(text),
+            Token::Escaped(character) => buf.push(character),
+            Token::Key("domain") => buf.push_str("apt.pop-os.org"),
+            Token::Key("name") => buf.push_str("system76"),
+            other => return Err(io::Error::other(format!("unsupported: {:?}", other))),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn split_writes() {
+        let mut writer = ExpandWriter::new(Vec::new(), map);
+        writer.write_all(b"https://$").unwrap();
+        writer.write_all(b"{domain}/\\").unwrap();
+        writer.write_all(b"$/$na").unwrap();
+        writer.write_all(b"me.deb").unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            b"https://apt.pop-os.org/$/system76.deb"
+        );
+    }
+
+    #[test]
+    fn split_mid_key() {
+        let mut writer = ExpandWriter::new(Vec::new(), map);
+        writer.write_all(b"https://${dom").unwrap();
+        assert_eq!(writer.inner, b"https://");
+        writer.write_all(b"ain}/${name}").unwrap();
+        assert_eq!(writer.finish().unwrap(), b"https://apt.pop-os.org/system76");
+    }
+
+    #[derive(Default)]
+    struct Counting {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flushing() {
+        // Yields five tokens: `https://`, `domain`, `/`, `$`, and `name`.
+        let template = "https://${domain}/\\$$name";
+        for &(flush_every, flushes) in &[(0, 5), (1, 5), (2, 3), (5, 1), (6, 1)] {
+            let mut out = Counting::default();
+            Tokenizer::new(template)
+                .expand_to_flushing(&mut out, map, flush_every)
+                .unwrap();
+            assert_eq!(out.written, b"https://apt.pop-os.org/$system76");
+            assert_eq!(out.flushes, flushes, "{}", flush_every);
+        }
+
+        let mut out = Counting::default();
+        assert!(Tokenizer::new("a/$b/c")
+            .expand_to_flushing(&mut out, map, 1)
+            .is_err());
+        assert_eq!((out.written, out.flushes), (b"a/".to_vec(), 1));
+    }
+
+    /// Fails the first write, and accepts every write after it.
+    #[derive(Default)]
+    struct FailsOnce {
+        written: Vec<u8>,
+        failed: bool,
+    }
+
+    impl Write for FailsOnce {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(io::Error::other("interrupted"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retried_write() {
+        let mut writer = ExpandWriter::new(FailsOnce::default(), map);
+        assert!(writer.write_all(b"https://${domain}/").is_err());
+        writer.write_all(b"https://${domain}/").unwrap();
+        writer.write_all(b"$name").unwrap();
+        assert_eq!(
+            writer.finish().unwrap().written,
+            b"https://apt.pop-os.org/system76"
+        );
+
+        let mut writer = ExpandWriter::new(Vec::new(), map);
+        assert!(writer.write_all(b"a/$unknown/").is_err());
+        writer.write_all(b"a/$name/").unwrap();
+        assert_eq!(writer.finish().unwrap(), b"a/system76/");
+    }
+
+    #[test]
+    fn split_character() {
+        let mut writer = ExpandWriter::new(Vec::new(), map);
+        let text = "é$name".as_bytes();
+        writer.write_all(&text[..1]).unwrap();
+        writer.write_all(&text[1..]).unwrap();
+        assert_eq!(writer.finish().unwrap(), "ésystem76".as_bytes());
+
+        let mut writer = ExpandWriter::new(Vec::new(), map);
+        assert!(writer.write_all(b"\xff").is_err());
+        assert_eq!(writer.finish().unwrap(), b"");
+    }
+}